@@ -10,12 +10,21 @@ use std::sync::Mutex;
 
 use crate::event::Event;
 use crate::matrix::matrix::Matrix;
+use crate::widgets::accounts::Accounts;
 use crate::widgets::chat::Chat;
 use crate::widgets::confirm::Confirm;
+use crate::widgets::devices::Devices;
+use crate::widgets::directory::Directory;
 use crate::widgets::error::Error;
 use crate::widgets::help::Help;
+use crate::widgets::join::Join;
+use crate::widgets::newdm::NewDm;
+use crate::widgets::newroom::NewRoom;
+use crate::widgets::password::Password;
 use crate::widgets::progress::Progress;
+use crate::widgets::qrcode::QrCode;
 use crate::widgets::rooms::Rooms;
+use crate::widgets::security::Security;
 use crate::widgets::signin::Signin;
 use crate::widgets::EventResult;
 use ratatui::backend::Backend;
@@ -35,7 +44,7 @@ pub struct App {
     pub popup: Option<Popup>,
     pub chat: Option<Chat>,
 
-    /// And our single Matrix client and channel
+    /// And our Matrix client (for every account) and channel
     pub matrix: Matrix,
     pub sender: Sender<Event>,
 
@@ -79,7 +88,9 @@ impl App {
     pub fn select_room(&mut self, room: Joined) {
         // don't re-select the same room
         if let Some(c) = &self.chat {
-            if c.room().room_id() == room.room_id() {
+            if c.room().room_id() == room.room_id()
+                && c.room().own_user_id() == room.own_user_id()
+            {
                 return;
             }
         }
@@ -141,10 +152,19 @@ impl App {
 // instead we'll use a giant enum. I tried for way too long and just have
 // to give up before I lose it. PRs welcome if there's a better way!
 pub enum Popup {
+    Accounts(Accounts),
     Confirm(Confirm),
+    Devices(Devices),
+    Directory(Directory),
     Error(Error),
+    Join(Join),
+    NewDm(NewDm),
+    NewRoom(NewRoom),
+    Password(Password),
     Progress(Progress),
+    QrCode(QrCode),
     Rooms(Rooms),
+    Security(Security),
     Signin(Signin),
     Help(Help)
 }
@@ -152,10 +172,19 @@ pub enum Popup {
 impl Popup {
     pub fn key_event(&mut self, event: &KeyEvent) -> EventResult {
         match self {
+            Popup::Accounts(w) => w.key_event(event),
             Popup::Confirm(w) => w.key_event(event),
+            Popup::Devices(w) => w.key_event(event),
+            Popup::Directory(w) => w.key_event(event),
             Popup::Error(w) => w.key_event(event),
+            Popup::Join(w) => w.key_event(event),
+            Popup::NewDm(w) => w.key_event(event),
+            Popup::NewRoom(w) => w.key_event(event),
+            Popup::Password(w) => w.key_event(event),
             Popup::Progress(_) => EventResult::Ignored,
+            Popup::QrCode(w) => w.key_event(event),
             Popup::Rooms(w) => w.key_event(event),
+            Popup::Security(w) => w.key_event(event),
             Popup::Signin(w) => w.key_event(event),
             Popup::Help(w) => w.key_event(event)
         }
@@ -169,10 +198,19 @@ impl Popup {
 
     pub fn render<B: Backend>(&self, frame: &mut Frame<'_, B>) {
         match self {
+            Popup::Accounts(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Confirm(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::Devices(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::Directory(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Error(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::Join(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::NewDm(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::NewRoom(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::Password(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Progress(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::QrCode(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Rooms(w) => frame.render_widget(w.widget(), frame.size()),
+            Popup::Security(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Signin(w) => frame.render_widget(w.widget(), frame.size()),
             Popup::Help(w) => frame.render_widget(w.widget(), frame.size()),
         }
@@ -1,40 +1,75 @@
 use crate::app::{App, Popup};
-use crate::matrix::matrix::format_emojis;
+use crate::matrix::backup::BackupStatus;
+use crate::matrix::clients::Connection;
+use crate::matrix::create::FoundUser;
+use crate::matrix::directory::{DirectoryPage, DirectoryQuery};
+use crate::matrix::matrix::{format_emojis, CrossSigning, OwnDevice, SasCode};
+use crate::matrix::outbox::OutboxItem;
+use crate::widgets::accounts::Accounts;
 use crate::widgets::confirm::{Confirm, ConfirmBehavior};
+use crate::widgets::devices::Devices;
+use crate::widgets::directory::Directory;
 use crate::widgets::error::Error;
 use crate::widgets::help::Help;
+use crate::widgets::join::Join;
+use crate::widgets::newdm::NewDm;
+use crate::widgets::newroom::NewRoom;
+use crate::widgets::password::{Password, PasswordBehavior};
 use crate::widgets::progress::Progress;
-use crate::widgets::rooms::{sort_rooms, Rooms};
+use crate::widgets::qrcode::QrCode;
+use crate::widgets::rooms::{next_room, top_room, Rooms};
+use crate::widgets::security::Security;
 use crate::widgets::signin::Signin;
 use crate::widgets::EventResult;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use ruma::events::receipt::ReceiptEventContent;
-use ruma::OwnedUserId;
+use ruma::{OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, UserId};
+use std::collections::HashSet;
 
 use crate::event::EventHandler;
-use matrix_sdk::encryption::verification::{Emoji, SasVerification};
-use matrix_sdk::room::{Joined, Room, RoomMember};
+use matrix_sdk::deserialized_responses::EncryptionInfo;
+use matrix_sdk::encryption::verification::{QrVerification, SasVerification, VerificationRequest};
+use matrix_sdk::room::{Invited, Joined, Room, RoomMember};
 use ruma::events::AnyTimelineEvent;
 
 #[derive(Clone, Debug)]
 pub enum MatuiEvent {
+    AuthRequired(Reauth, Option<String>, bool),
+    BackupStatus(OwnedUserId, BackupStatus),
     Confirm(String, String),
+    CrossSigningStatus(OwnedUserId, CrossSigning),
+    Devices(OwnedUserId, Vec<OwnDevice>),
+    Directory(DirectoryQuery, bool, Result<DirectoryPage, String>),
     Error(String),
+    Invited(Invited),
     LoginComplete,
     LoginRequired,
     LoginStarted,
+    LogoutComplete(OwnedUserId),
+    PassphraseRequired(PassphraseType),
     ProgressStarted(String, u64),
     ProgressComplete,
     Receipt(Joined, ReceiptEventContent),
     RoomMember(Joined, RoomMember),
     RoomSelected(Joined),
+    RoomsLoaded(OwnedUserId),
+    SessionExpired(OwnedUserId, bool),
     SyncComplete,
+    ConnectionChanged(OwnedUserId, Connection),
+    OutboxChanged(Vec<OutboxItem>),
     SyncStarted(SyncType),
-    Timeline(AnyTimelineEvent),
+    Timeline(AnyTimelineEvent, Option<EncryptionInfo>),
     TimelineBatch(Batch),
+    UsersFound(String, Result<Vec<FoundUser>, String>),
+    Decrypted(AnyTimelineEvent, Option<EncryptionInfo>),
+    RoomKeysReceived(OwnedRoomId, Vec<String>),
+    RoomLeft(Joined),
     Typing(Joined, Vec<OwnedUserId>),
-    VerificationStarted(SasVerification, [Emoji; 7]),
-    VerificationCompleted,
+    VerificationQrCode(VerificationRequest, usize, Vec<bool>),
+    VerificationQrScanned(QrVerification),
+    VerificationRequested(VerificationRequest, String),
+    VerificationStarted(SasVerification, SasCode),
+    VerificationCompleted(String),
 }
 
 #[derive(Clone, Debug)]
@@ -43,18 +78,74 @@ pub enum SyncType {
     Latest,
 }
 
+#[derive(Clone, Debug)]
+pub enum PassphraseType {
+    Choose,
+    Incorrect,
+    Unlock,
+}
+
+/// Things the homeserver wants the password for, before it will do them.
+#[derive(Clone, Debug)]
+pub enum Reauth {
+    BootstrapCrossSigning(OwnedUserId),
+    DeleteDevices(OwnedUserId, Vec<OwnedDeviceId>),
+}
+
 #[derive(Clone, Debug)]
 pub struct Batch {
     pub room: Joined,
-    pub events: Vec<AnyTimelineEvent>,
+    pub events: Vec<(AnyTimelineEvent, Option<EncryptionInfo>)>,
     pub cursor: Option<String>,
+
+    /// Cached events we never met up with, which come back out.
+    pub stale: HashSet<OwnedEventId>,
 }
 
 pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
     match event {
+        MatuiEvent::AuthRequired(reauth, session, retry) => {
+            let message = match (&reauth, retry) {
+                (_, true) => "That password didn't work. Please try again.".to_string(),
+                (Reauth::BootstrapCrossSigning(_), false) => {
+                    "Please enter your password to set up cross-signing.".to_string()
+                }
+                (Reauth::DeleteDevices(_, devices), false) => format!(
+                    "Please enter your password to delete {} session(s).",
+                    devices.len()
+                ),
+            };
+
+            app.set_popup(Popup::Password(Password::new(
+                "Password Required".to_string(),
+                message,
+                false,
+                PasswordBehavior::Reauth(reauth, session),
+            )));
+        }
+        MatuiEvent::BackupStatus(user_id, status) => {
+            if let Some(Popup::Security(s)) = &mut app.popup {
+                s.backup_status_event(&user_id, status);
+            }
+        }
         MatuiEvent::Confirm(header, msg) => {
             app.set_popup(Popup::Error(Error::with_heading(header, msg)));
         }
+        MatuiEvent::CrossSigningStatus(user_id, status) => {
+            if let Some(Popup::Security(s)) = &mut app.popup {
+                s.cross_signing_status_event(&user_id, status);
+            }
+        }
+        MatuiEvent::Devices(user_id, devices) => {
+            if let Some(Popup::Devices(d)) = &mut app.popup {
+                d.devices_event(&user_id, devices);
+            }
+        }
+        MatuiEvent::Directory(query, appended, page) => {
+            if let Some(Popup::Directory(d)) = &mut app.popup {
+                d.directory_event(query, appended, page);
+            }
+        }
         MatuiEvent::Error(msg) => {
             app.set_popup(Popup::Error(Error::new(msg)));
         }
@@ -67,6 +158,42 @@ pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
         MatuiEvent::LoginComplete => {
             app.popup = None;
         }
+        MatuiEvent::LogoutComplete(user_id) => {
+            app.popup = None;
+            forget_account(app, &user_id);
+
+            // sign in again if that was the last one
+            if app.matrix.active_account().is_none() {
+                app.set_popup(Popup::Signin(Signin::default()));
+            }
+        }
+        MatuiEvent::PassphraseRequired(pt) => {
+            let (title, message, confirm) = match pt {
+                PassphraseType::Choose => (
+                    "Choose a Passphrase",
+                    "Your sessions will be encrypted with this passphrase. \
+                     You'll need it every time Matui starts.",
+                    true,
+                ),
+                PassphraseType::Incorrect => (
+                    "Unlock",
+                    "That passphrase didn't work. Please try again.",
+                    false,
+                ),
+                PassphraseType::Unlock => (
+                    "Unlock",
+                    "Your sessions are encrypted. Please enter your passphrase.",
+                    false,
+                ),
+            };
+
+            app.set_popup(Popup::Password(Password::new(
+                title.to_string(),
+                message.to_string(),
+                confirm,
+                PasswordBehavior::UnlockSessions,
+            )));
+        }
         MatuiEvent::ProgressStarted(msg, delay) => {
             app.set_popup(Popup::Progress(Progress::new(&msg, delay)))
         }
@@ -78,7 +205,19 @@ pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
                 c.room_member_event(room, member);
             }
         }
+        MatuiEvent::Invited(room) => app.matrix.invite_event(room),
         MatuiEvent::RoomSelected(room) => app.select_room(room),
+        MatuiEvent::RoomsLoaded(user_id) => {
+            // sliding sync only has the rooms after it's started, so there
+            // was nothing to show at SyncComplete
+            if app.chat.is_none() && app.matrix.active_account() == Some(user_id) {
+                select_top_room(app);
+            }
+        }
+        MatuiEvent::SessionExpired(user_id, soft_logout) => {
+            forget_account(app, &user_id);
+            app.set_popup(Popup::Signin(Signin::expired(&user_id, soft_logout)));
+        }
         MatuiEvent::SyncStarted(st) => {
             match st {
                 SyncType::Initial => app.set_popup(Popup::Progress(Progress::new(
@@ -94,17 +233,47 @@ pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
             // now we can sync forever
             app.matrix.sync();
 
-            // and show the first room
-            let mut rooms = app.matrix.fetch_rooms();
-            sort_rooms(&mut rooms);
+            // and show the first room for the active account
+            select_top_room(app);
+        }
+        MatuiEvent::ConnectionChanged(user_id, connection) => {
+            if let Some(c) = &mut app.chat {
+                c.connection_event(&user_id, connection);
+            }
+        }
+        MatuiEvent::OutboxChanged(items) => {
+            if let Some(c) = &mut app.chat {
+                c.outbox_event(items);
+            }
+        }
+        MatuiEvent::RoomLeft(room) => {
+            let open = matches!(&app.chat, Some(c) if c.room().room_id() == room.room_id()
+                && c.room().own_user_id() == room.own_user_id());
+
+            // find where to go before the room is gone from the list
+            let next = next_room(app.matrix.fetch_rooms(), &room);
+            app.matrix.remove_room(&room);
+
+            app.receipts.retain(|(j, _)| {
+                j.room_id() != room.room_id() || j.own_user_id() != room.own_user_id()
+            });
 
-            if let Some(room) = rooms.first() {
-                app.select_room(room.inner.clone())
+            if open {
+                app.chat = None;
+
+                if let Some(next) = next {
+                    app.select_room(next);
+                }
+            }
+        }
+        MatuiEvent::UsersFound(term, users) => {
+            if let Some(Popup::NewDm(d)) = &mut app.popup {
+                d.users_event(term, users);
             }
         }
-        MatuiEvent::Timeline(event) => {
+        MatuiEvent::Timeline(event, encryption) => {
             if let Some(c) = &mut app.chat {
-                c.timeline_event(event.clone());
+                c.timeline_event(event.clone(), encryption);
             }
 
             // is it weird to send events all the way up here, then right
@@ -116,6 +285,16 @@ pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
                 c.batch_event(batch);
             }
         }
+        MatuiEvent::Decrypted(event, encryption) => {
+            if let Some(c) = &mut app.chat {
+                c.decrypted_event(event, encryption);
+            }
+        }
+        MatuiEvent::RoomKeysReceived(room_id, sessions) => {
+            if let Some(c) = &mut app.chat {
+                c.room_keys_event(&room_id, &sessions);
+            }
+        }
         MatuiEvent::Typing(joined, ids) => {
             if let Some(c) = &mut app.chat {
                 c.typing_event(joined, ids);
@@ -132,27 +311,93 @@ pub fn handle_app_event(event: MatuiEvent, app: &mut App) {
                 app.receipts.pop_front();
             }
         }
-        MatuiEvent::VerificationStarted(sas, emoji) => {
-            app.sas = Some(sas);
-
+        MatuiEvent::VerificationRequested(request, device) => {
             app.set_popup(Popup::Confirm(Confirm::new(
-                "Verify".to_string(),
+                "Verification Request".to_string(),
                 format!(
+                    "{} wants to verify their session {} with you.",
+                    request.other_user_id(),
+                    device
+                ),
+                "Accept".to_string(),
+                "Decline".to_string(),
+                ConfirmBehavior::VerificationRequest(request),
+            )));
+        }
+        MatuiEvent::VerificationQrCode(request, width, modules) => {
+            app.set_popup(Popup::QrCode(QrCode::new(request, width, modules)));
+        }
+        MatuiEvent::VerificationQrScanned(qr) => {
+            app.set_popup(Popup::Confirm(Confirm::new(
+                "Verify".to_string(),
+                "Your other session scanned the code. Does it say that the verification \
+                 was successful?"
+                    .to_string(),
+                "Yes".to_string(),
+                "No".to_string(),
+                ConfirmBehavior::QrVerification(qr),
+            )));
+        }
+        MatuiEvent::VerificationStarted(sas, code) => {
+            app.sas = Some(sas);
+
+            let message = match code {
+                SasCode::Emoji(emoji) => format!(
                     "Do these emojis match your other session?\n\n{}",
                     format_emojis(emoji)
                 ),
+                SasCode::Decimals(first, second, third) => format!(
+                    "Do these numbers match your other session?\n\n{}  {}  {}",
+                    first, second, third
+                ),
+            };
+
+            app.set_popup(Popup::Confirm(Confirm::new(
+                "Verify".to_string(),
+                message,
                 "Yes".to_string(),
                 "No".to_string(),
                 ConfirmBehavior::Verification,
             )));
         }
-        MatuiEvent::VerificationCompleted => {
-            app.popup = None;
+        MatuiEvent::VerificationCompleted(verified) => {
             app.sas = None;
+
+            app.set_popup(Popup::Error(Error::with_heading(
+                "Verified".to_string(),
+                format!("{} is now verified.", verified),
+            )));
         }
     }
 }
 
+// forget anything that belonged to the account, and fall back to another
+fn forget_account(app: &mut App, user_id: &UserId) {
+    if let Some(c) = &app.chat {
+        if c.room().own_user_id() == user_id {
+            app.chat = None;
+        }
+    }
+
+    app.receipts.retain(|(j, _)| j.own_user_id() != user_id);
+
+    if app.chat.is_none() {
+        select_top_room(app);
+    }
+}
+
+fn select_top_room(app: &mut App) {
+    let rooms = app.matrix.fetch_rooms();
+
+    if let Some(room) = app
+        .matrix
+        .active_account()
+        .and_then(|id| top_room(rooms, &id))
+    {
+        app.select_room(room)
+    }
+}
+
 pub fn handle_key_event(
     key_event: KeyEvent,
     app: &mut App,
@@ -179,6 +424,12 @@ pub fn handle_key_event(
         return Ok(());
     }
 
+    // try to sync again now, rather than waiting
+    if key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('r') {
+        app.matrix.reconnect();
+        return Ok(());
+    }
+
     // we own a few key events
     match key_event.code {
         KeyCode::Char(' ') => {
@@ -188,6 +439,34 @@ pub fn handle_key_event(
 
             return Ok(());
         }
+        KeyCode::Char('a') => {
+            app.set_popup(Popup::Accounts(Accounts::new(app.matrix.clone())));
+            return Ok(());
+        }
+        KeyCode::Char('J') => {
+            app.set_popup(Popup::Join(Join::new()));
+            return Ok(());
+        }
+        KeyCode::Char('N') => {
+            app.set_popup(Popup::NewRoom(NewRoom::new()));
+            return Ok(());
+        }
+        KeyCode::Char('M') => {
+            app.set_popup(Popup::NewDm(NewDm::new()));
+            return Ok(());
+        }
+        KeyCode::Char('B') => {
+            app.set_popup(Popup::Directory(Directory::new(app.matrix.clone())));
+            return Ok(());
+        }
+        KeyCode::Char('D') => {
+            app.set_popup(Popup::Devices(Devices::new(app.matrix.clone())));
+            return Ok(());
+        }
+        KeyCode::Char('S') => {
+            app.set_popup(Popup::Security(Security::new(app.matrix.clone())));
+            return Ok(());
+        }
         KeyCode::Char('q') => {
             app.running = false;
             return Ok(());
@@ -0,0 +1,651 @@
+//! Server-side key backup, built on the crypto in `recovery`. That's our
+//! own, rather than the SDK's, so none of this runs unless `key_backup` is
+//! turned on in the config.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context};
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+use log::warn;
+use matrix_sdk::crypto::olm::{ExportedRoomKey, InboundGroupSession};
+use matrix_sdk::crypto::{decrypt_room_key_export, encrypt_room_key_export};
+use matrix_sdk::encryption::RoomKeyImportResult;
+use matrix_sdk::ruma::api::client::backup::{
+    add_backup_keys, create_backup_version, get_backup_keys, get_latest_backup_info, RoomKeyBackup,
+};
+use matrix_sdk::ruma::api::client::config::get_global_account_data;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::exports::serde_json::{self, json, Value};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::CanonicalJsonValue;
+use matrix_sdk::{Client, HttpError};
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::Rng;
+use ruma::{OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::matrix::clients;
+use crate::matrix::recovery::{
+    backup_public_key, check_secret_storage_key, decode_base64, decode_recovery_key,
+    decrypt_secret, decrypt_session, encode_base64, encode_recovery_key, encrypt_session,
+    generate_backup_key, key_from_passphrase, EncryptedSecret, EncryptedSession,
+};
+use crate::settings::key_backup;
+
+const ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+const BACKUP_SECRET: &str = "m.megolm_backup.v1";
+const MASTER_SECRET: &str = "m.cross_signing.master";
+
+// the exports we pass to and from the SDK never leave this machine
+const EXPORT_ROUNDS: u32 = 10_000;
+
+/// Each account's uploads, so we only ever send the backup what's new.
+static UPLOADS: Mutex<BTreeMap<OwnedUserId, Uploads>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct Uploads {
+    /// The backup that `sessions` are in.
+    version: String,
+    sessions: HashSet<(OwnedRoomId, String)>,
+
+    /// Only one upload runs at a time, anything asked for in the meantime
+    /// is picked up by one more round once it's done.
+    running: bool,
+    again: bool,
+}
+
+/// The backup this device uploads its room keys to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupKey {
+    pub version: String,
+    pub private_key: String,
+}
+
+impl BackupKey {
+    fn new(version: String, private_key: &[u8; 32]) -> Self {
+        BackupKey {
+            version,
+            private_key: encode_base64(private_key),
+        }
+    }
+
+    fn private_key(&self) -> anyhow::Result<[u8; 32]> {
+        decode_base64(&self.private_key)?
+            .try_into()
+            .ok()
+            .context("Invalid backup key.")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BackupStatus {
+    /// Key backup isn't turned on in the config.
+    Off,
+
+    /// There's no backup on the server at all.
+    Missing,
+
+    /// There's a backup, but this device doesn't have the key for it.
+    Locked { version: String, count: u64 },
+
+    /// We're uploading our keys to the backup.
+    Enabled { version: String, count: u64 },
+}
+
+/// The latest backup on the server, if there is one.
+async fn latest_backup(client: &Client) -> anyhow::Result<Option<(String, u64, Value)>> {
+    let request = get_latest_backup_info::v3::Request::new();
+
+    match client.send(request, None).await {
+        Ok(response) => {
+            let algorithm: Value = serde_json::from_str(response.algorithm.json().get())?;
+            Ok(Some((response.version, response.count.into(), algorithm)))
+        }
+        Err(err) if not_found(&err) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn check_key_backup() -> anyhow::Result<()> {
+    if !key_backup() {
+        bail!("Key backup is off, set key_backup = true in the config to use it.");
+    }
+
+    Ok(())
+}
+
+fn not_found(err: &HttpError) -> bool {
+    matches!(err.client_api_error_kind(), Some(ErrorKind::NotFound))
+}
+
+/// The public key of a backup, as long as it's one we understand.
+fn public_key(algorithm: &Value) -> anyhow::Result<&str> {
+    if algorithm["algorithm"] != ALGORITHM {
+        bail!("The key backup uses an algorithm Matui doesn't support.");
+    }
+
+    algorithm["auth_data"]["public_key"]
+        .as_str()
+        .context("The key backup has no public key.")
+}
+
+pub async fn status(client: &Client, key: Option<&BackupKey>) -> anyhow::Result<BackupStatus> {
+    if !key_backup() {
+        return Ok(BackupStatus::Off);
+    }
+
+    Ok(match latest_backup(client).await? {
+        None => BackupStatus::Missing,
+        Some((version, count, _)) if key.map(|k| &k.version) == Some(&version) => {
+            BackupStatus::Enabled { version, count }
+        }
+        Some((version, count, _)) => BackupStatus::Locked { version, count },
+    })
+}
+
+/// Start a new backup, and upload everything we have to it. The recovery
+/// key we return is the only way to get the keys back out again.
+///
+/// With the recovery key or passphrase for secret storage, we sign the
+/// backup with the cross-signing master key, so our other sessions trust it.
+///
+/// An existing backup is only replaced once the user has said so, since any
+/// keys that are only in there are lost with it.
+pub async fn enable(
+    client: &Client,
+    secret_storage: Option<&str>,
+    replace: bool,
+) -> anyhow::Result<(BackupKey, String)> {
+    check_key_backup()?;
+
+    if !replace && latest_backup(client).await?.is_some() {
+        bail!("There's already a key backup, restore it with your recovery key or passphrase.");
+    }
+
+    let private_key = generate_backup_key();
+
+    let mut auth_data = json!({
+        "public_key": backup_public_key(&private_key),
+        "signatures": {},
+    });
+
+    if let Some(input) = secret_storage {
+        sign(client, &mut auth_data, input).await?;
+    }
+
+    let algorithm = json!({
+        "algorithm": ALGORITHM,
+        "auth_data": auth_data,
+    });
+
+    let request = create_backup_version::v3::Request::new(to_raw(&algorithm)?);
+    let response = client.send(request, None).await?;
+
+    let key = BackupKey::new(response.version, &private_key);
+    upload(client, &key).await?;
+
+    Ok((key, encode_recovery_key(&private_key)))
+}
+
+/// Pull every key out of the latest backup. The input can be a recovery key
+/// or passphrase for secret storage, or the recovery key of the backup
+/// itself.
+pub async fn restore(
+    client: &Client,
+    input: &str,
+) -> anyhow::Result<(BackupKey, RoomKeyImportResult)> {
+    check_key_backup()?;
+
+    let (version, _, algorithm) = latest_backup(client)
+        .await?
+        .context("There's no key backup to restore from.")?;
+
+    let public_key = public_key(&algorithm)?;
+
+    let private_key = match (
+        secret_storage_backup_key(client, input).await,
+        decode_recovery_key(input),
+    ) {
+        (Ok(Some(private_key)), _) => private_key,
+
+        // it could also be the recovery key of the backup itself
+        (_, Ok(private_key)) => private_key,
+        (Err(err), _) | (Ok(None), Err(err)) => return Err(err),
+    };
+
+    if backup_public_key(&private_key) != public_key.trim_end_matches('=') {
+        bail!("That key doesn't match the key backup.");
+    }
+
+    let request = get_backup_keys::v3::Request::new(version.clone());
+    let response = client.send(request, None).await?;
+
+    let mut keys = vec![];
+
+    for (room_id, backup) in response.rooms {
+        for (session_id, data) in backup.sessions {
+            let data: Value = serde_json::from_str(data.json().get())?;
+            let session = &data["session_data"];
+
+            let session = EncryptedSession {
+                ephemeral: string(&session["ephemeral"])?,
+                ciphertext: string(&session["ciphertext"])?,
+                mac: string(&session["mac"])?,
+            };
+
+            // the backup leaves out what it already has as keys
+            let plaintext = decrypt_session(&private_key, &session)?;
+            let mut key: Value = serde_json::from_slice(&plaintext)?;
+            key["room_id"] = json!(room_id);
+            key["session_id"] = json!(session_id);
+
+            keys.push(serde_json::from_value::<ExportedRoomKey>(key)?);
+        }
+    }
+
+    let result = import(client, &keys).await?;
+
+    Ok((BackupKey::new(version, &private_key), result))
+}
+
+/// Sign the auth data with the master key from secret storage. The SDK
+/// won't sign arbitrary JSON with the device key, so that's the only
+/// signature it gets.
+///
+/// The master key is only ever held here, just long enough to sign, and
+/// it's wiped from memory as soon as we're done with it.
+async fn sign(client: &Client, auth_data: &mut Value, input: &str) -> anyhow::Result<()> {
+    let (key_id, key) = secret_storage_key(client, input)
+        .await?
+        .context("There's no secret storage for this account.")?;
+
+    let master_key = clients::master_key(client).await?;
+
+    // the signature covers the canonical JSON, without the signatures
+    let mut unsigned = auth_data.clone();
+
+    if let Some(unsigned) = unsigned.as_object_mut() {
+        unsigned.remove("signatures");
+    }
+
+    let canonical = CanonicalJsonValue::try_from(unsigned)?.to_string();
+
+    // other clients keep the key there, but they don't have to
+    let seed = match stored_secret(client, &key_id, &key, MASTER_SECRET).await? {
+        Some(seed) => seed,
+        None => {
+            warn!("no cross-signing key in secret storage, the backup won't be signed");
+            return Ok(());
+        }
+    };
+
+    // nothing awaits from here on, so the key never outlives this call
+    let secret = SecretKey::from_bytes(seed.as_ref())
+        .ok()
+        .context("Invalid cross-signing key in secret storage.")?;
+
+    let public = PublicKey::from(&secret);
+    let signature = ExpandedSecretKey::from(&secret).sign(canonical.as_bytes(), &public);
+
+    drop(secret);
+    drop(seed);
+
+    let public_key = encode_base64(public.as_bytes());
+
+    if master_key.as_deref() != Some(public_key.as_str()) {
+        bail!("The cross-signing key in secret storage doesn't match your account.");
+    }
+
+    auth_data["signatures"][clients::user_id(client).as_str()][format!("ed25519:{}", public_key)] =
+        json!(encode_base64(signature.to_bytes()));
+
+    Ok(())
+}
+
+/// The default secret storage key, if the account has secret storage and
+/// the input unlocks it.
+async fn secret_storage_key(
+    client: &Client,
+    input: &str,
+) -> anyhow::Result<Option<(String, [u8; 32])>> {
+    let key_id = match account_data(client, "m.secret_storage.default_key").await? {
+        Some(default_key) => string(&default_key["key"])?,
+        None => return Ok(None),
+    };
+
+    let description = account_data(client, &format!("m.secret_storage.key.{}", key_id))
+        .await?
+        .context("The secret storage key is missing.")?;
+
+    let key = match decode_recovery_key(input) {
+        Ok(key) => key,
+        Err(_) => {
+            let passphrase = &description["passphrase"];
+
+            if passphrase.is_null() {
+                bail!("Enter your recovery key, there's no passphrase for this account.");
+            }
+
+            let salt = string(&passphrase["salt"])?;
+            let iterations = passphrase["iterations"].as_u64().unwrap_or(500_000);
+
+            key_from_passphrase(input, &salt, iterations as u32)
+        }
+    };
+
+    if let (Some(iv), Some(mac)) = (description["iv"].as_str(), description["mac"].as_str()) {
+        if !check_secret_storage_key(&key, iv, mac) {
+            bail!("That doesn't unlock your secret storage.");
+        }
+    }
+
+    Ok(Some((key_id, key)))
+}
+
+/// A 32 byte key out of secret storage, if it's there.
+async fn stored_secret(
+    client: &Client,
+    key_id: &str,
+    key: &[u8; 32],
+    name: &str,
+) -> anyhow::Result<Option<Zeroizing<[u8; 32]>>> {
+    let secret = match account_data(client, name).await? {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+
+    let encrypted = &secret["encrypted"][key_id];
+
+    let secret = Zeroizing::new(decrypt_secret(
+        key,
+        name,
+        &EncryptedSecret {
+            iv: string(&encrypted["iv"])?,
+            ciphertext: string(&encrypted["ciphertext"])?,
+            mac: string(&encrypted["mac"])?,
+        },
+    )?);
+
+    // the secret itself is the base64 of the key
+    let private_key = Zeroizing::new(decode_base64(std::str::from_utf8(&secret)?)?);
+
+    if private_key.len() != 32 {
+        bail!("Invalid key in secret storage.");
+    }
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&private_key);
+
+    Ok(Some(key))
+}
+
+/// The backup key, if the account has secret storage and the input unlocks
+/// it.
+async fn secret_storage_backup_key(
+    client: &Client,
+    input: &str,
+) -> anyhow::Result<Option<[u8; 32]>> {
+    let (key_id, key) = match secret_storage_key(client, input).await? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    // not every account keeps the backup key in secret storage
+    Ok(stored_secret(client, &key_id, &key, BACKUP_SECRET)
+        .await?
+        .map(|private_key| *private_key))
+}
+
+/// Encrypt the room keys the backup doesn't have yet, and upload them.
+pub async fn upload(client: &Client, key: &BackupKey) -> anyhow::Result<usize> {
+    check_key_backup()?;
+
+    let user_id = clients::user_id(client);
+
+    {
+        let mut uploads = UPLOADS.lock().expect("to lock uploads");
+        let uploads = uploads.entry(user_id.clone()).or_default();
+
+        if uploads.running {
+            uploads.again = true;
+            return Ok(0);
+        }
+
+        uploads.running = true;
+    }
+
+    let mut count = 0;
+
+    loop {
+        let result = upload_new(client, &user_id, key).await;
+
+        let mut uploads = UPLOADS.lock().expect("to lock uploads");
+        let uploads = uploads.entry(user_id.clone()).or_default();
+        let again = result.is_ok() && uploads.again;
+
+        uploads.running = again;
+        uploads.again = false;
+
+        count += result?;
+
+        if !again {
+            return Ok(count);
+        }
+    }
+}
+
+async fn upload_new(
+    client: &Client,
+    user_id: &OwnedUserId,
+    key: &BackupKey,
+) -> anyhow::Result<usize> {
+    let known = backed_up(client, user_id, &key.version).await?;
+    let public_key = backup_public_key(&key.private_key()?);
+    let mut rooms: BTreeMap<String, Value> = BTreeMap::new();
+    let mut uploaded = vec![];
+
+    let room_keys = export(client, |session| {
+        !known.contains(&(
+            session.room_id().to_owned(),
+            session.session_id().to_owned(),
+        ))
+    })
+    .await?;
+
+    for room_key in room_keys {
+        let mut data = serde_json::to_value(&room_key)?;
+        let (room_id, session_id) = (string(&data["room_id"])?, string(&data["session_id"])?);
+
+        if let Some(data) = data.as_object_mut() {
+            data.remove("room_id");
+            data.remove("session_id");
+        }
+
+        let forwarded_count = data["forwarding_curve25519_key_chain"]
+            .as_array()
+            .map(Vec::len)
+            .unwrap_or_default();
+
+        let first_message_index = first_message_index(&string(&data["session_key"])?)?;
+        let session = encrypt_session(&public_key, &serde_json::to_vec(&data)?)?;
+
+        uploaded.push((room_id.clone(), session_id.clone()));
+
+        rooms
+            .entry(room_id)
+            .or_insert_with(|| json!({ "sessions": {} }))["sessions"][session_id] = json!({
+            "first_message_index": first_message_index,
+            "forwarded_count": forwarded_count,
+            "is_verified": false,
+            "session_data": {
+                "ephemeral": session.ephemeral,
+                "ciphertext": session.ciphertext,
+                "mac": session.mac,
+            },
+        });
+    }
+
+    if uploaded.is_empty() {
+        return Ok(0);
+    }
+
+    // Raw values can only come from a string, not a Value
+    let rooms: BTreeMap<OwnedRoomId, RoomKeyBackup> =
+        serde_json::from_str(&serde_json::to_string(&rooms)?)?;
+
+    let request = add_backup_keys::v3::Request::new(key.version.clone(), rooms);
+    client.send(request, None).await?;
+
+    let count = uploaded.len();
+    let mut uploads = UPLOADS.lock().expect("to lock uploads");
+    let sessions = &mut uploads.entry(user_id.clone()).or_default().sessions;
+
+    for (room_id, session_id) in uploaded {
+        sessions.insert((OwnedRoomId::try_from(room_id)?, session_id));
+    }
+
+    Ok(count)
+}
+
+/// The sessions already in the backup. We ask the server the first time
+/// round, and keep track of our own uploads from then on.
+async fn backed_up(
+    client: &Client,
+    user_id: &OwnedUserId,
+    version: &str,
+) -> anyhow::Result<HashSet<(OwnedRoomId, String)>> {
+    {
+        let uploads = UPLOADS.lock().expect("to lock uploads");
+
+        if let Some(uploads) = uploads.get(user_id).filter(|u| u.version == version) {
+            return Ok(uploads.sessions.clone());
+        }
+    }
+
+    let request = get_backup_keys::v3::Request::new(version.to_string());
+    let response = client.send(request, None).await?;
+
+    let sessions: HashSet<_> = response
+        .rooms
+        .into_iter()
+        .flat_map(|(room_id, backup)| {
+            backup
+                .sessions
+                .into_keys()
+                .map(move |session_id| (room_id.clone(), session_id))
+        })
+        .collect();
+
+    let mut uploads = UPLOADS.lock().expect("to lock uploads");
+    let uploads = uploads.entry(user_id.clone()).or_default();
+    uploads.version = version.to_string();
+    uploads.sessions = sessions.clone();
+
+    Ok(sessions)
+}
+
+/// An exported session key starts with a version byte, then the index of the
+/// first message it can decrypt.
+fn first_message_index(session_key: &str) -> anyhow::Result<u32> {
+    let bytes = decode_base64(session_key)?;
+
+    let index = bytes
+        .get(1..5)
+        .context("Invalid session key.")?
+        .try_into()?;
+
+    Ok(u32::from_be_bytes(index))
+}
+
+/// The SDK will only export keys to a file, so we go through a temporary
+/// one.
+async fn export(
+    client: &Client,
+    predicate: impl FnMut(&InboundGroupSession) -> bool,
+) -> anyhow::Result<Vec<ExportedRoomKey>> {
+    let file = tempfile::NamedTempFile::new()?;
+    let passphrase = temporary_passphrase();
+
+    client
+        .encryption()
+        .export_room_keys(file.path().to_path_buf(), &passphrase, predicate)
+        .await?;
+
+    Ok(decrypt_room_key_export(
+        File::open(file.path())?,
+        &passphrase,
+    )?)
+}
+
+/// And import them from one, too.
+async fn import(client: &Client, keys: &[ExportedRoomKey]) -> anyhow::Result<RoomKeyImportResult> {
+    let file = tempfile::NamedTempFile::new()?;
+    let passphrase = temporary_passphrase();
+
+    fs::write(
+        file.path(),
+        encrypt_room_key_export(keys, &passphrase, EXPORT_ROUNDS)?,
+    )?;
+
+    let result = client
+        .encryption()
+        .import_room_keys(file.path().to_path_buf(), &passphrase)
+        .await?;
+
+    Ok(result)
+}
+
+fn temporary_passphrase() -> String {
+    OsRng
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+async fn account_data(client: &Client, event_type: &str) -> anyhow::Result<Option<Value>> {
+    let request =
+        get_global_account_data::v3::Request::new(clients::user_id(client), event_type.into());
+
+    match client.send(request, None).await {
+        Ok(response) => Ok(Some(serde_json::from_str(
+            response.account_data.json().get(),
+        )?)),
+        Err(err) if not_found(&err) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn to_raw<T>(value: &Value) -> anyhow::Result<Raw<T>> {
+    Ok(Raw::from_json(serde_json::value::to_raw_value(value)?))
+}
+
+fn string(value: &Value) -> anyhow::Result<String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .context("Unexpected data from the homeserver.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::first_message_index;
+    use crate::matrix::recovery::encode_base64;
+
+    #[test]
+    fn test_first_message_index() {
+        let mut session_key = vec![1, 0, 0, 1, 2];
+        session_key.extend_from_slice(&[9; 64]);
+
+        assert_eq!(
+            first_message_index(&encode_base64(session_key)).unwrap(),
+            258
+        );
+
+        assert!(first_message_index(&encode_base64([1, 0])).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use matrix_sdk::ruma::api::client::keys::get_keys;
+use matrix_sdk::ruma::exports::serde_json::{self, Value};
+use matrix_sdk::Client;
+use ruma::{OwnedUserId, RoomId, UserId};
+
+/// Every account we're signed in to, along with the one that's "active".
+/// Rooms always know their own client; the active account is the one that
+/// gets anything that isn't tied to a room.
+pub struct Clients {
+    clients: Mutex<Vec<Client>>,
+    active: Mutex<Option<OwnedUserId>>,
+    syncing: Mutex<Vec<OwnedUserId>>,
+    connections: Mutex<HashMap<OwnedUserId, Connection>>,
+}
+
+/// How an account's sync loop is getting on with the homeserver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Connection {
+    Connected,
+
+    /// The last sync failed, and we'll try again shortly.
+    Reconnecting(String),
+
+    /// Enough syncs in a row have failed that we're probably offline. We
+    /// keep trying, just not as often.
+    Offline(String),
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Clients {
+            clients: Mutex::new(vec![]),
+            active: Mutex::new(None),
+            syncing: Mutex::new(vec![]),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Clients {
+    /// Add a signed-in client, replacing any other client for the same
+    /// account. The first account added becomes the active one.
+    pub fn add(&self, client: Client) {
+        let id = user_id(&client);
+
+        {
+            let mut clients = self.clients.lock().expect("to unlock clients");
+            clients.retain(|c| user_id(c) != id);
+            clients.push(client);
+        }
+
+        let mut active = self.active.lock().expect("to unlock active");
+
+        if active.is_none() {
+            *active = Some(id);
+        }
+    }
+
+    pub fn remove(&self, user_id: &UserId) {
+        self.clients
+            .lock()
+            .expect("to unlock clients")
+            .retain(|c| c.user_id() != Some(user_id));
+
+        self.syncing
+            .lock()
+            .expect("to unlock syncing")
+            .retain(|id| id != user_id);
+
+        self.connections
+            .lock()
+            .expect("to unlock connections")
+            .remove(user_id);
+
+        let mut active = self.active.lock().expect("to unlock active");
+
+        if active.as_deref() == Some(user_id) {
+            *active = self.user_ids().into_iter().next();
+        }
+    }
+
+    pub fn get(&self, user_id: &UserId) -> Option<Client> {
+        self.clients
+            .lock()
+            .expect("to unlock clients")
+            .iter()
+            .find(|c| c.user_id() == Some(user_id))
+            .cloned()
+    }
+
+    pub fn all(&self) -> Vec<Client> {
+        self.clients.lock().expect("to unlock clients").clone()
+    }
+
+    pub fn user_ids(&self) -> Vec<OwnedUserId> {
+        self.all().iter().map(user_id).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().expect("to unlock clients").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn active(&self) -> Option<Client> {
+        let active = self.active.lock().expect("to unlock active").clone()?;
+        self.get(&active)
+    }
+
+    pub fn active_user_id(&self) -> Option<OwnedUserId> {
+        self.active.lock().expect("to unlock active").clone()
+    }
+
+    pub fn set_active(&self, user_id: &UserId) {
+        if self.get(user_id).is_some() {
+            *self.active.lock().expect("to unlock active") = Some(user_id.to_owned());
+        }
+    }
+
+    /// The first account that has joined the room. When more than one of
+    /// our accounts share a room, this is the one that speaks for it.
+    pub fn first_joined(&self, room_id: &RoomId) -> Option<Client> {
+        self.all()
+            .into_iter()
+            .find(|c| c.get_joined_room(room_id).is_some())
+    }
+
+    pub fn is_syncing(&self, user_id: &UserId) -> bool {
+        self.syncing
+            .lock()
+            .expect("to unlock syncing")
+            .iter()
+            .any(|id| id == user_id)
+    }
+
+    /// Mark an account as syncing, returning false if it already was.
+    pub fn start_syncing(&self, user_id: &UserId) -> bool {
+        let mut syncing = self.syncing.lock().expect("to unlock syncing");
+
+        if syncing.iter().any(|id| id == user_id) {
+            return false;
+        }
+
+        syncing.push(user_id.to_owned());
+        true
+    }
+
+    pub fn connection(&self, user_id: &UserId) -> Connection {
+        self.connections
+            .lock()
+            .expect("to unlock connections")
+            .get(user_id)
+            .cloned()
+            .unwrap_or(Connection::Connected)
+    }
+
+    /// Update an account's connection, returning false if nothing changed.
+    pub fn set_connection(&self, user_id: &UserId, connection: Connection) -> bool {
+        let mut connections = self.connections.lock().expect("to unlock connections");
+
+        if connections.get(user_id) == Some(&connection) {
+            return false;
+        }
+
+        // no entry means connected, too
+        if connection == Connection::Connected && !connections.contains_key(user_id) {
+            return false;
+        }
+
+        connections.insert(user_id.to_owned(), connection);
+        true
+    }
+}
+
+pub fn user_id(client: &Client) -> OwnedUserId {
+    client
+        .user_id()
+        .expect("client is not signed in")
+        .to_owned()
+}
+
+/// The cross-signing master key the homeserver has for the account, if it
+/// has one. We ask every time, since another session could have set it up.
+pub async fn master_key(client: &Client) -> anyhow::Result<Option<String>> {
+    let user_id = user_id(client);
+
+    let mut request = get_keys::v3::Request::new();
+    request.device_keys.insert(user_id.clone(), vec![]);
+
+    let response = client.send(request, None).await?;
+
+    let master_key = match response.master_keys.get(&user_id) {
+        Some(master_key) => master_key,
+        None => return Ok(None),
+    };
+
+    let master_key: Value = serde_json::from_str(master_key.json().get())?;
+
+    Ok(master_key["keys"]
+        .as_object()
+        .and_then(|keys| keys.values().next())
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
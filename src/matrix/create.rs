@@ -0,0 +1,150 @@
+//! Starting new conversations: rooms, and direct messages with one other
+//! person. Direct rooms are only "direct" because they're listed in our
+//! `m.direct` account data, which we have to keep up to date ourselves.
+
+use matrix_sdk::ruma::api::client::config::{get_global_account_data, set_global_account_data};
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::room::create_room::v3::{Request, RoomPreset};
+use matrix_sdk::ruma::api::client::room::Visibility;
+use matrix_sdk::ruma::api::client::user_directory::search_users;
+use matrix_sdk::{Client, HttpError};
+use ruma::events::direct::DirectEventContent;
+use ruma::events::room::encryption::RoomEncryptionEventContent;
+use ruma::events::{GlobalAccountDataEventType, InitialStateEvent};
+use ruma::{OwnedRoomId, OwnedUserId, UInt, UserId};
+
+use crate::matrix::clients;
+
+// how many matches we'll show from the user directory
+const SEARCH_LIMIT: u32 = 20;
+
+/// Someone from the user directory.
+#[derive(Clone, Debug)]
+pub struct FoundUser {
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+}
+
+pub fn room_request(name: String, topic: Option<String>, encrypted: bool, public: bool) -> Request {
+    let mut request = Request::new();
+    request.name = Some(name);
+    request.topic = topic;
+
+    if public {
+        request.preset = Some(RoomPreset::PublicChat);
+        request.visibility = Visibility::Public;
+    } else {
+        request.preset = Some(RoomPreset::PrivateChat);
+        request.visibility = Visibility::Private;
+    }
+
+    if encrypted {
+        request.initial_state = vec![encryption_event()];
+    }
+
+    request
+}
+
+pub async fn create_room(client: &Client, request: Request) -> anyhow::Result<OwnedRoomId> {
+    Ok(client.send(request, None).await?.room_id)
+}
+
+pub async fn search_users(client: &Client, term: &str) -> anyhow::Result<Vec<FoundUser>> {
+    let mut request = search_users::v3::Request::new(term.to_string());
+    request.limit = UInt::from(SEARCH_LIMIT);
+
+    let response = client.send(request, None).await?;
+
+    Ok(response
+        .results
+        .into_iter()
+        .map(|u| FoundUser {
+            user_id: u.user_id,
+            display_name: u.display_name,
+        })
+        .collect())
+}
+
+/// The direct room we already have with someone, or a new (encrypted) one.
+pub async fn direct_room(client: &Client, user_id: &UserId) -> anyhow::Result<OwnedRoomId> {
+    let mut direct = direct_rooms(client).await?;
+
+    let existing = direct
+        .get(user_id)
+        .into_iter()
+        .flatten()
+        .find(|id| client.get_joined_room(id).is_some());
+
+    if let Some(room_id) = existing {
+        return Ok(room_id.clone());
+    }
+
+    let mut request = Request::new();
+    request.is_direct = true;
+    request.invite = vec![user_id.to_owned()];
+    request.preset = Some(RoomPreset::TrustedPrivateChat);
+    request.initial_state = vec![encryption_event()];
+
+    let room_id = create_room(client, request).await?;
+
+    direct
+        .entry(user_id.to_owned())
+        .or_default()
+        .push(room_id.clone());
+
+    let request = set_global_account_data::v3::Request::new(&direct, clients::user_id(client))?;
+    client.send(request, None).await?;
+
+    Ok(room_id)
+}
+
+async fn direct_rooms(client: &Client) -> anyhow::Result<DirectEventContent> {
+    let request = get_global_account_data::v3::Request::new(
+        clients::user_id(client),
+        GlobalAccountDataEventType::Direct,
+    );
+
+    match client.send(request, None).await {
+        Ok(response) => Ok(response.account_data.deserialize_as()?),
+        Err(err) if not_found(&err) => Ok(DirectEventContent::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn encryption_event() -> ruma::serde::Raw<ruma::events::AnyInitialStateEvent> {
+    InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults()).to_raw_any()
+}
+
+fn not_found(err: &HttpError) -> bool {
+    matches!(err.client_api_error_kind(), Some(ErrorKind::NotFound))
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset;
+    use matrix_sdk::ruma::api::client::room::Visibility;
+
+    use super::room_request;
+
+    #[test]
+    fn test_room_request() {
+        let private = room_request("Private".to_string(), None, true, false);
+
+        assert_eq!(private.name.as_deref(), Some("Private"));
+        assert_eq!(private.preset, Some(RoomPreset::PrivateChat));
+        assert_eq!(private.visibility, Visibility::Private);
+        assert_eq!(private.initial_state.len(), 1);
+
+        let public = room_request(
+            "Public".to_string(),
+            Some("Everyone's welcome".to_string()),
+            false,
+            true,
+        );
+
+        assert_eq!(public.topic.as_deref(), Some("Everyone's welcome"));
+        assert_eq!(public.preset, Some(RoomPreset::PublicChat));
+        assert_eq!(public.visibility, Visibility::Public);
+        assert!(public.initial_state.is_empty());
+    }
+}
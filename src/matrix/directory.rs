@@ -0,0 +1,90 @@
+//! Browsing public room directories, ours or any other homeserver's.
+
+use anyhow::anyhow;
+use matrix_sdk::ruma::api::client::directory::get_public_rooms_filtered;
+use matrix_sdk::ruma::directory::{Filter, PublicRoomsChunk};
+use matrix_sdk::Client;
+use ruma::{OwnedServerName, UInt};
+
+// rooms per page
+const PAGE_SIZE: u32 = 30;
+
+/// What we're looking for, and where.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirectoryQuery {
+    pub server: Option<OwnedServerName>,
+    pub term: String,
+}
+
+impl DirectoryQuery {
+    /// An empty server means our own homeserver's directory.
+    pub fn parse(server: &str, term: &str) -> anyhow::Result<Self> {
+        let server = server.trim();
+
+        let server = if server.is_empty() {
+            None
+        } else {
+            Some(
+                OwnedServerName::try_from(server)
+                    .map_err(|_| anyhow!("{} isn't a server name.", server))?,
+            )
+        };
+
+        Ok(DirectoryQuery {
+            server,
+            term: term.trim().to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DirectoryPage {
+    pub rooms: Vec<PublicRoomsChunk>,
+
+    /// Where the next page starts, if there is one.
+    pub next_batch: Option<String>,
+    pub total: Option<u64>,
+}
+
+pub async fn public_rooms(
+    client: &Client,
+    query: &DirectoryQuery,
+    since: Option<String>,
+) -> anyhow::Result<DirectoryPage> {
+    let mut filter = Filter::new();
+
+    if !query.term.is_empty() {
+        filter.generic_search_term = Some(query.term.clone());
+    }
+
+    let mut request = get_public_rooms_filtered::v3::Request::new();
+    request.server = query.server.clone();
+    request.limit = Some(UInt::from(PAGE_SIZE));
+    request.since = since;
+    request.filter = filter;
+
+    let response = client.send(request, None).await?;
+
+    Ok(DirectoryPage {
+        rooms: response.chunk,
+        next_batch: response.next_batch,
+        total: response.total_room_count_estimate.map(u64::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirectoryQuery;
+
+    #[test]
+    fn test_parse_query() {
+        let own = DirectoryQuery::parse(" ", " rust ").unwrap();
+        assert!(own.server.is_none());
+        assert_eq!(own.term, "rust");
+
+        let other = DirectoryQuery::parse("matrix.org", "").unwrap();
+        assert_eq!(other.server.unwrap().as_str(), "matrix.org");
+
+        assert!(DirectoryQuery::parse("not a server", "").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, bail};
+use matrix_sdk::reqwest::{self, StatusCode};
+use matrix_sdk::ruma::exports::serde_json;
+use matrix_sdk::ServerName;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct WellKnown {
+    #[serde(rename = "m.homeserver")]
+    homeserver: Option<HomeserverInfo>,
+}
+
+#[derive(Deserialize)]
+struct HomeserverInfo {
+    base_url: Option<String>,
+}
+
+/// Figure out the base URL we should talk to, either from what the user typed
+/// in, or by asking the server itself.
+pub async fn resolve_homeserver(
+    server: Option<&ServerName>,
+    explicit: Option<&str>,
+) -> anyhow::Result<String> {
+    if let Some(url) = explicit.map(str::trim).filter(|u| !u.is_empty()) {
+        return Ok(normalize_url(url));
+    }
+
+    match server {
+        Some(server) => discover_homeserver(server).await,
+        None => bail!("Enter your Matrix ID or a homeserver URL."),
+    }
+}
+
+/// Client-server discovery, as described in the spec:
+/// https://spec.matrix.org/latest/client-server-api/#well-known-uri
+async fn discover_homeserver(server: &ServerName) -> anyhow::Result<String> {
+    let well_known = format!("https://{}/.well-known/matrix/client", server);
+
+    let response = reqwest::get(&well_known).await.map_err(|e| {
+        anyhow!(
+            "Could not reach {} to discover its homeserver: {}",
+            server,
+            e
+        )
+    })?;
+
+    // no well-known file just means the server name is the homeserver
+    let base_url = match response.status() {
+        StatusCode::NOT_FOUND => format!("https://{}", server),
+        status if status.is_success() => {
+            let body = response.text().await?;
+
+            parse_well_known(&body)
+                .map_err(|e| anyhow!("Homeserver discovery failed for {}: {}", server, e))?
+        }
+        status => bail!(
+            "Homeserver discovery failed for {}: {} returned {}.",
+            server,
+            well_known,
+            status
+        ),
+    };
+
+    check_homeserver(&base_url).await?;
+
+    Ok(base_url)
+}
+
+/// Make sure there's actually a Matrix server at the other end.
+async fn check_homeserver(base_url: &str) -> anyhow::Result<()> {
+    let versions = format!("{}/_matrix/client/versions", base_url);
+
+    let response = reqwest::get(&versions)
+        .await
+        .map_err(|e| anyhow!("Could not reach the homeserver at {}: {}", base_url, e))?;
+
+    if !response.status().is_success() {
+        bail!(
+            "{} doesn't look like a Matrix homeserver ({}).",
+            base_url,
+            response.status()
+        );
+    }
+
+    Ok(())
+}
+
+fn parse_well_known(body: &str) -> anyhow::Result<String> {
+    let well_known: WellKnown = serde_json::from_str(body)
+        .map_err(|_| anyhow!("the .well-known file is not valid JSON."))?;
+
+    let base_url = well_known
+        .homeserver
+        .and_then(|h| h.base_url)
+        .filter(|u| !u.trim().is_empty())
+        .ok_or_else(|| anyhow!("the .well-known file has no homeserver base URL."))?;
+
+    if !base_url.starts_with("https://") && !base_url.starts_with("http://") {
+        bail!("the .well-known base URL ({}) is not valid.", base_url);
+    }
+
+    Ok(normalize_url(&base_url))
+}
+
+/// Allow folks to leave off the scheme, and drop any trailing slashes.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("https://{}", url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_url, parse_well_known};
+
+    #[test]
+    fn it_parses_well_known() {
+        let body = r#"{"m.homeserver": {"base_url": "https://matrix.example.com/"}}"#;
+
+        assert_eq!(
+            parse_well_known(body).unwrap(),
+            "https://matrix.example.com"
+        );
+    }
+
+    #[test]
+    fn it_rejects_bad_well_known() {
+        assert!(parse_well_known("<html>").is_err());
+        assert!(parse_well_known("{}").is_err());
+        assert!(parse_well_known(r#"{"m.homeserver": {}}"#).is_err());
+        assert!(parse_well_known(r#"{"m.homeserver": {"base_url": "matrix"}}"#).is_err());
+    }
+
+    #[test]
+    fn it_normalizes_urls() {
+        assert_eq!(normalize_url("example.com"), "https://example.com");
+        assert_eq!(
+            normalize_url(" http://localhost:8008/ "),
+            "http://localhost:8008"
+        );
+    }
+}
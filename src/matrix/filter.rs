@@ -0,0 +1,124 @@
+//! The filter we sync with, which the config can trim down for slow
+//! connections. The homeserver keeps filters for us, so each one is uploaded
+//! once and we sync with its ID from then on.
+
+use log::{error, warn};
+use matrix_sdk::ruma::api::client::filter::{
+    Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
+};
+use matrix_sdk::ruma::api::client::sync::sync_events;
+use matrix_sdk::ruma::exports::serde_json;
+use matrix_sdk::Client;
+use ruma::{OwnedRoomId, UInt};
+use sha2::{Digest, Sha256};
+
+use crate::settings::SyncFilter;
+
+pub fn definition(settings: &SyncFilter) -> FilterDefinition {
+    // we load members as we need them
+    let mut state_filter = RoomEventFilter::empty();
+    state_filter.lazy_load_options = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+    state_filter.not_types = settings.skip_state_types.clone();
+
+    let mut timeline_filter = RoomEventFilter::empty();
+    timeline_filter.limit = settings.timeline_limit.map(UInt::from);
+
+    let mut room_filter = RoomFilter::empty();
+    room_filter.state = state_filter;
+    room_filter.timeline = timeline_filter;
+    room_filter.not_rooms = settings
+        .exclude_rooms
+        .iter()
+        .filter_map(|id| match OwnedRoomId::try_from(id.as_str()) {
+            Ok(id) => Some(id),
+            Err(err) => {
+                warn!("not excluding {} from sync: {}", id, err);
+                None
+            }
+        })
+        .collect();
+
+    if !settings.ephemeral {
+        room_filter.ephemeral = RoomEventFilter::ignore_all();
+    }
+
+    let mut filter = FilterDefinition::empty();
+    filter.room = room_filter;
+
+    if !settings.presence {
+        filter.presence = Filter::ignore_all();
+    }
+
+    filter
+}
+
+/// Filters are saved by name, so the name changes along with the filter.
+pub fn name(definition: &FilterDefinition) -> anyhow::Result<String> {
+    let json = serde_json::to_string(definition)?;
+    let hash = format!("{:x}", Sha256::digest(json.as_bytes()));
+
+    Ok(format!("matui-{}", &hash[..16]))
+}
+
+/// The ID of the filter for the current config, uploading it if we have to.
+/// If that doesn't work out, we send the whole thing with each sync instead.
+pub async fn sync_filter(client: &Client, settings: &SyncFilter) -> sync_events::v3::Filter {
+    let definition = definition(settings);
+
+    let name = match name(&definition) {
+        Ok(name) => name,
+        Err(err) => {
+            error!("could not name the sync filter: {}", err);
+            return definition.into();
+        }
+    };
+
+    match client.get_or_upload_filter(&name, definition.clone()).await {
+        Ok(id) => sync_events::v3::Filter::FilterId(id),
+        Err(err) => {
+            error!("could not upload the sync filter: {}", err);
+            definition.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::api::client::filter::LazyLoadOptions;
+    use ruma::UInt;
+
+    use crate::settings::SyncFilter;
+
+    use super::{definition, name};
+
+    #[test]
+    fn test_definition() {
+        let default = definition(&SyncFilter::default());
+
+        assert!(default.room.timeline.limit.is_none());
+        assert!(default.room.not_rooms.is_empty());
+        assert!(default.presence.types.is_none());
+        assert!(matches!(
+            default.room.state.lazy_load_options,
+            LazyLoadOptions::Enabled { .. }
+        ));
+
+        let trimmed = definition(&SyncFilter {
+            timeline_limit: Some(5),
+            skip_state_types: vec!["m.room.topic".to_string()],
+            exclude_rooms: vec!["!room:example.org".to_string(), "nope".to_string()],
+            presence: false,
+            ephemeral: false,
+        });
+
+        assert_eq!(trimmed.room.timeline.limit, Some(UInt::from(5u32)));
+        assert_eq!(trimmed.room.state.not_types, vec!["m.room.topic"]);
+        assert_eq!(trimmed.room.not_rooms.len(), 1);
+        assert_eq!(trimmed.presence.types, Some(vec![]));
+        assert_eq!(trimmed.room.ephemeral.types, Some(vec![]));
+
+        assert_ne!(name(&default).unwrap(), name(&trimmed).unwrap());
+    }
+}
@@ -0,0 +1,112 @@
+//! Joining rooms by whatever the user has on hand: an alias, a room ID, or
+//! a link to either.
+
+use anyhow::{anyhow, bail};
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::Client;
+use ruma::matrix_uri::MatrixId;
+use ruma::{
+    MatrixToUri, MatrixUri, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, RoomOrAliasId,
+};
+
+/// The room someone typed in or pasted, along with any servers the link
+/// suggests we join through.
+pub fn parse_room(input: &str) -> anyhow::Result<(OwnedRoomOrAliasId, Vec<OwnedServerName>)> {
+    let input = input.trim();
+
+    if input.starts_with("https://matrix.to/") {
+        let uri = MatrixToUri::parse(input).map_err(|e| anyhow!("Invalid link: {}", e))?;
+        return from_matrix_id(uri.id(), uri.via());
+    }
+
+    if input.starts_with("matrix:") {
+        let uri = MatrixUri::parse(input).map_err(|e| anyhow!("Invalid link: {}", e))?;
+        return from_matrix_id(uri.id(), uri.via());
+    }
+
+    match OwnedRoomOrAliasId::try_from(input) {
+        Ok(room) => Ok((room, vec![])),
+        Err(_) => bail!("Enter a room alias (#room:server), ID (!id:server) or link."),
+    }
+}
+
+fn from_matrix_id(
+    id: &MatrixId,
+    via: &[OwnedServerName],
+) -> anyhow::Result<(OwnedRoomOrAliasId, Vec<OwnedServerName>)> {
+    let room = match id {
+        MatrixId::Room(room_id) => room_id.clone().into(),
+        MatrixId::RoomAlias(alias) => alias.clone().into(),
+        MatrixId::Event(room, _) => room.clone(),
+        _ => bail!("That's not a link to a room."),
+    };
+
+    Ok((room, via.to_vec()))
+}
+
+/// Join the room, if we haven't already, returning its ID.
+pub async fn join(
+    client: &Client,
+    room: &RoomOrAliasId,
+    mut via: Vec<OwnedServerName>,
+) -> anyhow::Result<OwnedRoomId> {
+    let room_id = match OwnedRoomId::try_from(room.to_owned()) {
+        Ok(room_id) => room_id,
+        Err(alias) => {
+            let response = client
+                .resolve_room_alias(&alias)
+                .await
+                .map_err(|e| explain(e.client_api_error_kind(), e.to_string(), room))?;
+
+            via.extend(response.servers);
+            response.room_id
+        }
+    };
+
+    if client.get_joined_room(&room_id).is_some() {
+        return Ok(room_id);
+    }
+
+    client
+        .join_room_by_id_or_alias((&*room_id).into(), &via)
+        .await
+        .map_err(|e| explain(e.client_api_error_kind(), e.to_string(), room))?;
+
+    Ok(room_id)
+}
+
+// the homeserver's errors, in plainer words where we can
+fn explain(kind: Option<&ErrorKind>, err: String, room: &RoomOrAliasId) -> anyhow::Error {
+    match kind {
+        Some(ErrorKind::NotFound) => anyhow!("Couldn't find {}.", room),
+        Some(ErrorKind::Forbidden) => anyhow!("You're not allowed to join {}.", room),
+        _ => anyhow!(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_room;
+
+    #[test]
+    fn test_parse_room() {
+        let (room, via) = parse_room(" #matui:matrix.org ").unwrap();
+        assert_eq!(room.as_str(), "#matui:matrix.org");
+        assert!(via.is_empty());
+
+        let (room, _) = parse_room("!abc123:matrix.org").unwrap();
+        assert_eq!(room.as_str(), "!abc123:matrix.org");
+
+        let (room, via) =
+            parse_room("https://matrix.to/#/!abc123:matrix.org?via=example.org").unwrap();
+        assert_eq!(room.as_str(), "!abc123:matrix.org");
+        assert_eq!(via.len(), 1);
+        assert_eq!(via[0].as_str(), "example.org");
+
+        let (room, _) = parse_room("matrix:r/matui:matrix.org").unwrap();
+        assert_eq!(room.as_str(), "#matui:matrix.org");
+
+        assert!(parse_room("matui").is_err());
+        assert!(parse_room("https://matrix.to/#/@someone:matrix.org").is_err());
+    }
+}
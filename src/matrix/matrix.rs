@@ -1,36 +1,48 @@
 use crate::matrix::matrix::MessageType::File;
 use std::{fs, thread};
 
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::{channel, Sender, TryRecvError};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context};
+use futures::future::join_all;
+use futures::pin_mut;
 use futures::stream::StreamExt;
-use log::{error, info};
+use log::{error, info, warn};
 use matrix_sdk::attachment::AttachmentConfig;
 use matrix_sdk::config::SyncSettings;
-use matrix_sdk::encryption::verification::{Emoji, SasState, SasVerification, Verification};
-use matrix_sdk::media::{MediaFormat, MediaRequest};
-use matrix_sdk::room::{Joined, MessagesOptions, Receipts, Room};
-use matrix_sdk::ruma::api::client::filter::{
-    FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
+use matrix_sdk::deserialized_responses::EncryptionInfo;
+use matrix_sdk::encryption::verification::{
+    Emoji, QrVerification, SasState, SasVerification, Verification, VerificationRequest,
 };
+use matrix_sdk::encryption::RoomKeyImportResult;
+use matrix_sdk::media::{MediaFormat, MediaRequest};
+use matrix_sdk::room::{Invited, Joined, MessagesOptions, Receipts, Room};
+use matrix_sdk::ruma::api::client::context::get_context;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::uiaa::{self, AuthData, AuthType, UiaaInfo, UserIdentifier};
 use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::ruma::events::forwarded_room_key::ToDeviceForwardedRoomKeyEvent;
 use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
 use matrix_sdk::ruma::events::key::verification::start::{
     OriginalSyncKeyVerificationStartEvent, ToDeviceKeyVerificationStartEvent,
 };
 use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent};
-use matrix_sdk::ruma::exports::serde_json;
-use matrix_sdk::ruma::UserId;
-use matrix_sdk::{Client, LoopCtrl, ServerName, Session};
-use once_cell::sync::OnceCell;
+use matrix_sdk::ruma::events::room_key::ToDeviceRoomKeyEvent;
+use matrix_sdk::ruma::{OwnedServerName, UserId};
+use matrix_sdk::{Client, LoopCtrl, ServerName};
 use rand::rngs::OsRng;
 use rand::{distributions::Alphanumeric, Rng};
 use ruma::events::key::verification::VerificationMethod;
 use ruma::events::reaction::ReactionEventContent;
+use ruma::events::room::member::{MembershipState, StrippedRoomMemberEvent};
+use ruma::serde::Raw;
 
 use ruma::events::relation::Annotation;
 use ruma::events::room::message::MessageType::Image;
@@ -40,8 +52,10 @@ use ruma::events::{
     AnyMessageLikeEvent, AnySyncEphemeralRoomEvent, AnySyncTimelineEvent, AnyTimelineEvent,
     MessageLikeEvent, OriginalMessageLikeEvent, SyncEphemeralRoomEvent,
 };
-use ruma::{OwnedEventId, OwnedUserId, UInt};
-use serde::{Deserialize, Serialize};
+use ruma::{
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedRoomOrAliasId,
+    OwnedUserId, RoomId, TransactionId, UInt,
+};
 use tokio::runtime::Runtime;
 
 use crate::app::App;
@@ -50,20 +64,56 @@ use crate::event::Event::Matui;
 use crate::handler::MatuiEvent::{
     Error, ProgressComplete, ProgressStarted, VerificationCompleted, VerificationStarted,
 };
-use crate::handler::{Batch, MatuiEvent, SyncType};
-use crate::matrix::roomcache::{DecoratedRoom, RoomCache};
-use crate::spawn::{save_file, view_file};
-
+use crate::handler::{Batch, MatuiEvent, PassphraseType, Reauth, SyncType};
+use crate::matrix::backup::{self, BackupKey};
+use crate::matrix::clients::{self, Clients, Connection};
+use crate::matrix::outbox::{Outbox, OutboxItem, Outgoing};
+use crate::matrix::roomcache::{DecoratedInvite, DecoratedRoom, RoomCache};
+use crate::matrix::session::{self, ClientSession, FullSession};
+use crate::matrix::timeline::TimelineCache;
+use crate::settings::{key_backup, sliding_sync_proxy, sync_filter};
+use crate::spawn::{make_unique, open_url, save_file, view_file};
+
+use super::create;
+use super::directory::{self, DirectoryQuery};
+use super::discovery::resolve_homeserver;
+use super::filter;
+use super::join;
 use super::mime::mime_from_path;
 use super::notify::Notify;
+use super::sliding;
+use super::sso::{callback_path, wait_for_login_token};
+
+/// How many pages we'll backfill to meet up with the cached timeline, before
+/// we give up on it and start over.
+const MAX_GAP_PAGES: usize = 8;
+
+/// How many times in a row we'll try to send something before we give up and
+/// let the user decide.
+const MAX_SEND_ATTEMPTS: u32 = 6;
+
+/// How long we'll wait for a room we joined to come down with a sync.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we'll wait for the other side to answer a verification request.
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The longest we'll wait between syncs, when they keep failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How many failed syncs in a row before we call it offline.
+const OFFLINE_AFTER: u32 = 3;
 
 /// A Matrix client that maintains it's own Tokio runtime
 #[derive(Clone)]
 pub struct Matrix {
     rt: Arc<Runtime>,
-    client: Arc<OnceCell<Client>>,
+    clients: Arc<Clients>,
     room_cache: Arc<RoomCache>,
     notify: Arc<Notify>,
+    reconnect: Arc<tokio::sync::Notify>,
+    timeline_cache: Arc<TimelineCache>,
+    outbox: Arc<Outbox>,
 }
 
 /// What should we do with the file after we download it?
@@ -80,169 +130,1249 @@ impl Default for Matrix {
             .build()
             .unwrap();
 
-        Matrix {
-            rt: Arc::new(rt),
-            client: Arc::new(OnceCell::default()),
-            room_cache: Arc::new(RoomCache::default()),
-            notify: Arc::new(Notify::default()),
-        }
-    }
-}
+        Matrix {
+            rt: Arc::new(rt),
+            clients: Arc::new(Clients::default()),
+            room_cache: Arc::new(RoomCache::default()),
+            notify: Arc::new(Notify::default()),
+            reconnect: Arc::new(tokio::sync::Notify::new()),
+            timeline_cache: Arc::new(TimelineCache::default()),
+            outbox: Arc::new(Outbox::default()),
+        }
+    }
+}
+
+impl Matrix {
+    /// The client for the active account.
+    fn client(&self) -> Client {
+        self.clients.active().expect("client expected but not set")
+    }
+
+    /// Invites that are still waiting on us.
+    pub fn fetch_invites(&self) -> Vec<DecoratedInvite> {
+        let mut invites = self.room_cache.get_invites();
+
+        // they might have been answered from another client
+        invites.retain(|i| {
+            self.clients
+                .get(i.account())
+                .and_then(|c| c.get_invited_room(i.room_id()))
+                .is_some()
+        });
+
+        invites
+    }
+
+    pub fn invite_event(&self, room: Invited) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            let invite = match matrix.room_cache.add_invite(room).await {
+                Some(invite) => invite,
+                None => return,
+            };
+
+            if let Err(e) = matrix.notify.invite_event(&invite).await {
+                error!("could not send notification: {}", e.to_string());
+            }
+        });
+    }
+
+    pub fn accept_invite(&self, room: Invited) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted("Joining room.".to_string(), 0));
+
+            if let Err(err) = room.accept_invitation().await {
+                Matrix::send(Error(err.to_string()));
+                return;
+            }
+
+            matrix
+                .room_cache
+                .remove_invite(room.own_user_id(), room.room_id());
+
+            matrix.open_joined(&room.client(), room.room_id()).await;
+        });
+    }
+
+    pub fn decline_invite(&self, room: Invited) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted("Declining invite.".to_string(), 500));
+
+            if let Err(err) = room.reject_invitation().await {
+                Matrix::send(Error(err.to_string()));
+                return;
+            }
+
+            matrix
+                .room_cache
+                .remove_invite(room.own_user_id(), room.room_id());
+
+            Matrix::send(ProgressComplete);
+        });
+    }
+
+    /// Leave the room and, if asked, forget it too so it won't come back in
+    /// our history.
+    pub fn leave_room(&self, room: Joined, forget: bool) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            let msg = if forget {
+                "Forgetting room."
+            } else {
+                "Leaving room."
+            };
+
+            Matrix::send(ProgressStarted(msg.to_string(), 500));
+
+            let left = match room.leave().await {
+                Ok(left) => left,
+                Err(err) => {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            };
+
+            if forget {
+                if let Err(err) = left.forget().await {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            }
+
+            matrix
+                .timeline_cache
+                .remove_room(room.own_user_id(), room.room_id());
+
+            Matrix::send(ProgressComplete);
+            Matrix::send(MatuiEvent::RoomLeft(room));
+        });
+    }
+
+    pub fn remove_room(&self, room: &Joined) {
+        self.room_cache
+            .remove_room(room.own_user_id(), room.room_id());
+    }
+
+    /// Join a room with the active account, and switch to it.
+    pub fn join_room(&self, room: OwnedRoomOrAliasId, via: Vec<OwnedServerName>) {
+        let client = self.client();
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted(format!("Joining {}.", room), 0));
+
+            match join::join(&client, &room, via).await {
+                Ok(room_id) => matrix.open_joined(&client, &room_id).await,
+                Err(err) => Matrix::send(Error(err.to_string())),
+            }
+        });
+    }
+
+    pub fn create_room(&self, name: String, topic: Option<String>, encrypted: bool, public: bool) {
+        let client = self.client();
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted(format!("Creating {}.", name), 0));
+
+            let request = create::room_request(name, topic, encrypted, public);
+
+            match create::create_room(&client, request).await {
+                Ok(room_id) => matrix.open_joined(&client, &room_id).await,
+                Err(err) => Matrix::send(Error(err.to_string())),
+            }
+        });
+    }
+
+    /// A page of the public room directory, which comes back to the popup
+    /// even if it fails, since other servers' directories often do.
+    pub fn public_rooms(&self, query: DirectoryQuery, since: Option<String>) {
+        let client = self.client();
+
+        self.rt.spawn(async move {
+            let appended = since.is_some();
+
+            let page = directory::public_rooms(&client, &query, since)
+                .await
+                .map_err(|e| e.to_string());
+
+            Matrix::send(MatuiEvent::Directory(query, appended, page));
+        });
+    }
+
+    pub fn search_users(&self, term: String) {
+        let client = self.client();
+
+        self.rt.spawn(async move {
+            let users = create::search_users(&client, &term)
+                .await
+                .map_err(|e| e.to_string());
+
+            Matrix::send(MatuiEvent::UsersFound(term, users));
+        });
+    }
+
+    pub fn start_dm(&self, user_id: OwnedUserId) {
+        let client = self.client();
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted(
+                format!("Opening a chat with {}.", user_id),
+                0,
+            ));
+
+            match create::direct_room(&client, &user_id).await {
+                Ok(room_id) => matrix.open_joined(&client, &room_id).await,
+                Err(err) => Matrix::send(Error(err.to_string())),
+            }
+        });
+    }
+
+    // A room we just joined only shows up as joined after the next sync, so
+    // we wait for that before we switch to it.
+    async fn open_joined(&self, client: &Client, room_id: &RoomId) {
+        let started = Instant::now();
+
+        while started.elapsed() < JOIN_TIMEOUT {
+            if let Some(joined) = client.get_joined_room(room_id) {
+                self.room_cache
+                    .add_rooms(client, &[room_id.to_owned()])
+                    .await;
+
+                Matrix::send(ProgressComplete);
+                Matrix::send(MatuiEvent::RoomSelected(joined));
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+
+        Matrix::send(Error(
+            "Joined, but the room hasn't shown up yet. It should soon.".to_string(),
+        ));
+    }
+
+    pub fn wrap_room(&self, room: &Joined) -> Option<DecoratedRoom> {
+        self.room_cache.wrap(room)
+    }
+
+    pub fn send(event: MatuiEvent) {
+        App::get_sender()
+            .send(Matui(event))
+            .expect("could not send Matrix event");
+    }
+
+    pub fn init(&self) {
+        info!("initializing matrix");
+
+        let session_files = session::session_files();
+
+        // we can't read (or write) anything until we have the passphrase
+        if session::locked(&session_files) {
+            let passphrase_type = if session_files.iter().any(|f| session::is_encrypted(f)) {
+                PassphraseType::Unlock
+            } else {
+                PassphraseType::Choose
+            };
+
+            Matrix::send(MatuiEvent::PassphraseRequired(passphrase_type));
+            return;
+        }
+
+        // before anything can change it, or we'd lose what's on disk
+        self.outbox.load();
+
+        if session_files.is_empty() {
+            Matrix::send(MatuiEvent::LoginRequired);
+            return;
+        }
+
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(MatuiEvent::SyncStarted(SyncType::Latest));
+
+            let restores = session_files.iter().map(|file| matrix.restore(file));
+            let mut errors = vec![];
+            let mut expired = vec![];
+            let mut first = None;
+
+            for result in join_all(restores).await {
+                match result {
+                    Ok(Restored::Ready(user_id)) => {
+                        first.get_or_insert(user_id);
+                    }
+                    Ok(Restored::Expired(user_id, soft_logout)) => {
+                        expired.push(MatuiEvent::SessionExpired(user_id, soft_logout))
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+
+            // the first account on disk starts out active
+            if let Some(user_id) = first {
+                matrix.clients.set_active(&user_id);
+            }
+
+            if matrix.clients.is_empty() && errors.is_empty() && expired.is_empty() {
+                Matrix::send(MatuiEvent::LoginRequired);
+                return;
+            }
+
+            if !matrix.clients.is_empty() {
+                Matrix::send(MatuiEvent::SyncComplete);
+            }
+
+            // show these after the sync, so they don't get closed right away
+            for err in errors {
+                Matrix::send(Error(err.to_string()));
+            }
+
+            for event in expired {
+                Matrix::send(event);
+            }
+        });
+    }
+
+    /// Try the passphrase for our encrypted sessions, and carry on with
+    /// startup if it works.
+    pub fn unlock(&self, passphrase: String) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted("Unlocking sessions.".to_string(), 500));
+
+            // deriving the key takes a moment, on purpose
+            match tokio::task::spawn_blocking(move || session::unlock(passphrase)).await {
+                Ok(Ok(())) => matrix.init(),
+                Ok(Err(err)) => {
+                    error!("could not unlock sessions: {}", err);
+                    Matrix::send(MatuiEvent::PassphraseRequired(PassphraseType::Incorrect));
+                }
+                Err(err) => Matrix::send(Error(err.to_string())),
+            }
+        });
+    }
+
+    async fn restore(&self, session_file: &Path) -> anyhow::Result<Restored> {
+        session::migrate(session_file)?;
+
+        let (client, token) = restore_session(session_file).await?;
+        let user_id = clients::user_id(&client);
+
+        info!("session restored from {:?}", session_file);
+        // sliding sync loads the rooms as it goes, so there's nothing to wait for
+        if sliding_sync_proxy().is_none() {
+            info!("syncing with token {:?}", token);
+
+            if let Err(err) = sync_once(client.clone(), token).await {
+                return match err.downcast_ref().and_then(soft_logout) {
+                    Some(soft_logout) => {
+                        self.expire_session(&user_id, soft_logout);
+                        Ok(Restored::Expired(user_id, soft_logout))
+                    }
+                    None => Err(err),
+                };
+            }
+
+            self.room_cache.populate(client.clone()).await;
+        }
+
+        self.clients.add(client);
+
+        Ok(Restored::Ready(user_id))
+    }
+
+    /// The homeserver doesn't know our access token anymore. After a soft
+    /// logout we keep the session around to sign back in to the same device,
+    /// otherwise it's gone for good.
+    fn expire_session(&self, user_id: &UserId, soft_logout: bool) {
+        info!("session expired for {} (soft: {})", user_id, soft_logout);
+
+        self.clients.remove(user_id);
+        self.room_cache.remove_account(user_id);
+
+        if !soft_logout {
+            self.timeline_cache.remove_account(user_id);
+            self.outbox.remove_account(user_id);
+
+            if let Err(err) = remove_session(user_id) {
+                error!("could not remove session: {}", err);
+            }
+        }
+    }
+
+    /// With `resume`, we sign back in to the device (and crypto store) of
+    /// a session that was soft logged out.
+    pub fn login(&self, username: &str, password: &str, homeserver: Option<String>, resume: bool) {
+        let user = username.to_string();
+        let pass = password.to_string();
+        let matrix = self.clone();
+        let previous = if resume {
+            previous_session(username)
+        } else {
+            None
+        };
+
+        self.rt.spawn(async move {
+            Matrix::send(MatuiEvent::LoginStarted);
+
+            let client = match login(&user, &pass, homeserver, previous).await {
+                Ok(client) => client,
+                Err(err) => {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            };
+
+            matrix.after_login(client).await;
+        });
+    }
+
+    pub fn login_sso(&self, id: &str, homeserver: Option<String>, resume: bool) {
+        let matrix = self.clone();
+        let previous = if resume { previous_session(id) } else { None };
+
+        // with an explicit homeserver, we don't need to know anything else
+        let server = if id.trim().is_empty() && homeserver.is_some() {
+            None
+        } else {
+            match server_name_from(id) {
+                Ok(server) => Some(server),
+                Err(err) => {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            }
+        };
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted(
+                "Waiting for SSO login in your browser.".to_string(),
+                0,
+            ));
+
+            let client = match login_sso(server.as_deref(), homeserver, previous).await {
+                Ok(client) => client,
+                Err(err) => {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            };
+
+            matrix.after_login(client).await;
+        });
+    }
+
+    async fn after_login(&self, client: Client) {
+        Matrix::send(MatuiEvent::LoginComplete);
+        Matrix::send(MatuiEvent::SyncStarted(SyncType::Initial));
+
+        if sliding_sync_proxy().is_none() {
+            if let Err(err) = sync_once(client.clone(), None).await {
+                Matrix::send(Error(err.to_string()));
+                return;
+            };
+
+            self.room_cache.populate(client.clone()).await;
+        }
+
+        // a brand new sign-in becomes the active account
+        self.clients.add(client.clone());
+        self.clients.set_active(&clients::user_id(&client));
+
+        Matrix::send(MatuiEvent::SyncComplete);
+
+        if let Some(user_id) = client.user_id() {
+            match client.encryption().get_user_identity(user_id).await {
+                Ok(Some(identity)) => {
+                    if let Err(err) = identity
+                        .request_verification_with_methods(vec![VerificationMethod::SasV1])
+                        .await
+                    {
+                        error!("could not request verification: {}", err);
+                    } else {
+                        info!("verification requested");
+                    }
+                }
+                // we haven't seen one yet, but that doesn't mean the server
+                // doesn't have one, and bootstrapping would replace it
+                Ok(None) => match clients::master_key(&client).await {
+                    Ok(Some(_)) => info!("cross-signing is already set up for {}", user_id),
+
+                    // we're the first client, so it's up to us
+                    Ok(None) => self.bootstrap_cross_signing(user_id.to_owned(), None),
+                    Err(err) => error!("could not query cross-signing keys: {}", err),
+                },
+                Err(err) => error!("could not get user identity: {}", err),
+            }
+        }
+    }
+
+    /// Create the cross-signing keys for an account that doesn't have any.
+    /// The homeserver will usually want the password again first, so we'll
+    /// ask for it, and come back with `auth`.
+    pub fn bootstrap_cross_signing(&self, user_id: OwnedUserId, auth: Option<AuthData>) {
+        let client = match self.clients.get(&user_id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        self.rt.spawn(async move {
+            let retry = auth.is_some();
+
+            if retry {
+                Matrix::send(ProgressStarted("Setting up cross-signing.".to_string(), 0));
+            }
+
+            let err = match client.encryption().bootstrap_cross_signing(auth).await {
+                Ok(()) => {
+                    info!("cross-signing bootstrapped for {}", user_id);
+
+                    Matrix::send(MatuiEvent::Confirm(
+                        "Cross-Signing".to_string(),
+                        "Cross-signing is set up. Any session you verify from now on will be \
+                         trusted by everyone you talk to."
+                            .to_string(),
+                    ));
+
+                    return;
+                }
+                Err(err) => err,
+            };
+
+            match err.uiaa_response() {
+                Some(info) => ask_for_password(info, Reauth::BootstrapCrossSigning(user_id), retry),
+                None => Matrix::send(Error(format!("Could not set up cross-signing: {}", err))),
+            }
+        });
+    }
+
+    /// Try again, now that we have the password.
+    pub fn reauth(&self, reauth: Reauth, session: Option<String>, password: String) {
+        match reauth {
+            Reauth::BootstrapCrossSigning(user_id) => {
+                let auth = password_auth(&user_id, session, password);
+                self.bootstrap_cross_signing(user_id, Some(auth));
+            }
+            Reauth::DeleteDevices(user_id, devices) => {
+                let auth = password_auth(&user_id, session, password);
+                self.delete_devices(user_id, devices, Some(auth));
+            }
+        }
+    }
+
+    /// Where cross-signing stands for the active account.
+    pub fn cross_signing_status(&self) {
+        let client = self.client();
+
+        self.rt.spawn(async move {
+            let user_id = clients::user_id(&client);
+
+            let identity = match client.encryption().get_user_identity(&user_id).await {
+                Ok(identity) => identity,
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not check cross-signing: {}", err)));
+                    return;
+                }
+            };
+
+            // the server might know about keys we haven't seen yet
+            let has_identity = match identity {
+                Some(_) => true,
+                None => match clients::master_key(&client).await {
+                    Ok(master_key) => master_key.is_some(),
+                    Err(err) => {
+                        Matrix::send(Error(format!("Could not check cross-signing: {}", err)));
+                        return;
+                    }
+                },
+            };
+
+            let keys = client.encryption().cross_signing_status().await;
+
+            let status = match (has_identity, keys) {
+                (false, _) => CrossSigning::Missing,
+                (true, Some(status)) if status.is_complete() => CrossSigning::Ready,
+                (true, _) => CrossSigning::Unverified,
+            };
+
+            Matrix::send(MatuiEvent::CrossSigningStatus(user_id, status));
+        });
+    }
+
+    /// Start the long-running sync for every account that isn't already.
+    pub fn sync(&self) {
+        self.start_outbox();
+
+        for client in self.clients.all() {
+            let user_id = clients::user_id(&client);
+
+            if !self.clients.start_syncing(&user_id) {
+                continue;
+            }
+
+            add_default_handlers(
+                client.clone(),
+                self.clients.clone(),
+                self.timeline_cache.clone(),
+            );
+            add_verification_handlers(client.clone());
+            self.upload_backup(client.clone());
+
+            if let Some(proxy) = sliding_sync_proxy() {
+                self.sliding_sync(client, proxy);
+                continue;
+            }
+
+            let session_file = session::session_file(&user_id);
+            let matrix = self.clone();
+
+            self.rt.spawn(async move {
+                // apparently we only need the token for sync_once
+                let sync_settings = build_sync_settings(&client, None).await;
+
+                // failures in a row, across both loops
+                let failures = Arc::new(AtomicU32::new(0));
+
+                loop {
+                    let result = client
+                        .sync_with_result_callback(sync_settings.clone(), |sync_result| {
+                            let session_file = session_file.clone();
+                            let matrix = matrix.clone();
+                            let user_id = user_id.clone();
+                            let failures = failures.clone();
+
+                            async move {
+                                // the account was logged out from under us
+                                if !matrix.clients.is_syncing(&user_id) {
+                                    return Ok(LoopCtrl::Break);
+                                }
+
+                                let response = match sync_result {
+                                    Ok(resp) => resp,
+                                    Err(err) => {
+                                        if let Some(soft_logout) = soft_logout(&err) {
+                                            matrix.expire_session(&user_id, soft_logout);
+
+                                            Matrix::send(MatuiEvent::SessionExpired(
+                                                user_id,
+                                                soft_logout,
+                                            ));
+
+                                            return Ok(LoopCtrl::Break);
+                                        }
+
+                                        error!("no sync result: {}", err.to_string());
+
+                                        let failed = failures.fetch_add(1, Ordering::SeqCst) + 1;
+                                        matrix.back_off(&user_id, failed, err.to_string()).await;
+
+                                        return Ok(LoopCtrl::Continue);
+                                    }
+                                };
+
+                                if failures.swap(0, Ordering::SeqCst) > 0 {
+                                    matrix.set_connection(&user_id, Connection::Connected);
+                                }
+
+                                // We persist the token each time to keep the disk up-to-date
+                                if let Err(err) =
+                                    session::persist_sync_token(&session_file, response.next_batch)
+                                {
+                                    error!("could not persist sync token {}", err.to_string())
+                                }
+
+                                Ok(LoopCtrl::Continue)
+                            }
+                        })
+                        .await;
+
+                    // we only break out on purpose
+                    let err = match result {
+                        Ok(()) => break,
+                        Err(err) => err,
+                    };
+
+                    error!("sync stopped: {}", err);
+
+                    if !matrix.clients.is_syncing(&user_id) {
+                        break;
+                    }
+
+                    let failed = failures.fetch_add(1, Ordering::SeqCst) + 1;
+                    matrix.back_off(&user_id, failed, err.to_string()).await;
+                }
+            });
+        }
+    }
+
+    // Like the sync above, but through a sliding sync proxy. The events go
+    // through the same handlers, so mostly we just keep the room list up to
+    // date as it grows.
+    fn sliding_sync(&self, client: Client, proxy: String) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            let user_id = clients::user_id(&client);
+
+            let sliding_sync = match sliding::proxy_url(&proxy) {
+                Ok(url) => sliding::build(&client, url).await,
+                Err(err) => Err(err),
+            };
+
+            let sliding_sync = match sliding_sync {
+                Ok(sliding_sync) => sliding_sync,
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not start sliding sync: {}", err)));
+                    return;
+                }
+            };
+
+            let mut failures = 0;
+
+            // the first rooms are the ones to show, once we have them
+            let mut loaded = false;
+
+            'sync: loop {
+                let stream = sliding_sync.stream();
+                pin_mut!(stream);
+
+                while let Some(result) = stream.next().await {
+                    // the account was logged out from under us
+                    if !matrix.clients.is_syncing(&user_id) {
+                        break 'sync;
+                    }
+
+                    match result {
+                        Ok(summary) => {
+                            if failures > 0 {
+                                failures = 0;
+                                matrix.set_connection(&user_id, Connection::Connected);
+                            }
+
+                            matrix.room_cache.add_rooms(&client, &summary.rooms).await;
+
+                            if !loaded && !summary.rooms.is_empty() {
+                                loaded = true;
+                                Matrix::send(MatuiEvent::RoomsLoaded(user_id.clone()));
+                            }
+                        }
+                        Err(err) => {
+                            if let Some(soft_logout) = soft_logout(&err) {
+                                matrix.expire_session(&user_id, soft_logout);
+                                Matrix::send(MatuiEvent::SessionExpired(user_id, soft_logout));
+                                break 'sync;
+                            }
+
+                            error!("sliding sync failed: {}", err);
+
+                            failures += 1;
+                            matrix.back_off(&user_id, failures, err.to_string()).await;
+                        }
+                    }
+                }
+
+                if !matrix.clients.is_syncing(&user_id) {
+                    break;
+                }
+
+                // the stream shouldn't end on its own, so start a new one
+                failures += 1;
+                matrix
+                    .back_off(&user_id, failures, "The sync stopped.".to_string())
+                    .await;
+            }
+
+            info!("sliding sync stopped for {}", user_id);
+        });
+    }
+
+    pub fn connection(&self, user_id: &UserId) -> Connection {
+        self.clients.connection(user_id)
+    }
+
+    /// Skip the wait, for any account that's waiting to sync again.
+    pub fn reconnect(&self) {
+        self.reconnect.notify_waiters();
+        self.outbox.wake();
+    }
+
+    fn set_connection(&self, user_id: &UserId, connection: Connection) {
+        // anything waiting to go out can try again right away
+        if connection == Connection::Connected {
+            self.outbox.wake();
+        }
+
+        if self.clients.set_connection(user_id, connection.clone()) {
+            Matrix::send(MatuiEvent::ConnectionChanged(
+                user_id.to_owned(),
+                connection,
+            ));
+        }
+    }
+
+    // Wait a little longer after each failure in a row, unless someone asks
+    // us to try again right away.
+    async fn back_off(&self, user_id: &UserId, failures: u32, err: String) {
+        let connection = if failures < OFFLINE_AFTER {
+            Connection::Reconnecting(err)
+        } else {
+            Connection::Offline(err)
+        };
+
+        self.set_connection(user_id, connection);
+
+        let delay = backoff(failures);
+        info!("trying to sync {} again in {:?}", user_id, delay);
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = self.reconnect.notified() => info!("reconnecting {} now", user_id),
+        }
+    }
+
+    pub fn accounts(&self) -> Vec<OwnedUserId> {
+        self.clients.user_ids()
+    }
+
+    pub fn active_account(&self) -> Option<OwnedUserId> {
+        self.clients.active_user_id()
+    }
+
+    pub fn set_active_account(&self, user_id: &UserId) {
+        self.clients.set_active(user_id);
+    }
+
+    pub fn logout(&self, user_id: OwnedUserId) {
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            let client = match matrix.clients.get(&user_id) {
+                Some(client) => client,
+                None => return,
+            };
+
+            Matrix::send(ProgressStarted("Logging out.".to_string(), 0));
+
+            // if the server already forgot us, there's nothing to revoke
+            if let Err(err) = client.logout().await {
+                if !matches!(
+                    err.client_api_error_kind(),
+                    Some(ErrorKind::UnknownToken { .. })
+                ) {
+                    Matrix::send(Error(err.to_string()));
+                    return;
+                }
+            }
+
+            matrix.clients.remove(&user_id);
+            matrix.room_cache.remove_account(&user_id);
+            matrix.timeline_cache.remove_account(&user_id);
+            matrix.outbox.remove_account(&user_id);
+
+            // the avatars are shared between accounts, so they stay until the last one goes
+            if matrix.clients.is_empty() {
+                matrix.notify.clear_cache();
+            }
+
+            if let Err(err) = remove_session(&user_id) {
+                Matrix::send(Error(err.to_string()));
+            }
+
+            Matrix::send(MatuiEvent::LogoutComplete(user_id));
+        });
+    }
+
+    /// Ask the homeserver about the active account's key backup.
+    pub fn backup_status(&self) {
+        let client = self.client();
+
+        self.rt.spawn(async move {
+            let user_id = clients::user_id(&client);
+
+            match backup::status(&client, backup_key(&user_id).as_ref()).await {
+                Ok(status) => Matrix::send(MatuiEvent::BackupStatus(user_id, status)),
+                Err(err) => Matrix::send(Error(format!("Could not check key backup: {}", err))),
+            }
+        });
+    }
+
+    /// The input unlocks secret storage, so we can sign the backup. Any
+    /// existing backup is only replaced when asked to.
+    pub fn enable_backup(&self, secret_storage: Option<String>, replace: bool) {
+        let client = self.client();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted("Backing up keys.".to_string(), 0));
+
+            let enabled = backup::enable(&client, secret_storage.as_deref(), replace).await;
+
+            let (key, recovery_key) = match enabled {
+                Ok(enabled) => enabled,
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not enable key backup: {}", err)));
+                    return;
+                }
+            };
+
+            let session_file = session::session_file(&clients::user_id(&client));
+
+            if let Err(err) = session::persist_backup_key(&session_file, key) {
+                error!("could not persist backup key: {}", err);
+            }
+
+            Matrix::send(MatuiEvent::Confirm(
+                "Recovery Key".to_string(),
+                format!(
+                    "Your keys are backed up. Keep this recovery key somewhere safe, \
+                     it's the only way to restore them:\n\n{}",
+                    recovery_key
+                ),
+            ));
+        });
+    }
+
+    /// The input is either a recovery key or a passphrase.
+    pub fn restore_backup(&self, input: String) {
+        let client = self.client();
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted(
+                "Restoring keys from backup.".to_string(),
+                0,
+            ));
+
+            let (key, result) = match backup::restore(&client, &input).await {
+                Ok(restored) => restored,
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not restore keys: {}", err)));
+                    return;
+                }
+            };
+
+            let session_file = session::session_file(&clients::user_id(&client));
+
+            if let Err(err) = session::persist_backup_key(&session_file, key) {
+                error!("could not persist backup key: {}", err);
+            }
+
+            room_keys_received(&result);
 
-impl Matrix {
-    fn dirs() -> (PathBuf, PathBuf) {
-        let data_dir = dirs::data_dir()
-            .expect("no data directory found")
-            .join("matui");
+            Matrix::send(MatuiEvent::Confirm(
+                "Keys Restored".to_string(),
+                format!(
+                    "Restored {} of {} keys from the backup.",
+                    result.imported_count, result.total_count
+                ),
+            ));
 
-        let session_file = data_dir.join("session");
-        (data_dir, session_file)
+            // and now that we have the key, back up anything we have
+            matrix.upload_backup(client);
+        });
     }
 
-    fn client(&self) -> Client {
-        self.client
-            .get()
-            .expect("client expected but not set")
-            .to_owned()
+    // keep the backup up-to-date with the keys we've collected
+    fn upload_backup(&self, client: Client) {
+        self.rt.spawn(async move { upload_backup(&client).await });
     }
 
-    pub fn wrap_room(&self, room: &Joined) -> Option<DecoratedRoom> {
-        self.room_cache.wrap(room)
-    }
+    /// Export every room key we have to the standard, passphrase protected,
+    /// key file.
+    pub fn export_room_keys(&self, passphrase: String) {
+        let client = self.client();
 
-    pub fn send(event: MatuiEvent) {
-        App::get_sender()
-            .send(Matui(event))
-            .expect("could not send Matrix event");
-    }
+        self.rt.spawn(async move {
+            Matrix::send(ProgressStarted("Exporting room keys.".to_string(), 0));
 
-    pub fn init(&self) {
-        info!("initializing matrix");
+            let destination = match dirs::download_dir() {
+                Some(dir) => make_unique(dir.join("matui-keys.txt")),
+                None => {
+                    Matrix::send(Error("No download directory.".to_string()));
+                    return;
+                }
+            };
 
-        let (_, session_file) = Matrix::dirs();
+            if let Err(err) = client
+                .encryption()
+                .export_room_keys(destination.clone(), &passphrase, |_| true)
+                .await
+            {
+                Matrix::send(Error(format!("Could not export room keys: {}", err)));
+                return;
+            }
 
-        if !session_file.exists() {
-            Matrix::send(MatuiEvent::LoginRequired);
-            return;
-        }
+            Matrix::send(MatuiEvent::Confirm(
+                "Keys Exported".to_string(),
+                format!(
+                    "The room keys for {} were exported to {}",
+                    clients::user_id(&client),
+                    destination.display()
+                ),
+            ));
+        });
+    }
 
+    pub fn import_room_keys(&self, path: PathBuf, passphrase: String) {
+        let client = self.client();
         let matrix = self.clone();
 
         self.rt.spawn(async move {
-            Matrix::send(MatuiEvent::SyncStarted(SyncType::Latest));
+            Matrix::send(ProgressStarted("Importing room keys.".to_string(), 0));
 
-            let (client, token) = match restore_session(session_file.as_path()).await {
-                Ok(tuple) => tuple,
+            let result = match client
+                .encryption()
+                .import_room_keys(path, &passphrase)
+                .await
+            {
+                Ok(result) => result,
                 Err(err) => {
-                    Matrix::send(Error(err.to_string()));
+                    Matrix::send(Error(format!("Could not import room keys: {}", err)));
                     return;
                 }
             };
 
-            info!("session restored");
+            room_keys_received(&result);
 
-            matrix
-                .client
-                .set(client.clone())
-                .expect("could not set client");
+            Matrix::send(MatuiEvent::Confirm(
+                "Keys Imported".to_string(),
+                format!(
+                    "Imported {} of {} room keys.",
+                    result.imported_count, result.total_count
+                ),
+            ));
 
-            info!("syncing with token {:?}", token);
+            // the backup should have them too
+            matrix.upload_backup(client);
+        });
+    }
 
-            if let Err(err) = sync_once(client.clone(), token, &session_file).await {
-                Matrix::send(Error(err.to_string()));
-                return;
+    /// Ask another user to verify with us. The request goes to them in a
+    /// direct message.
+    pub fn verify_user(&self, room: Joined, user_id: OwnedUserId) {
+        let client = match self.clients.get(room.own_user_id()) {
+            Some(client) => client,
+            None => return,
+        };
+
+        self.rt.spawn(async move {
+            let identity = match client.encryption().get_user_identity(&user_id).await {
+                Ok(Some(identity)) => identity,
+                Ok(None) => {
+                    Matrix::send(Error(format!(
+                        "{} hasn't set up cross-signing, so they can't be verified.",
+                        user_id
+                    )));
+                    return;
+                }
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not verify {}: {}", user_id, err)));
+                    return;
+                }
             };
 
-            matrix.room_cache.populate(client).await;
+            Matrix::send(ProgressStarted(
+                format!("Waiting for {} to accept.", user_id),
+                0,
+            ));
+
+            let result = match identity.request_verification().await {
+                Ok(request) => wait_for_ready(&client, request).await,
+                Err(err) => Err(err.into()),
+            };
 
-            Matrix::send(MatuiEvent::SyncComplete);
+            if let Err(err) = result {
+                Matrix::send(Error(format!("Could not verify {}: {}", user_id, err)));
+            }
         });
     }
 
-    pub fn login(&self, username: &str, password: &str) {
-        let (data_dir, session_file) = Matrix::dirs();
-        let user = username.to_string();
-        let pass = password.to_string();
-        let matrix = self.clone();
+    /// Verify another one of our own sessions.
+    pub fn verify_device(&self, device_id: OwnedDeviceId) {
+        let client = self.client();
 
         self.rt.spawn(async move {
-            Matrix::send(MatuiEvent::LoginStarted);
+            let user_id = clients::user_id(&client);
 
-            let client = match login(&data_dir, &session_file, &user, &pass).await {
-                Ok(client) => client,
+            let device = match client.encryption().get_device(&user_id, &device_id).await {
+                Ok(Some(device)) => device,
+                Ok(None) => {
+                    Matrix::send(Error(format!("There's no session {}.", device_id)));
+                    return;
+                }
                 Err(err) => {
-                    Matrix::send(Error(err.to_string()));
+                    Matrix::send(Error(format!("Could not verify {}: {}", device_id, err)));
                     return;
                 }
             };
 
-            matrix
-                .client
-                .set(client.clone())
-                .expect("could not set client");
-
-            Matrix::send(MatuiEvent::LoginComplete);
-            Matrix::send(MatuiEvent::SyncStarted(SyncType::Initial));
+            Matrix::send(ProgressStarted(
+                format!("Accept the request in {} to continue.", device_id),
+                0,
+            ));
 
-            if let Err(err) = sync_once(client.clone(), None, &session_file).await {
-                Matrix::send(Error(err.to_string()));
-                return;
+            let result = match device.request_verification().await {
+                Ok(request) => wait_for_ready(&client, request).await,
+                Err(err) => Err(err.into()),
             };
 
-            matrix.room_cache.populate(client.clone()).await;
+            if let Err(err) = result {
+                Matrix::send(Error(format!("Could not verify {}: {}", device_id, err)));
+            }
+        });
+    }
 
-            Matrix::send(MatuiEvent::SyncComplete);
+    /// Our own sessions, and whether we trust them.
+    pub fn fetch_devices(&self) {
+        let client = self.client();
 
-            if let Some(user_id) = client.user_id() {
-                match client.encryption().get_user_identity(user_id).await {
-                    Ok(Some(identity)) => {
-                        if let Err(err) = identity
-                            .request_verification_with_methods(vec![VerificationMethod::SasV1])
-                            .await
-                        {
-                            error!("could not request verification: {}", err);
-                        } else {
-                            info!("verification requested");
-                        }
-                    }
-                    Ok(None) => error!("no user identity"),
-                    Err(err) => error!("could not get user identity: {}", err),
+        self.rt.spawn(async move {
+            let user_id = clients::user_id(&client);
+
+            let response = match client.devices().await {
+                Ok(response) => response,
+                Err(err) => {
+                    Matrix::send(Error(format!("Could not fetch sessions: {}", err)));
+                    return;
                 }
+            };
+
+            let mut devices = vec![];
+
+            for device in response.devices {
+                // only the crypto store knows if we trust it
+                let verified = match client
+                    .encryption()
+                    .get_device(&user_id, &device.device_id)
+                    .await
+                {
+                    Ok(Some(d)) => d.is_verified(),
+                    _ => false,
+                };
+
+                devices.push(OwnDevice {
+                    current: client.device_id() == Some(&device.device_id),
+                    device_id: device.device_id,
+                    display_name: device.display_name,
+                    last_seen_ip: device.last_seen_ip,
+                    last_seen: device.last_seen_ts,
+                    verified,
+                });
             }
+
+            Matrix::send(MatuiEvent::Devices(user_id, devices));
         });
     }
 
-    pub fn sync(&self) {
-        add_default_handlers(self.client());
-        add_verification_handlers(self.client());
-
+    pub fn rename_device(&self, device_id: OwnedDeviceId, name: String) {
         let client = self.client();
+        let matrix = self.clone();
+
+        self.rt.spawn(async move {
+            if let Err(err) = client.rename_device(&device_id, &name).await {
+                Matrix::send(Error(format!("Could not rename {}: {}", device_id, err)));
+                return;
+            }
+
+            matrix.fetch_devices();
+        });
+    }
 
-        // apparently we only need the token for sync_once
-        let sync_settings = build_sync_settings(None);
+    /// Sign out other sessions. The homeserver will want the password for
+    /// this, so we'll ask, and come back with `auth`.
+    pub fn delete_devices(
+        &self,
+        user_id: OwnedUserId,
+        devices: Vec<OwnedDeviceId>,
+        auth: Option<AuthData>,
+    ) {
+        let client = match self.clients.get(&user_id) {
+            Some(client) => client,
+            None => return,
+        };
 
         self.rt.spawn(async move {
-            client
-                .sync_with_result_callback(sync_settings, |sync_result| async move {
-                    let response = match sync_result {
-                        Ok(resp) => resp,
-                        Err(err) => {
-                            error!("no sync result: {}", err.to_string());
-                            return Ok(LoopCtrl::Continue);
-                        }
-                    };
+            let retry = auth.is_some();
 
-                    let (_, session_file) = Matrix::dirs();
+            Matrix::send(ProgressStarted("Deleting sessions.".to_string(), 500));
 
-                    // We persist the token each time to keep the disk up-to-date
-                    if let Err(err) = persist_sync_token(&session_file, response.next_batch) {
-                        error!("could not persist sync token {}", err.to_string())
-                    }
+            let err = match client.delete_devices(&devices, auth).await {
+                Ok(_) => {
+                    Matrix::send(MatuiEvent::Confirm(
+                        "Sessions Deleted".to_string(),
+                        format!("Deleted {} session(s).", devices.len()),
+                    ));
 
-                    Ok(LoopCtrl::Continue)
-                })
-                .await
-                .expect("could not sync");
+                    return;
+                }
+                Err(err) => err,
+            };
+
+            match err.as_uiaa_response() {
+                Some(info) => {
+                    ask_for_password(info, Reauth::DeleteDevices(user_id, devices), retry)
+                }
+                None => Matrix::send(Error(format!("Could not delete sessions: {}", err))),
+            }
+        });
+    }
+
+    pub fn accept_verification(&self, request: VerificationRequest) {
+        self.rt.spawn(async move {
+            if let Err(err) = request.accept().await {
+                error!("could not accept verification request: {}", err);
+                Matrix::send(Error(format!("Could not accept verification: {}", err)));
+                return;
+            }
+
+            // otherwise, we wait for them to start comparing emoji
+            if let Err(err) = show_qr_code(&request).await {
+                error!("could not show a QR code: {}", err);
+            }
+        });
+    }
+
+    /// Compare emoji, rather than scanning a QR code.
+    pub fn start_sas_verification(&self, request: VerificationRequest) {
+        self.rt.spawn(async move {
+            if let Err(err) = start_sas(&request).await {
+                Matrix::send(Error(format!("Could not verify: {}", err)));
+            }
+        });
+    }
+
+    /// The other side scanned our QR code, and says it's all good.
+    pub fn confirm_qr_verification(&self, qr: QrVerification) {
+        self.rt.spawn(async move {
+            if let Err(err) = qr.confirm().await {
+                error!("could not confirm QR verification: {}", err);
+                Matrix::send(Error(format!("Could not verify: {}", err)));
+            }
+        });
+    }
+
+    pub fn mismatched_qr_verification(&self, qr: QrVerification) {
+        self.rt.spawn(async move {
+            if let Err(err) = qr.cancel().await {
+                error!("could not cancel QR verification: {}", err);
+            }
+        });
+    }
+
+    pub fn decline_verification(&self, request: VerificationRequest) {
+        self.rt.spawn(async move {
+            if let Err(err) = request.cancel().await {
+                error!("could not decline verification request: {}", err);
+                Matrix::send(Error(format!("Could not decline verification: {}", err)));
+            }
         });
     }
 
@@ -269,7 +1399,96 @@ impl Matrix {
         self.room_cache.get_rooms()
     }
 
+    /// Show what we have cached for the room right away, then backfill
+    /// whatever we missed since.
+    pub fn open_timeline(&self, room: Joined) {
+        let cached = self.timeline_cache.read(room.own_user_id(), room.room_id());
+
+        if cached.is_empty() {
+            self.fetch_messages(room, None);
+            return;
+        }
+
+        let oldest = cached.first().map(|(e, _)| e.event_id().to_owned());
+        let known: HashSet<OwnedEventId> = cached
+            .iter()
+            .map(|(e, _)| e.event_id().to_owned())
+            .collect();
+
+        Matrix::send(MatuiEvent::TimelineBatch(Batch {
+            room: room.clone(),
+            events: cached,
+            cursor: None,
+            stale: HashSet::new(),
+        }));
+
+        let cache = self.timeline_cache.clone();
+
+        self.rt.spawn(async move {
+            let mut gap: Vec<(Raw<AnySyncTimelineEvent>, Option<EncryptionInfo>)> = vec![];
+            let mut cursor = None;
+            let mut closed = false;
+
+            for _ in 0..MAX_GAP_PAGES {
+                let mut options = MessagesOptions::new(Direction::Backward);
+                options.limit = UInt::from(25_u16);
+                options.from = cursor.clone();
+
+                let messages = match room.messages(options).await {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        Matrix::send(Error(err.to_string()));
+                        return;
+                    }
+                };
+
+                for te in messages.chunk {
+                    if let Ok(e) = te.event.deserialize() {
+                        closed |= known.contains(e.event_id());
+                    }
+
+                    gap.push((te.event.cast(), te.encryption_info));
+                }
+
+                cursor = messages.end;
+
+                if closed || cursor.is_none() {
+                    break;
+                }
+            }
+
+            // carry on paging from the far side of the cache
+            if let (true, Some(oldest)) = (closed, oldest) {
+                if let Some(start) = context_start(&room, oldest).await {
+                    cursor = Some(start);
+                }
+            }
+
+            cache.caught_up(room.own_user_id(), room.room_id(), gap.clone(), closed);
+
+            let events = gap
+                .into_iter()
+                .filter_map(|(raw, encryption)| {
+                    let event = raw.deserialize().ok()?;
+                    Some((event.into_full_event(room.room_id().into()), encryption))
+                })
+                .collect();
+
+            // otherwise there'd be a hole between them and what we fetched
+            let stale = if closed { HashSet::new() } else { known };
+
+            Matrix::send(MatuiEvent::TimelineBatch(Batch {
+                room,
+                events,
+                cursor,
+                stale,
+            }));
+        });
+    }
+
     pub fn fetch_messages(&self, room: Joined, cursor: Option<String>) {
+        let cache = self.timeline_cache.clone();
+
         self.rt.spawn(async move {
             Matrix::send(ProgressStarted("Fetching more messages.".to_string(), 1000));
 
@@ -278,6 +1497,8 @@ impl Matrix {
             options.limit = UInt::from(25_u16);
             options.from = cursor;
 
+            let newest = options.from.is_none();
+
             let messages = match room.messages(options).await {
                 Ok(msg) => msg,
                 Err(err) => {
@@ -286,16 +1507,34 @@ impl Matrix {
                 }
             };
 
-            let unpacked: Vec<AnyTimelineEvent> = messages
+            let raw = messages
+                .chunk
+                .iter()
+                .map(|te| (te.event.clone().cast(), te.encryption_info.clone()))
+                .collect();
+
+            if newest {
+                cache.caught_up(room.own_user_id(), room.room_id(), raw, false);
+            } else {
+                cache.add(room.own_user_id(), room.room_id(), raw);
+            }
+
+            let unpacked: Vec<(AnyTimelineEvent, Option<EncryptionInfo>)> = messages
                 .chunk
                 .iter()
-                .map(|te| te.event.deserialize().expect("could not deserialize"))
+                .map(|te| {
+                    (
+                        te.event.deserialize().expect("could not deserialize"),
+                        te.encryption_info.clone(),
+                    )
+                })
                 .collect();
 
             let batch = Batch {
                 room: room.clone(),
                 events: unpacked,
                 cursor: messages.end,
+                stale: HashSet::new(),
             };
 
             Matrix::send(MatuiEvent::ProgressComplete);
@@ -312,9 +1551,8 @@ impl Matrix {
         });
     }
 
-    pub fn download_content(&self, message: MessageType, after: AfterDownload) {
-        let matrix = self.clone();
-
+    /// Media comes through the account the room belongs to.
+    pub fn download_content(&self, room: Joined, message: MessageType, after: AfterDownload) {
         self.rt.spawn(async move {
             Matrix::send(ProgressStarted("Downloading file.".to_string(), 250));
 
@@ -349,7 +1587,7 @@ impl Matrix {
                 }
             };
 
-            let handle = match matrix
+            let handle = match room
                 .client()
                 .media()
                 .get_media_file(&request, &content_type.parse().unwrap(), true)
@@ -380,41 +1618,11 @@ impl Matrix {
     }
 
     pub fn send_text_message(&self, room: Joined, message: String) {
-        self.rt.spawn(async move {
-            Matrix::send(ProgressStarted("Sending message.".to_string(), 500));
-
-            if let Err(err) = room
-                .send(RoomMessageEventContent::text_markdown(message), None)
-                .await
-            {
-                Matrix::send(Error(err.to_string()));
-            }
-
-            Matrix::send(ProgressComplete);
-        });
+        self.queue(&room, Outgoing::Text(message));
     }
 
     pub fn send_reply(&self, room: Joined, message: String, in_reply_to: OwnedEventId) {
-        self.rt.spawn(async move {
-            Matrix::send(ProgressStarted("Sending message.".to_string(), 500));
-
-            let in_reply_to = match Matrix::get_room_event(&room, &in_reply_to).await {
-                Some(e) => e,
-                None => {
-                    Matrix::send(Error("Could not find reply event.".to_string()));
-                    return;
-                }
-            };
-
-            let reply = RoomMessageEventContent::text_markdown(message)
-                .make_reply_to(&in_reply_to, ForwardThread::Yes);
-
-            if let Err(err) = room.send(reply, None).await {
-                Matrix::send(Error(err.to_string()));
-            }
-
-            Matrix::send(ProgressComplete);
-        });
+        self.queue(&room, Outgoing::Reply(message, in_reply_to));
     }
 
     pub fn send_attachements(&self, room: Joined, paths: Vec<PathBuf>) {
@@ -457,16 +1665,14 @@ impl Matrix {
     }
 
     pub fn send_reaction(&self, room: Joined, event_id: OwnedEventId, key: String) {
+        self.queue(&room, Outgoing::Reaction(event_id, key));
+    }
+
+    pub fn redact_event(&self, room: Joined, event_id: OwnedEventId) {
         self.rt.spawn(async move {
-            Matrix::send(ProgressStarted("Sending reaction.".to_string(), 500));
+            Matrix::send(ProgressStarted("Removing.".to_string(), 500));
 
-            if let Err(err) = room
-                .send(
-                    ReactionEventContent::new(Annotation::new(event_id, key)),
-                    None,
-                )
-                .await
-            {
+            if let Err(err) = room.redact(&event_id, None, None).await {
                 Matrix::send(Error(err.to_string()));
             }
 
@@ -474,33 +1680,48 @@ impl Matrix {
         });
     }
 
-    pub fn redact_event(&self, room: Joined, event_id: OwnedEventId) {
+    /// Try an event we couldn't decrypt again. If we still don't have the
+    /// key, the failed attempt queues a key request to our other sessions,
+    /// which goes out with the next sync.
+    pub fn decrypt_event(&self, room: Joined, event_id: OwnedEventId) {
+        let cache = self.timeline_cache.clone();
+
         self.rt.spawn(async move {
-            Matrix::send(ProgressStarted("Removing.".to_string(), 500));
+            let event = match room.event(&event_id).await {
+                Ok(event) => event,
+                Err(err) => {
+                    error!("could not fetch {} to decrypt: {}", event_id, err);
+                    return;
+                }
+            };
 
-            if let Err(err) = room.redact(&event_id, None, None).await {
-                Matrix::send(Error(err.to_string()));
+            match event.event.deserialize() {
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomEncrypted(_))) => {
+                    info!("still waiting on the key for {}", event_id)
+                }
+                Ok(decrypted) => {
+                    cache.add(
+                        room.own_user_id(),
+                        room.room_id(),
+                        vec![(event.event.cast(), event.encryption_info.clone())],
+                    );
+
+                    Matrix::send(MatuiEvent::Decrypted(decrypted, event.encryption_info))
+                }
+                Err(err) => error!("could not deserialize {}: {}", event_id, err),
             }
-
-            Matrix::send(ProgressComplete);
         });
     }
 
     async fn get_room_event(
         room: &Joined,
         id: &OwnedEventId,
-    ) -> Option<OriginalMessageLikeEvent<RoomMessageEventContent>> {
-        match room.event(id).await {
-            Ok(event) => match event.event.deserialize().unwrap() {
-                AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
-                    MessageLikeEvent::Original(c),
-                )) => Some(c),
-                _ => None,
-            },
-            Err(err) => {
-                error!("could not get in_reply_to event: {}", err);
-                None
-            }
+    ) -> matrix_sdk::Result<Option<OriginalMessageLikeEvent<RoomMessageEventContent>>> {
+        match room.event(id).await?.event.deserialize() {
+            Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                MessageLikeEvent::Original(c),
+            ))) => Ok(Some(c)),
+            _ => Ok(None),
         }
     }
 
@@ -511,45 +1732,93 @@ impl Matrix {
         message: String,
         in_reply_to: Option<OwnedEventId>,
     ) {
+        self.queue(&room, Outgoing::Replacement(id, message, in_reply_to));
+    }
+
+    /// Everything still on its way to the room, oldest first.
+    pub fn outbox(&self, room: &Joined) -> Vec<OutboxItem> {
+        let mut items = self.outbox.items();
+        items.retain(|i| i.user_id == room.own_user_id() && i.room_id == room.room_id());
+        items
+    }
+
+    pub fn retry_outgoing(&self, txn_id: &TransactionId) {
+        let outbox = self.outbox.clone();
+        let txn_id = txn_id.to_owned();
+
         self.rt.spawn(async move {
-            Matrix::send(ProgressStarted("Editing message.".to_string(), 500));
+            outbox.retry(&txn_id);
+            Matrix::send(MatuiEvent::OutboxChanged(outbox.items()));
+        });
+    }
 
-            let reply_event = match in_reply_to {
-                Some(id) => Matrix::get_room_event(&room, &id).await,
-                None => None,
-            };
+    pub fn discard_outgoing(&self, txn_id: &TransactionId) {
+        let outbox = self.outbox.clone();
+        let txn_id = txn_id.to_owned();
 
-            info!("reply event: {:?}", reply_event);
+        self.rt.spawn(async move {
+            outbox.discard(&txn_id);
+            Matrix::send(MatuiEvent::OutboxChanged(outbox.items()));
+        });
+    }
 
-            if let Err(err) = room
-                .send(
-                    RoomMessageEventContent::text_markdown(message)
-                        .make_replacement(id, reply_event.as_ref()),
-                    None,
-                )
-                .await
-            {
-                Matrix::send(Error(err.to_string()));
-            }
+    // The chat shows it as soon as it's queued, and the outbox takes it from
+    // there.
+    fn queue(&self, room: &Joined, outgoing: Outgoing) {
+        let outbox = self.outbox.clone();
+        let room = room.clone();
 
-            Matrix::send(ProgressComplete);
+        self.rt.spawn(async move {
+            // if we can't tell, it's safest to keep it off disk
+            let encrypted = room.is_encrypted().await.unwrap_or(true);
+
+            outbox.push(OutboxItem::new(
+                room.own_user_id().to_owned(),
+                room.room_id().to_owned(),
+                outgoing,
+                encrypted,
+            ));
+
+            Matrix::send(MatuiEvent::OutboxChanged(outbox.items()));
         });
     }
 
-    pub fn me(&self) -> OwnedUserId {
-        self.client().user_id().unwrap().to_owned()
+    // Every account works through its own items, so one that's stuck (or
+    // offline) doesn't hold up the others.
+    fn start_outbox(&self) {
+        for client in self.clients.all() {
+            let user_id = clients::user_id(&client);
+
+            if !self.outbox.start_sending(&user_id) {
+                continue;
+            }
+
+            let matrix = self.clone();
+            self.rt
+                .spawn(async move { send_outbox(matrix, user_id).await });
+        }
     }
 
     pub fn timeline_event(&self, event: AnyTimelineEvent) {
         let matrix = self.clone();
 
         self.rt.spawn(async move {
-            matrix
-                .room_cache
-                .timeline_event(matrix.client(), &event)
-                .await;
+            // every account in the room has its own copy to update
+            for client in matrix.clients.all() {
+                matrix.room_cache.timeline_event(client, &event).await;
+            }
+
+            // no need to notify anyone about messages from our other accounts
+            if matrix.clients.get(event.sender()).is_some() {
+                return;
+            }
+
+            let client = match matrix.clients.first_joined(event.room_id()) {
+                Some(c) => c,
+                None => return,
+            };
 
-            if let Err(e) = matrix.notify.timeline_event(matrix.client(), event).await {
+            if let Err(e) = matrix.notify.timeline_event(client, event).await {
                 error!("could not send notification: {}", e.to_string());
             }
         });
@@ -608,39 +1877,258 @@ impl Matrix {
     }
 }
 
-/// The data needed to re-build a client.
-#[derive(Debug, Serialize, Deserialize)]
-struct ClientSession {
-    homeserver: String,
-    db_path: PathBuf,
-    passphrase: String,
+async fn send_outgoing(room: &Joined, item: &OutboxItem) -> matrix_sdk::Result<()> {
+    let txn_id = Some(&*item.txn_id);
+
+    match &item.outgoing {
+        Outgoing::Text(message) => {
+            room.send(RoomMessageEventContent::text_markdown(message), txn_id)
+                .await?;
+        }
+        Outgoing::Reply(message, in_reply_to) => {
+            let mut content = RoomMessageEventContent::text_markdown(message);
+
+            if let Some(in_reply_to) = quoted_event(room, in_reply_to).await? {
+                content = content.make_reply_to(&in_reply_to, ForwardThread::Yes);
+            }
+
+            room.send(content, txn_id).await?;
+        }
+        Outgoing::Reaction(event_id, key) => {
+            let content = ReactionEventContent::new(Annotation::new(event_id.clone(), key.clone()));
+            room.send(content, txn_id).await?;
+        }
+        Outgoing::Replacement(id, message, in_reply_to) => {
+            let reply_event = match in_reply_to {
+                Some(id) => quoted_event(room, id).await?,
+                None => None,
+            };
+
+            let content = RoomMessageEventContent::text_markdown(message)
+                .make_replacement(id.clone(), reply_event.as_ref());
+
+            room.send(content, txn_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Work through an account's items in order, for as long as it's around. When
+// something fails we wait a little longer each time, unless the connection
+// comes back first.
+async fn send_outbox(matrix: Matrix, user_id: OwnedUserId) {
+    let outbox = matrix.outbox.clone();
+    let mut wake = outbox.subscribe();
+
+    loop {
+        let client = match matrix.clients.get(&user_id) {
+            Some(client) => client,
+            None => {
+                outbox.stop_sending(&user_id);
+                return;
+            }
+        };
+
+        let item = match outbox.next(&user_id) {
+            Some(item) => item,
+            None => {
+                let _ = wake.changed().await;
+                continue;
+            }
+        };
+
+        // With sliding sync the room list comes in a page at a time, so a room
+        // we can't find yet might still turn up. The errors come with whether
+        // they're worth another try.
+        let result = match client.get_joined_room(&item.room_id) {
+            Some(room) => send_outgoing(&room, &item)
+                .await
+                .map_err(|err| (retryable(&err), err.to_string())),
+            None => Err((true, "We're not in this room.".to_string())),
+        };
+
+        match result {
+            Ok(()) => outbox.sent(&item.txn_id),
+            Err((true, err)) => {
+                let attempts = outbox.attempted(&item.txn_id);
+
+                if attempts >= MAX_SEND_ATTEMPTS {
+                    outbox.fail(&item.txn_id, err);
+                } else {
+                    Matrix::send(MatuiEvent::OutboxChanged(outbox.items()));
+
+                    let delay = backoff(attempts);
+                    info!("sending {} again in {:?}", item.txn_id, delay);
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = wake.changed() => {}
+                    }
+
+                    continue;
+                }
+            }
+            Err((false, err)) => outbox.fail(&item.txn_id, err),
+        }
+
+        Matrix::send(MatuiEvent::OutboxChanged(outbox.items()));
+    }
+}
+
+// If what we're replying to is gone, or we can't see it, the message can still
+// go out without the quote. Only a lookup worth trying again holds it up.
+async fn quoted_event(
+    room: &Joined,
+    id: &OwnedEventId,
+) -> matrix_sdk::Result<Option<OriginalMessageLikeEvent<RoomMessageEventContent>>> {
+    match Matrix::get_room_event(room, id).await {
+        Err(err) if !retryable(&err) => {
+            warn!("sending without quoting {}: {}", id, err);
+            Ok(None)
+        }
+        result => result,
+    }
 }
 
-/// The full session to persist.
-#[derive(Debug, Serialize, Deserialize)]
-struct FullSession {
-    client_session: ClientSession,
-    user_session: Session,
-    sync_token: Option<String>,
+/// Is it worth trying again? The homeserver turning us down usually means
+/// it'll do the same next time, unless it's just asking us to slow down.
+fn retryable(err: &matrix_sdk::Error) -> bool {
+    matches!(
+        err.client_api_error_kind(),
+        None | Some(ErrorKind::LimitExceeded { .. })
+    )
 }
 
-async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
-    let serialized_session = fs::read_to_string(session_file)?;
+/// The pagination token from just before an event.
+async fn context_start(room: &Joined, event_id: OwnedEventId) -> Option<String> {
+    let mut request = get_context::v3::Request::new(room.room_id().to_owned(), event_id);
+    request.limit = UInt::from(0_u16);
+
+    match room.client().send(request, None).await {
+        Ok(response) => response.start,
+        Err(err) => {
+            error!("could not get the context of the cached timeline: {}", err);
+            None
+        }
+    }
+}
+
+/// Delete everything we've stored locally for the account.
+fn remove_session(user_id: &UserId) -> anyhow::Result<()> {
+    let session_file = session::session_file(user_id);
+    let FullSession { client_session, .. } = session::read(&session_file)?;
 
+    fs::remove_file(&session_file)?;
+    session::forget_backup_key(&session_file);
+
+    if client_session.db_path.exists() {
+        fs::remove_dir_all(&client_session.db_path)?;
+    }
+
+    Ok(())
+}
+
+/// The key for the account's backup, if we've set one up.
+fn backup_key(user_id: &UserId) -> Option<BackupKey> {
+    session::backup_key(&session::session_file(user_id))
+}
+
+/// What the user compares with the other side during a SAS verification.
+#[derive(Clone, Debug)]
+pub enum SasCode {
+    Emoji([Emoji; 7]),
+    Decimals(u16, u16, u16),
+}
+
+/// One of our own sessions.
+#[derive(Clone, Debug)]
+pub struct OwnDevice {
+    pub device_id: OwnedDeviceId,
+    pub display_name: Option<String>,
+    pub last_seen_ip: Option<String>,
+    pub last_seen: Option<MilliSecondsSinceUnixEpoch>,
+    pub verified: bool,
+
+    /// Is it the session we're using right now?
+    pub current: bool,
+}
+
+/// Where cross-signing stands, from the point of view of this device.
+#[derive(Clone, Debug)]
+pub enum CrossSigning {
+    /// The account doesn't have a cross-signing identity yet.
+    Missing,
+
+    /// There's an identity, but this device doesn't have the keys for it.
+    Unverified,
+
+    /// We can sign with all of the keys.
+    Ready,
+}
+
+/// The homeserver wants more than an access token, which we can help with
+/// as long as a password will do.
+fn ask_for_password(info: &UiaaInfo, reauth: Reauth, retry: bool) {
+    if info
+        .flows
+        .iter()
+        .any(|flow| flow.stages == [AuthType::Password])
+    {
+        Matrix::send(MatuiEvent::AuthRequired(
+            reauth,
+            info.session.clone(),
+            retry,
+        ));
+    } else {
+        Matrix::send(Error(
+            "Your homeserver needs a kind of authentication Matui doesn't support. \
+             Please use another client for this."
+                .to_string(),
+        ));
+    }
+}
+
+fn password_auth(user_id: &UserId, session: Option<String>, password: String) -> AuthData {
+    let identifier = UserIdentifier::UserIdOrLocalpart(user_id.localpart().to_string());
+
+    let mut password = uiaa::Password::new(identifier, password);
+    password.session = session;
+
+    AuthData::Password(password)
+}
+
+/// How did restoring a session go?
+enum Restored {
+    Ready(OwnedUserId),
+    Expired(OwnedUserId, bool),
+}
+
+/// If the homeserver no longer accepts our access token, was it a soft
+/// logout?
+fn soft_logout(err: &matrix_sdk::Error) -> Option<bool> {
+    match err.client_api_error_kind() {
+        Some(ErrorKind::UnknownToken { soft_logout }) => Some(*soft_logout),
+        _ => None,
+    }
+}
+
+/// The session we'd like to sign back in to, if it's still on disk.
+fn previous_session(id: &str) -> Option<FullSession> {
+    let id = <&UserId>::try_from(id).ok()?;
+    session::read(&session::session_file(id)).ok()
+}
+
+async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
     let FullSession {
         client_session,
         user_session,
         sync_token,
-    } = serde_json::from_str(&serialized_session)?;
-
-    let homeserver = <&ServerName>::try_from(client_session.homeserver.as_str())?;
+        ..
+    } = session::read(session_file)?;
 
     // Build the client with the previous settings from the session.
-    let client = Client::builder()
-        .server_name(homeserver)
-        .sled_store(client_session.db_path, Some(&client_session.passphrase))
-        .build()
-        .await?;
+    let client = rebuild_client(&client_session).await?;
 
     // Restore the Matrix user session.
     client.restore_session(user_session).await?;
@@ -648,39 +2136,134 @@ async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<
     Ok((client, sync_token))
 }
 
+async fn rebuild_client(client_session: &ClientSession) -> anyhow::Result<Client> {
+    let builder = if client_session.homeserver.contains("://") {
+        Client::builder().homeserver_url(&client_session.homeserver)
+    } else {
+        let homeserver = <&ServerName>::try_from(client_session.homeserver.as_str())?;
+        Client::builder().server_name(homeserver)
+    };
+
+    Ok(builder
+        .sled_store(&client_session.db_path, Some(&client_session.passphrase))
+        .build()
+        .await?)
+}
+
+/// A brand new client, unless we have a previous session to reuse the
+/// store and device of.
+async fn login_client(
+    previous: Option<FullSession>,
+    homeserver: impl Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<(Client, ClientSession, Option<OwnedDeviceId>)> {
+    match previous {
+        Some(FullSession {
+            client_session,
+            user_session,
+            ..
+        }) => {
+            let client = rebuild_client(&client_session).await?;
+            Ok((client, client_session, Some(user_session.device_id)))
+        }
+        None => {
+            let (client, client_session) = build_client(homeserver.await?).await?;
+            Ok((client, client_session, None))
+        }
+    }
+}
+
 async fn login(
-    data_dir: &Path,
-    session_file: &Path,
     id: &str,
     password: &str,
+    homeserver: Option<String>,
+    previous: Option<FullSession>,
 ) -> anyhow::Result<Client> {
     let id = <&UserId>::try_from(id)?;
     let username = id.localpart();
 
-    let (client, client_session) = build_client(data_dir, id).await?;
+    let homeserver = resolve_homeserver(Some(id.server_name()), homeserver.as_deref());
+    let (client, client_session, device_id) = login_client(previous, homeserver).await?;
 
-    client
+    let mut login = client
         .login_username(username, password)
-        .initial_device_display_name("Matui")
-        .send()
-        .await?;
+        .initial_device_display_name("Matui");
+
+    if let Some(device_id) = &device_id {
+        login = login.device_id(device_id.as_str());
+    }
+
+    login.send().await?;
+
+    persist_session(&client, client_session)?;
+
+    Ok(client)
+}
+
+async fn login_sso(
+    server: Option<&ServerName>,
+    homeserver: Option<String>,
+    previous: Option<FullSession>,
+) -> anyhow::Result<Client> {
+    let homeserver = resolve_homeserver(server, homeserver.as_deref());
+    let (client, client_session, device_id) = login_client(previous, homeserver).await?;
+
+    // the homeserver will send the browser back here once the user is done
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let path = callback_path();
+    let redirect_url = format!("http://{}{}", listener.local_addr()?, path);
+
+    let sso_url = client.get_sso_login_url(&redirect_url, None).await?;
+    tokio::task::spawn_blocking(move || open_url(&sso_url)).await??;
+
+    let token =
+        tokio::task::spawn_blocking(move || wait_for_login_token(listener, &path)).await??;
+
+    let mut login = client
+        .login_token(&token)
+        .initial_device_display_name("Matui");
+
+    if let Some(device_id) = &device_id {
+        login = login.device_id(device_id.as_str());
+    }
 
+    login.send().await?;
+
+    persist_session(&client, client_session)?;
+
+    Ok(client)
+}
+
+fn persist_session(client: &Client, client_session: ClientSession) -> anyhow::Result<()> {
     let user_session = client
         .session()
         .context("Your logged-in user has no session.")?;
 
-    let serialized_session = serde_json::to_string(&FullSession {
-        client_session,
-        user_session,
-        sync_token: None,
-    })?;
+    let session_file = session::session_file(&user_session.user_id);
+
+    session::write(
+        &session_file,
+        &FullSession {
+            client_session,
+            user_session,
+            sync_token: None,
+            backup_key: None,
+        },
+    )
+}
 
-    fs::write(session_file, serialized_session)?;
+/// SSO users might not know their full Matrix ID, so we'll take either that
+/// or just the server name.
+fn server_name_from(input: &str) -> anyhow::Result<OwnedServerName> {
+    let input = input.trim();
 
-    Ok(client)
+    if input.starts_with('@') {
+        return Ok(<&UserId>::try_from(input)?.server_name().to_owned());
+    }
+
+    ServerName::parse(input).context("Enter your Matrix ID or homeserver name.")
 }
 
-async fn build_client(data_dir: &Path, id: &UserId) -> anyhow::Result<(Client, ClientSession)> {
+async fn build_client(homeserver: String) -> anyhow::Result<(Client, ClientSession)> {
     let mut rng = OsRng;
 
     let db_subfolder: String = (&mut rng)
@@ -689,7 +2272,7 @@ async fn build_client(data_dir: &Path, id: &UserId) -> anyhow::Result<(Client, C
         .map(char::from)
         .collect();
 
-    let db_path = data_dir.join(db_subfolder.as_str());
+    let db_path = session::data_dir().join(db_subfolder.as_str());
 
     // Generate a random passphrase.
     let passphrase: String = (&mut rng)
@@ -699,7 +2282,7 @@ async fn build_client(data_dir: &Path, id: &UserId) -> anyhow::Result<(Client, C
         .collect();
 
     let client = Client::builder()
-        .server_name(id.server_name())
+        .homeserver_url(&homeserver)
         .sled_store(&db_path, Some(passphrase.as_str()))
         .build()
         .await?;
@@ -707,26 +2290,16 @@ async fn build_client(data_dir: &Path, id: &UserId) -> anyhow::Result<(Client, C
     Ok((
         client,
         ClientSession {
-            homeserver: id.server_name().host().to_string(),
+            homeserver,
             db_path,
             passphrase,
         },
     ))
 }
 
-fn build_sync_settings(sync_token: Option<String>) -> SyncSettings {
-    let mut state_filter = RoomEventFilter::empty();
-    state_filter.lazy_load_options = LazyLoadOptions::Enabled {
-        include_redundant_members: false,
-    };
-
-    let mut room_filter = RoomFilter::empty();
-    room_filter.state = state_filter;
-
-    let mut filter = FilterDefinition::empty();
-    filter.room = room_filter;
-
-    let mut sync_settings = SyncSettings::default().filter(filter.into());
+async fn build_sync_settings(client: &Client, sync_token: Option<String>) -> SyncSettings {
+    let filter = filter::sync_filter(client, &sync_filter()).await;
+    let mut sync_settings = SyncSettings::default().filter(filter);
 
     if let Some(token) = sync_token {
         sync_settings = sync_settings.token(token);
@@ -735,19 +2308,19 @@ fn build_sync_settings(sync_token: Option<String>) -> SyncSettings {
     sync_settings
 }
 
-async fn sync_once(
-    client: Client,
-    sync_token: Option<String>,
-    session_file: &Path,
-) -> anyhow::Result<String> {
-    let sync_settings = build_sync_settings(sync_token);
+async fn sync_once(client: Client, sync_token: Option<String>) -> anyhow::Result<String> {
+    let sync_settings = build_sync_settings(&client, sync_token).await;
+    let session_file = session::session_file(&clients::user_id(&client));
 
     for _ in 0..10 {
         match client.sync_once(sync_settings.clone()).await {
             Ok(response) => {
-                persist_sync_token(session_file, response.next_batch.clone())?;
+                session::persist_sync_token(&session_file, response.next_batch.clone())?;
                 return Ok(response.next_batch);
             }
+
+            // no amount of retrying will fix this one
+            Err(error) if soft_logout(&error).is_some() => return Err(error.into()),
             Err(error) => {
                 info!("An error occurred during initial sync: {error}");
                 info!("Trying again…");
@@ -758,25 +2331,52 @@ async fn sync_once(
     bail!("Sync timeout.")
 }
 
-fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file)?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+fn add_default_handlers(client: Client, clients: Arc<Clients>, cache: Arc<TimelineCache>) {
+    client.add_event_handler(
+        move |raw: Raw<AnySyncTimelineEvent>, room: Room, encryption: Option<EncryptionInfo>| {
+            let cache = cache.clone();
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session)?;
+            async move {
+                cache.add(room.own_user_id(), room.room_id(), vec![(raw, encryption)]);
+            }
+        },
+    );
 
-    Ok(())
-}
+    client.add_event_handler(
+        move |event: AnySyncTimelineEvent, room: Room, encryption: Option<EncryptionInfo>| {
+            let clients = clients.clone();
 
-fn add_default_handlers(client: Client) {
-    client.add_event_handler(|event: AnySyncTimelineEvent, room: Room| async move {
-        App::get_sender()
-            .send(Matui(MatuiEvent::Timeline(
-                event.into_full_event(room.room_id().into()),
-            )))
-            .expect("could not send timeline event");
-    });
+            async move {
+                // shared rooms would otherwise see every event once per account
+                let first = clients.first_joined(room.room_id());
+
+                if first.map(|c| clients::user_id(&c)).as_deref() != Some(room.own_user_id()) {
+                    return;
+                }
+
+                App::get_sender()
+                    .send(Matui(MatuiEvent::Timeline(
+                        event.into_full_event(room.room_id().into()),
+                        encryption,
+                    )))
+                    .expect("could not send timeline event");
+            }
+        },
+    );
+
+    client.add_event_handler(
+        |ev: StrippedRoomMemberEvent, room: Room, client: Client| async move {
+            if client.user_id() != Some(&*ev.state_key)
+                || ev.content.membership != MembershipState::Invite
+            {
+                return;
+            }
+
+            if let Room::Invited(invited) = room {
+                Matrix::send(MatuiEvent::Invited(invited));
+            }
+        },
+    );
 
     client.add_event_handler(|event: AnySyncEphemeralRoomEvent, room: Room| async move {
         let joined = match room {
@@ -815,10 +2415,7 @@ fn add_verification_handlers(client: Client) {
                 }
             };
 
-            request
-                .accept()
-                .await
-                .expect("Can't accept verification request");
+            verification_requested(&client, request, &ev.content.from_device).await;
         },
     );
 
@@ -834,9 +2431,31 @@ fn add_verification_handlers(client: Client) {
         },
     );
 
+    // new keys might unlock messages we're showing placeholders for, and
+    // they belong in the backup
+    client.add_event_handler(|ev: ToDeviceRoomKeyEvent, client: Client| async move {
+        Matrix::send(MatuiEvent::RoomKeysReceived(
+            ev.content.room_id,
+            vec![ev.content.session_id],
+        ));
+
+        tokio::spawn(async move { upload_backup(&client).await });
+    });
+
+    client.add_event_handler(
+        |ev: ToDeviceForwardedRoomKeyEvent, client: Client| async move {
+            Matrix::send(MatuiEvent::RoomKeysReceived(
+                ev.content.room_id,
+                vec![ev.content.session_id],
+            ));
+
+            tokio::spawn(async move { upload_backup(&client).await });
+        },
+    );
+
     client.add_event_handler(
         |ev: OriginalSyncRoomMessageEvent, client: Client| async move {
-            if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
+            if let MessageType::VerificationRequest(content) = &ev.content.msgtype {
                 let request = match client
                     .encryption()
                     .get_verification_request(&ev.sender, &ev.event_id)
@@ -849,10 +2468,7 @@ fn add_verification_handlers(client: Client) {
                     }
                 };
 
-                request
-                    .accept()
-                    .await
-                    .expect("Can't accept verification request");
+                verification_requested(&client, request, &content.from_device).await;
             }
         },
     );
@@ -870,40 +2486,222 @@ fn add_verification_handlers(client: Client) {
     );
 }
 
+/// Let the user decide if they want to go ahead with the verification.
+async fn verification_requested(
+    client: &Client,
+    request: VerificationRequest,
+    device_id: &DeviceId,
+) {
+    // our own requests come back to us in rooms
+    if request.we_started() {
+        return;
+    }
+
+    let device = match client
+        .encryption()
+        .get_device(request.other_user_id(), device_id)
+        .await
+    {
+        Ok(Some(device)) => match device.display_name() {
+            Some(name) => format!("{} ({})", name, device_id),
+            None => device_id.to_string(),
+        },
+        _ => device_id.to_string(),
+    };
+
+    Matrix::send(MatuiEvent::VerificationRequested(request, device));
+}
+
+/// Once the other side is ready, show them a QR code, or start comparing
+/// emoji if they can't scan one. If they start something first, the handlers
+/// above take care of it.
+async fn wait_for_ready(client: &Client, request: VerificationRequest) -> anyhow::Result<()> {
+    let started = Instant::now();
+
+    // there's no way to wait for the request to change, so we check in on it
+    while started.elapsed() < VERIFICATION_TIMEOUT {
+        if request.is_cancelled() {
+            bail!("The request was declined.");
+        }
+
+        if client
+            .encryption()
+            .get_verification(request.other_user_id(), request.flow_id().as_str())
+            .await
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        if request.is_ready() {
+            if !show_qr_code(&request).await? {
+                start_sas(&request).await?;
+            }
+
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    request.cancel().await?;
+    bail!("Nobody answered the request.")
+}
+
+async fn start_sas(request: &VerificationRequest) -> anyhow::Result<()> {
+    if let Some(sas) = request.start_sas().await? {
+        tokio::spawn(sas_verification_handler(sas, App::get_sender()));
+    }
+
+    Ok(())
+}
+
+/// Put a QR code on the screen, as long as the other side can scan it.
+async fn show_qr_code(request: &VerificationRequest) -> anyhow::Result<bool> {
+    let qr = match request.generate_qr_code().await? {
+        Some(qr) => qr,
+        None => return Ok(false),
+    };
+
+    let code = qr.to_qr_code()?;
+
+    let modules = code
+        .to_colors()
+        .into_iter()
+        .map(|c| c.select(true, false))
+        .collect();
+
+    Matrix::send(MatuiEvent::VerificationQrCode(
+        request.clone(),
+        code.width(),
+        modules,
+    ));
+
+    tokio::spawn(qr_verification_handler(qr, App::get_sender()));
+
+    Ok(true)
+}
+
+async fn qr_verification_handler(qr: QrVerification, sender: Sender<Event>) {
+    let started = Instant::now();
+    let mut scanned = false;
+
+    while started.elapsed() < VERIFICATION_TIMEOUT {
+        if qr.is_done() {
+            info!("verification done");
+
+            sender
+                .send(Matui(VerificationCompleted(qr.other_user_id().to_string())))
+                .expect("could not send qr completed event");
+
+            return;
+        }
+
+        // they went with emoji, or gave up, and we'll hear about that elsewhere
+        if qr.is_cancelled() {
+            return;
+        }
+
+        if qr.has_been_scanned() && !scanned {
+            info!("qr code scanned");
+            scanned = true;
+
+            sender
+                .send(Matui(MatuiEvent::VerificationQrScanned(qr.clone())))
+                .expect("could not send qr scanned event");
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
 async fn sas_verification_handler(sas: SasVerification, sender: Sender<Event>) {
-    sas.accept().await.unwrap();
+    if let Err(err) = sas.accept().await {
+        error!("could not accept SAS verification: {}", err);
+
+        sender
+            .send(Matui(Error(format!("Could not verify: {}", err))))
+            .expect("could not send sas error event");
+
+        return;
+    }
 
     let mut stream = sas.changes();
 
     while let Some(state) = stream.next().await {
         match state {
-            SasState::KeysExchanged {
-                emojis,
-                decimals: _,
-            } => {
+            SasState::KeysExchanged { emojis, decimals } => {
                 info!("verification keys exchanged");
 
-                let emoji_slice = emojis.expect("only emoji verification is supported").emojis;
+                // not every client can do emoji, but they can all do numbers
+                let code = match emojis {
+                    Some(emojis) => SasCode::Emoji(emojis.emojis),
+                    None => SasCode::Decimals(decimals.0, decimals.1, decimals.2),
+                };
 
                 sender
-                    .send(Matui(VerificationStarted(sas.clone(), emoji_slice)))
+                    .send(Matui(VerificationStarted(sas.clone(), code)))
                     .expect("could not send sas started event");
             }
             SasState::Done { .. } => {
                 info!("verification done");
 
+                let verified = format!(
+                    "{} ({})",
+                    sas.other_user_id(),
+                    sas.other_device().device_id()
+                );
+
                 sender
-                    .send(Matui(VerificationCompleted))
+                    .send(Matui(VerificationCompleted(verified)))
                     .expect("could not send sas completed event");
             }
             SasState::Started { .. } => info!("verification started"),
             SasState::Accepted { .. } => info!("verification accepted"),
             SasState::Confirmed => info!("verification confirmed"),
-            SasState::Cancelled(_) => info!("verification cancelled"),
+            SasState::Cancelled(cancel_info) => {
+                info!("verification cancelled");
+
+                sender
+                    .send(Matui(Error(format!(
+                        "Verification cancelled: {}",
+                        cancel_info.reason()
+                    ))))
+                    .expect("could not send sas cancelled event");
+            }
         }
     }
 }
 
+// 2, 4, 8... seconds, up to a minute
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.min(6)).min(MAX_BACKOFF)
+}
+
+async fn upload_backup(client: &Client) {
+    if !key_backup() {
+        return;
+    }
+
+    let key = match backup_key(&clients::user_id(client)) {
+        Some(key) => key,
+        None => return,
+    };
+
+    match backup::upload(client, &key).await {
+        Ok(count) => info!("backed up {} room keys", count),
+        Err(err) => error!("could not back up room keys: {}", err),
+    }
+}
+
+// let any chat waiting on these keys know they're here
+fn room_keys_received(result: &RoomKeyImportResult) {
+    for (room_id, senders) in &result.keys {
+        let sessions = senders.values().flatten().cloned().collect();
+        Matrix::send(MatuiEvent::RoomKeysReceived(room_id.clone(), sessions));
+    }
+}
+
 pub fn pad_emoji(emoji: &str) -> String {
     // These are emojis that need VARIATION-SELECTOR-16 (U+FE0F) so that they are
     // rendered with coloured glyphs. For these, we need to add an extra
@@ -937,3 +2735,19 @@ pub fn format_emojis(emojis: [Emoji; 7]) -> String {
         .collect::<Vec<_>>()
         .join("")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::backoff;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(5), Duration::from_secs(32));
+        assert_eq!(backoff(6), Duration::from_secs(60));
+        assert_eq!(backoff(100), Duration::from_secs(60));
+    }
+}
@@ -1,7 +1,21 @@
 #[allow(clippy::module_inception)]
 pub mod matrix;
 
+pub mod backup;
+pub mod clients;
+pub mod create;
+pub mod directory;
+pub mod discovery;
+pub mod filter;
+pub mod join;
 pub mod mime;
 pub mod notify;
+pub mod outbox;
+pub mod recovery;
 pub mod roomcache;
+pub mod sealed;
+pub mod session;
+pub mod sliding;
+pub mod sso;
+pub mod timeline;
 pub mod username;
@@ -1,6 +1,6 @@
 use log::error;
-use ruma::UserId;
 use ruma::{events::AnyTimelineEvent, OwnedRoomId};
+use ruma::{MilliSecondsSinceUnixEpoch, UserId};
 use std::fs::OpenOptions;
 use std::{
     collections::HashMap,
@@ -25,8 +25,12 @@ use notify_rust::{CloseReason, Hint};
 use crate::{handler::MatuiEvent, settings::is_muted, widgets::message::Message};
 
 use super::matrix::Matrix;
+use super::roomcache::DecoratedInvite;
 
 pub struct Notify {
+    // a sliding sync fills in the room list with old messages, which no one
+    // wants to hear about
+    started: MilliSecondsSinceUnixEpoch,
     focus: AtomicBool,
     room_id: Mutex<Option<OwnedRoomId>>,
     rooms: Mutex<HashMap<String, u32>>,
@@ -35,6 +39,7 @@ pub struct Notify {
 impl Default for Notify {
     fn default() -> Self {
         Notify {
+            started: MilliSecondsSinceUnixEpoch::now(),
             focus: AtomicBool::new(false),
             room_id: Mutex::new(None),
             rooms: Mutex::new(HashMap::new()),
@@ -54,6 +59,11 @@ impl Notify {
                 return Ok(());
             }
 
+            // or anything from before we started
+            if message.sent < self.started {
+                return Ok(());
+            }
+
             // or when the room is muted
             if is_muted(message.room_id.as_ref()) {
                 return Ok(());
@@ -86,6 +96,18 @@ impl Notify {
         Ok(())
     }
 
+    pub async fn invite_event(&self, invite: &DecoratedInvite) -> anyhow::Result<()> {
+        let summary = match &invite.inviter {
+            Some(inviter) => format!("{} invited you", inviter),
+            None => "You've been invited".to_string(),
+        };
+
+        let room = Room::Invited(invite.inner());
+        let avatar = Notify::get_room_image(&room).await;
+
+        self.send_notification(&summary, &invite.name.to_string(), room, avatar)
+    }
+
     pub fn focus_event(&self) {
         self.focus.store(true, Ordering::Relaxed);
     }
@@ -152,14 +174,30 @@ impl Notify {
         Ok(())
     }
 
-    fn get_cache_path(key: &str) -> PathBuf {
+    fn get_cache_dir() -> PathBuf {
         let mut path = dirs::cache_dir().expect("no cache directory");
         path.push("matui");
+        path
+    }
+
+    fn get_cache_path(key: &str) -> PathBuf {
+        let mut path = Notify::get_cache_dir();
         fs::create_dir_all(&path).unwrap();
         path.push(key);
         path
     }
 
+    /// Throw away the cached avatars, they'll be fetched again when needed.
+    pub fn clear_cache(&self) {
+        let path = Notify::get_cache_dir();
+
+        if path.exists() {
+            if let Err(e) = fs::remove_dir_all(path) {
+                error!("could not clear the avatar cache: {}", e);
+            }
+        }
+    }
+
     fn write_image_to_file(img: Vec<u8>, path: &PathBuf) -> anyhow::Result<()> {
         let data = Cursor::new(img);
         let reader = image::io::Reader::new(data).with_guessed_format()?;
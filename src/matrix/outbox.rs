@@ -0,0 +1,443 @@
+//! Everything we've been asked to send, until the homeserver has it. The
+//! queue is kept on disk, so nothing is lost if we're offline or get closed,
+//! and it's sealed along with the sessions, when those are. Without sealing,
+//! messages for encrypted rooms only ever live in memory.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use matrix_sdk::ruma::exports::serde_json;
+use ruma::{
+    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedTransactionId, OwnedUserId,
+    TransactionId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::matrix::session::{data_dir, read_file, sealing, write_file};
+
+/// What we're sending, which we turn into an event when it's time to go.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Outgoing {
+    Text(String),
+    Reply(String, OwnedEventId),
+    Reaction(OwnedEventId, String),
+    Replacement(OwnedEventId, String, Option<OwnedEventId>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutboxState {
+    Sending,
+
+    /// We've given up, until someone asks us to try again.
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutboxItem {
+    /// Reused for every attempt, so the homeserver can spot duplicates.
+    pub txn_id: OwnedTransactionId,
+    pub user_id: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub outgoing: Outgoing,
+    pub queued: MilliSecondsSinceUnixEpoch,
+    pub state: OutboxState,
+
+    /// Whether the room is encrypted, which keeps it off disk unless sealed.
+    #[serde(default)]
+    pub encrypted: bool,
+
+    // failed attempts in a row, which start over with each run
+    #[serde(skip)]
+    pub attempts: u32,
+}
+
+impl OutboxItem {
+    pub fn new(
+        user_id: OwnedUserId,
+        room_id: OwnedRoomId,
+        outgoing: Outgoing,
+        encrypted: bool,
+    ) -> Self {
+        OutboxItem {
+            txn_id: TransactionId::new(),
+            user_id,
+            room_id,
+            outgoing,
+            queued: MilliSecondsSinceUnixEpoch::now(),
+            state: OutboxState::Sending,
+            encrypted,
+            attempts: 0,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        matches!(self.state, OutboxState::Failed(_))
+    }
+}
+
+pub struct Outbox {
+    path: PathBuf,
+    items: Mutex<Vec<OutboxItem>>,
+    loaded: AtomicBool,
+
+    // the accounts with something working through their items
+    sending: Mutex<HashSet<OwnedUserId>>,
+
+    // pokes everyone who is sending, when there's something new to try
+    wake: watch::Sender<()>,
+
+    // Set when the queue has changed since we last wrote it. The writer runs
+    // off the async threads, and whatever changes while it's busy goes out
+    // in the next write.
+    pending: Mutex<Pending>,
+
+    // so two writers don't trample each other
+    writing: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Pending {
+    changed: bool,
+    flushing: bool,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox::new(outbox_file())
+    }
+}
+
+impl Outbox {
+    fn new(path: PathBuf) -> Self {
+        Outbox {
+            path,
+            items: Mutex::new(vec![]),
+            loaded: AtomicBool::new(false),
+            sending: Mutex::new(HashSet::new()),
+            wake: watch::channel(()).0,
+            pending: Mutex::new(Pending::default()),
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Pick up whatever was left over from last time. This only happens once,
+    /// and the passphrase has to be set first.
+    pub fn load(&self) {
+        if self.loaded.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let path = &self.path;
+
+        if path.exists() {
+            let read = read_file(path).and_then(|c| Ok(serde_json::from_str(&c)?));
+
+            match read {
+                Ok(left_over) => {
+                    let mut items = self.items.lock().expect("to unlock items");
+                    let queued = std::mem::replace(&mut *items, left_over);
+                    items.extend(queued);
+                }
+                Err(err) => error!("could not read the outbox: {}", err),
+            }
+        }
+    }
+
+    pub fn push(self: &Arc<Self>, item: OutboxItem) {
+        self.update(|items| items.push(item));
+        self.wake();
+    }
+
+    pub fn items(&self) -> Vec<OutboxItem> {
+        self.items.lock().expect("to unlock items").clone()
+    }
+
+    /// The account's oldest item that we're still trying to send.
+    pub fn next(&self, user_id: &UserId) -> Option<OutboxItem> {
+        self.items
+            .lock()
+            .expect("to unlock items")
+            .iter()
+            .find(|i| i.user_id == user_id && !i.failed())
+            .cloned()
+    }
+
+    /// Mark an account as sending, returning false if it already was.
+    pub fn start_sending(&self, user_id: &UserId) -> bool {
+        self.sending
+            .lock()
+            .expect("to unlock sending")
+            .insert(user_id.to_owned())
+    }
+
+    pub fn stop_sending(&self, user_id: &UserId) {
+        self.sending
+            .lock()
+            .expect("to unlock sending")
+            .remove(user_id);
+    }
+
+    pub fn sent(self: &Arc<Self>, txn_id: &TransactionId) {
+        self.update(|items| items.retain(|i| i.txn_id != txn_id));
+    }
+
+    /// Note another failed attempt, returning how many there have been.
+    pub fn attempted(&self, txn_id: &TransactionId) -> u32 {
+        let mut items = self.items.lock().expect("to unlock items");
+
+        match items.iter_mut().find(|i| i.txn_id == txn_id) {
+            Some(item) => {
+                item.attempts += 1;
+                item.attempts
+            }
+            None => 0,
+        }
+    }
+
+    pub fn fail(self: &Arc<Self>, txn_id: &TransactionId, err: String) {
+        self.update(|items| {
+            if let Some(item) = items.iter_mut().find(|i| i.txn_id == txn_id) {
+                item.state = OutboxState::Failed(err);
+                item.attempts = 0;
+            }
+        });
+    }
+
+    pub fn retry(self: &Arc<Self>, txn_id: &TransactionId) {
+        self.update(|items| {
+            if let Some(item) = items.iter_mut().find(|i| i.txn_id == txn_id) {
+                item.state = OutboxState::Sending;
+            }
+        });
+
+        self.wake();
+    }
+
+    pub fn discard(self: &Arc<Self>, txn_id: &TransactionId) {
+        self.update(|items| items.retain(|i| i.txn_id != txn_id || !i.failed()));
+    }
+
+    pub fn remove_account(self: &Arc<Self>, user_id: &UserId) {
+        self.update(|items| items.retain(|i| i.user_id != user_id));
+
+        // so whoever is sending for it notices it's gone
+        self.wake();
+    }
+
+    pub fn wake(&self) {
+        self.wake.send_replace(());
+    }
+
+    /// Wakes up whenever `wake` is called, from the moment it's created.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.wake.subscribe()
+    }
+
+    // Change the queue, and have it written out. What's on disk has to be in
+    // here first, or we'd write over it.
+    fn update(self: &Arc<Self>, f: impl FnOnce(&mut Vec<OutboxItem>)) {
+        self.load();
+        f(&mut self.items.lock().expect("to unlock items"));
+
+        let mut pending = self.pending.lock().expect("to unlock pending");
+        pending.changed = true;
+
+        if pending.flushing {
+            return;
+        }
+
+        pending.flushing = true;
+
+        let outbox = self.clone();
+        tokio::task::spawn_blocking(move || outbox.flush());
+    }
+
+    // write out the queue, until it stops changing
+    fn flush(&self) {
+        loop {
+            {
+                let mut pending = self.pending.lock().expect("to unlock pending");
+
+                if !pending.changed {
+                    pending.flushing = false;
+                    return;
+                }
+
+                pending.changed = false;
+            }
+
+            self.write();
+        }
+    }
+
+    fn write(&self) {
+        let _writing = self.writing.lock().expect("to unlock writing");
+
+        // decrypted messages don't go on disk in the clear
+        let sealing = sealing();
+
+        let items: Vec<OutboxItem> = self
+            .items()
+            .into_iter()
+            .filter(|i| sealing || !i.encrypted)
+            .collect();
+
+        let written = serde_json::to_string(&items)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| {
+                if sealing {
+                    write_file(&self.path, json)
+                } else {
+                    Ok(fs::write(&self.path, json)?)
+                }
+            });
+
+        if let Err(err) = written {
+            error!("could not save the outbox: {}", err);
+        }
+    }
+}
+
+fn outbox_file() -> PathBuf {
+    data_dir().join("outbox")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use matrix_sdk::ruma::exports::serde_json;
+    use ruma::{OwnedRoomId, OwnedUserId};
+
+    use super::{Outbox, OutboxItem, OutboxState, Outgoing};
+
+    #[test]
+    fn test_next_skips_failed() {
+        let user_id = OwnedUserId::try_from("@me:example.org").unwrap();
+        let room_id = OwnedRoomId::try_from("!room:example.org").unwrap();
+
+        let mut failed = OutboxItem::new(
+            user_id.clone(),
+            room_id.clone(),
+            Outgoing::Text("first".to_string()),
+            false,
+        );
+
+        failed.state = OutboxState::Failed("nope".to_string());
+
+        let sending = OutboxItem::new(
+            user_id.clone(),
+            room_id,
+            Outgoing::Text("second".to_string()),
+            false,
+        );
+
+        let outbox = Outbox::default();
+        *outbox.items.lock().unwrap() = vec![failed, sending.clone()];
+
+        assert_eq!(outbox.next(&user_id).unwrap().txn_id, sending.txn_id);
+    }
+
+    #[test]
+    fn test_next_per_account() {
+        let me = OwnedUserId::try_from("@me:example.org").unwrap();
+        let other = OwnedUserId::try_from("@other:example.org").unwrap();
+        let room_id = OwnedRoomId::try_from("!room:example.org").unwrap();
+
+        let stuck = OutboxItem::new(
+            other.clone(),
+            room_id.clone(),
+            Outgoing::Text("first".to_string()),
+            false,
+        );
+
+        let mine = OutboxItem::new(
+            me.clone(),
+            room_id,
+            Outgoing::Text("second".to_string()),
+            false,
+        );
+
+        let outbox = Outbox::default();
+        *outbox.items.lock().unwrap() = vec![stuck.clone(), mine.clone()];
+
+        // another account's oldest item doesn't hold ours up
+        assert_eq!(outbox.next(&me).unwrap().txn_id, mine.txn_id);
+        assert_eq!(outbox.next(&other).unwrap().txn_id, stuck.txn_id);
+    }
+
+    #[tokio::test]
+    async fn test_remove_account_before_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox");
+
+        let me = OwnedUserId::try_from("@me:example.org").unwrap();
+        let expired = OwnedUserId::try_from("@expired:example.org").unwrap();
+        let room_id = OwnedRoomId::try_from("!room:example.org").unwrap();
+
+        let mine = OutboxItem::new(
+            me.clone(),
+            room_id.clone(),
+            Outgoing::Text("mine".to_string()),
+            false,
+        );
+
+        let theirs = OutboxItem::new(
+            expired.clone(),
+            room_id,
+            Outgoing::Text("theirs".to_string()),
+            false,
+        );
+
+        let left_over = vec![mine.clone(), theirs];
+        fs::write(&path, serde_json::to_string(&left_over).unwrap()).unwrap();
+
+        // a session can expire while we're still starting up
+        let outbox = Arc::new(Outbox::new(path.clone()));
+        outbox.remove_account(&expired);
+        outbox.write();
+
+        let reloaded = Outbox::new(path);
+        reloaded.load();
+
+        let items = reloaded.items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].txn_id, mine.txn_id);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_stays_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox");
+
+        let user_id = OwnedUserId::try_from("@me:example.org").unwrap();
+        let room_id = OwnedRoomId::try_from("!room:example.org").unwrap();
+
+        let plain = OutboxItem::new(
+            user_id.clone(),
+            room_id.clone(),
+            Outgoing::Text("plain".to_string()),
+            false,
+        );
+
+        let secret = OutboxItem::new(user_id, room_id, Outgoing::Text("secret".to_string()), true);
+
+        // nothing is sealed without a passphrase
+        let outbox = Arc::new(Outbox::new(path.clone()));
+        outbox.push(plain.clone());
+        outbox.push(secret);
+        outbox.write();
+
+        assert_eq!(outbox.items().len(), 2);
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("plain"));
+        assert!(!written.contains("secret"));
+    }
+}
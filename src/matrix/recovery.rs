@@ -0,0 +1,503 @@
+//! The crypto behind server-side key backup and secret storage. The SDK
+//! doesn't know about either yet, so we do it ourselves, following the
+//! `m.megolm_backup.v1.curve25519-aes-sha2` and
+//! `m.secret_storage.v2.aes-hmac-sha2` sections of the spec. Since it's our
+//! own, it's only used when `key_backup` is turned on, and each primitive is
+//! tested against its published vectors.
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, StreamCipher};
+use aes::Aes256;
+use anyhow::{bail, Context};
+use base64::alphabet::STANDARD;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+// Matrix uses unpadded base64, but we're happy to read it either way.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &STANDARD,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+pub fn encode_base64(bytes: impl AsRef<[u8]>) -> String {
+    BASE64.encode(bytes)
+}
+
+pub fn decode_base64(input: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(BASE64.decode(input)?)
+}
+
+/// Turn a private key into the format users are shown: base58 with a
+/// prefix and parity byte, in groups of four.
+pub fn encode_recovery_key(key: &[u8; 32]) -> String {
+    let mut bytes = RECOVERY_KEY_PREFIX.to_vec();
+    bytes.extend_from_slice(key);
+    bytes.push(bytes.iter().fold(0, |parity, b| parity ^ b));
+
+    let encoded = bs58::encode(bytes).into_string();
+
+    encoded
+        .as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn decode_recovery_key(input: &str) -> anyhow::Result<[u8; 32]> {
+    let input: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+
+    let bytes = bs58::decode(input)
+        .into_vec()
+        .ok()
+        .context("That's not a recovery key.")?;
+
+    if bytes.len() != 35 || bytes[..2] != RECOVERY_KEY_PREFIX {
+        bail!("That's not a recovery key.");
+    }
+
+    if bytes.iter().fold(0, |parity, b| parity ^ b) != 0 {
+        bail!("The recovery key has a typo in it.");
+    }
+
+    Ok(bytes[2..34].try_into()?)
+}
+
+/// Derive a secret storage key from the user's passphrase.
+pub fn key_from_passphrase(passphrase: &str, salt: &str, iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt.as_bytes(), iterations, &mut key);
+    key
+}
+
+/// A secret, encrypted for secret storage (all base64).
+#[derive(Debug, PartialEq)]
+pub struct EncryptedSecret {
+    pub iv: String,
+    pub ciphertext: String,
+    pub mac: String,
+}
+
+// HKDF with the all zero salt, which is the same as no salt at all
+fn hkdf_sha256(ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), ikm)
+        .expand(info, okm)
+        .expect("a valid length");
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("any key length works");
+    mac.update(data);
+    mac
+}
+
+fn aes_ctr(key: &[u8; 32], iv: &[u8; 16], data: &mut [u8]) {
+    Aes256Ctr::new(key.into(), iv.into()).apply_keystream(data);
+}
+
+fn aes_cbc_encrypt(key: &[u8; 32], iv: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+    Aes256CbcEnc::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext)
+}
+
+fn aes_cbc_decrypt(key: &[u8; 32], iv: &[u8; 16], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    Aes256CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .ok()
+}
+
+fn x25519(private_key: &[u8; 32], public_key: &[u8; 32]) -> [u8; 32] {
+    let secret = StaticSecret::from(*private_key);
+    secret
+        .diffie_hellman(&PublicKey::from(*public_key))
+        .to_bytes()
+}
+
+// every secret gets its own AES and MAC keys, based on its name
+fn secret_keys(key: &[u8; 32], name: &str) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    hkdf_sha256(key, name.as_bytes(), &mut okm);
+
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+fn encrypt_secret_with_iv(
+    key: &[u8; 32],
+    name: &str,
+    secret: &[u8],
+    iv: [u8; 16],
+) -> EncryptedSecret {
+    let (aes_key, mac_key) = secret_keys(key, name);
+
+    let mut ciphertext = secret.to_vec();
+    aes_ctr(&aes_key, &iv, &mut ciphertext);
+
+    let mac = hmac_sha256(&mac_key, &ciphertext);
+
+    EncryptedSecret {
+        iv: encode_base64(iv),
+        ciphertext: encode_base64(ciphertext),
+        mac: encode_base64(mac.finalize().into_bytes()),
+    }
+}
+
+pub fn encrypt_secret(key: &[u8; 32], name: &str, secret: &[u8]) -> EncryptedSecret {
+    let mut iv = [0u8; 16];
+    OsRng.fill_bytes(&mut iv);
+
+    // the spec wants bit 63 cleared to leave room for the counter
+    iv[8] &= 0x7f;
+
+    encrypt_secret_with_iv(key, name, secret, iv)
+}
+
+pub fn decrypt_secret(
+    key: &[u8; 32],
+    name: &str,
+    secret: &EncryptedSecret,
+) -> anyhow::Result<Vec<u8>> {
+    let (aes_key, mac_key) = secret_keys(key, name);
+
+    let iv: [u8; 16] = decode_base64(&secret.iv)?
+        .try_into()
+        .ok()
+        .context("Invalid secret IV.")?;
+
+    let mut ciphertext = decode_base64(&secret.ciphertext)?;
+
+    hmac_sha256(&mac_key, &ciphertext)
+        .verify_slice(&decode_base64(&secret.mac)?)
+        .ok()
+        .context("That key doesn't unlock your secret storage.")?;
+
+    aes_ctr(&aes_key, &iv, &mut ciphertext);
+
+    Ok(ciphertext)
+}
+
+/// Does the key match the `iv` and `mac` published with the key's
+/// description?
+pub fn check_secret_storage_key(key: &[u8; 32], iv: &str, mac: &str) -> bool {
+    let iv: [u8; 16] = match decode_base64(iv).ok().and_then(|iv| iv.try_into().ok()) {
+        Some(iv) => iv,
+        None => return false,
+    };
+
+    let mac = match decode_base64(mac) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+
+    // the MAC of 32 zero bytes, encrypted as a secret with an empty name
+    let (aes_key, mac_key) = secret_keys(key, "");
+    let mut zeros = [0u8; 32];
+    aes_ctr(&aes_key, &iv, &mut zeros);
+
+    hmac_sha256(&mac_key, &zeros).verify_slice(&mac).is_ok()
+}
+
+/// A freshly generated backup key.
+pub fn generate_backup_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+pub fn backup_public_key(private_key: &[u8; 32]) -> String {
+    let secret = StaticSecret::from(*private_key);
+    encode_base64(PublicKey::from(&secret).as_bytes())
+}
+
+/// One room key, encrypted for the backup (all base64).
+#[derive(Debug, PartialEq)]
+pub struct EncryptedSession {
+    pub ephemeral: String,
+    pub ciphertext: String,
+    pub mac: String,
+}
+
+fn session_keys(shared_secret: &[u8]) -> ([u8; 32], [u8; 32], [u8; 16]) {
+    let mut okm = [0u8; 80];
+    hkdf_sha256(shared_secret, b"", &mut okm);
+
+    (
+        okm[..32].try_into().unwrap(),
+        okm[32..64].try_into().unwrap(),
+        okm[64..].try_into().unwrap(),
+    )
+}
+
+// This is all `m.megolm_backup.v1.curve25519-aes-sha2`. libolm calculated its
+// MAC over an empty string, rather than the ciphertext, and since every v1
+// backup out there has that MAC, it's become part of v1. It's the only MAC we
+// write, and the only one we accept.
+fn session_mac(mac_key: &[u8; 32]) -> HmacSha256 {
+    hmac_sha256(mac_key, b"")
+}
+
+pub fn encrypt_session(public_key: &str, plaintext: &[u8]) -> anyhow::Result<EncryptedSession> {
+    let public_key: [u8; 32] = decode_base64(public_key)?
+        .try_into()
+        .ok()
+        .context("Invalid backup public key.")?;
+
+    let ephemeral = generate_backup_key();
+    let (aes_key, mac_key, iv) = session_keys(&x25519(&ephemeral, &public_key));
+
+    let ciphertext = aes_cbc_encrypt(&aes_key, &iv, plaintext);
+    let mac = session_mac(&mac_key).finalize().into_bytes();
+
+    Ok(EncryptedSession {
+        ephemeral: backup_public_key(&ephemeral),
+        ciphertext: encode_base64(ciphertext),
+        mac: encode_base64(&mac[..8]),
+    })
+}
+
+pub fn decrypt_session(
+    private_key: &[u8; 32],
+    session: &EncryptedSession,
+) -> anyhow::Result<Vec<u8>> {
+    let ephemeral: [u8; 32] = decode_base64(&session.ephemeral)?
+        .try_into()
+        .ok()
+        .context("Invalid ephemeral key.")?;
+
+    let (aes_key, mac_key, iv) = session_keys(&x25519(private_key, &ephemeral));
+
+    let ciphertext = decode_base64(&session.ciphertext)?;
+    let mac = decode_base64(&session.mac)?;
+
+    if mac.len() != 8 || session_mac(&mac_key).verify_truncated_left(&mac).is_err() {
+        bail!("Bad MAC on a backed up room key.");
+    }
+
+    aes_cbc_decrypt(&aes_key, &iv, &ciphertext).context("Could not decrypt a backed up room key.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the X25519 key pairs from RFC 7748, section 6.1
+    const ALICE_PRIVATE: [u8; 32] = [
+        0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66,
+        0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9,
+        0x2c, 0x2a,
+    ];
+
+    const BOB_PRIVATE: [u8; 32] = [
+        0x5d, 0xab, 0x08, 0x7e, 0x62, 0x4a, 0x8a, 0x4b, 0x79, 0xe1, 0x7f, 0x8b, 0x83, 0x80, 0x0e,
+        0xe6, 0x6f, 0x3b, 0xb1, 0x29, 0x26, 0x18, 0xb6, 0xfd, 0x1c, 0x2f, 0x8b, 0x27, 0xff, 0x88,
+        0xe0, 0xeb,
+    ];
+
+    fn hex(input: &str) -> Vec<u8> {
+        (0..input.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&input[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Independent vectors for each primitive, so none of the Matrix level
+    // tests below are only checking us against ourselves.
+    #[test]
+    fn test_primitives() {
+        // RFC 7748, section 6.1
+        let mut base_point = [0u8; 32];
+        base_point[0] = 9;
+
+        assert_eq!(
+            x25519(&ALICE_PRIVATE, &x25519(&BOB_PRIVATE, &base_point)).to_vec(),
+            hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742")
+        );
+
+        // RFC 5869, test case 3
+        let mut okm = [0u8; 42];
+        hkdf_sha256(&[0x0b; 22], b"", &mut okm);
+
+        assert_eq!(
+            okm.to_vec(),
+            hex(
+                "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+                 9d201395faa4b61a96c8"
+            )
+        );
+
+        // RFC 4231, test case 2
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?")
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+
+        // NIST SP 800-38A, F.5.5 and F.2.5
+        let key: [u8; 32] = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4")
+            .try_into()
+            .unwrap();
+        let plaintext = hex("6bc1bee22e409f96e93d7e117393172a");
+
+        let mut ctr = plaintext.clone();
+        let counter: [u8; 16] = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        aes_ctr(&key, &counter, &mut ctr);
+
+        assert_eq!(ctr, hex("601ec313775789a5b7a7f504bbf3d228"));
+
+        let iv: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let cbc = aes_cbc_encrypt(&key, &iv, &plaintext);
+
+        // the second block is just our padding
+        assert_eq!(cbc[..16], hex("f58c4c04d6e5f1ba779eabfb5f7bfbd6"));
+        assert_eq!(aes_cbc_decrypt(&key, &iv, &cbc).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_known_recovery_key() {
+        let encoded = "EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d";
+
+        assert_eq!(encode_recovery_key(&ALICE_PRIVATE), encoded);
+        assert_eq!(decode_recovery_key(encoded).unwrap(), ALICE_PRIVATE);
+    }
+
+    #[test]
+    fn test_known_passphrase() {
+        // the first half of the published PBKDF2-HMAC-SHA512 vector
+        let expected = [
+            0x86, 0x7f, 0x70, 0xcf, 0x1a, 0xde, 0x02, 0xcf, 0xf3, 0x75, 0x25, 0x99, 0xa3, 0xa5,
+            0x3d, 0xc4, 0xaf, 0x34, 0xc7, 0xa6, 0x69, 0x81, 0x5a, 0xe5, 0xd5, 0x13, 0x55, 0x4e,
+            0x1c, 0x8c, 0xf2, 0x52,
+        ];
+
+        assert_eq!(key_from_passphrase("password", "salt", 1), expected);
+    }
+
+    #[test]
+    fn test_known_secret() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let iv: [u8; 16] = core::array::from_fn(|i| i as u8);
+
+        let expected = EncryptedSecret {
+            iv: "AAECAwQFBgcICQoLDA0ODw".to_string(),
+            ciphertext: "rClai0l5BuOvHsXH/epHEjF4i+DKwX6IA/Td2oSYnNeTxl/Y6Lm2DkY4Qw".to_string(),
+            mac: "66SmQ4L51CacH1StUqmsLJ18kDxXFH4qUn18X+JIXoY".to_string(),
+        };
+
+        let secret = b"Ha9cklU/9NqFo9WKdVfGzmqUL/9wlkdxfEitbSIPVXw";
+
+        assert_eq!(
+            encrypt_secret_with_iv(&key, "m.megolm_backup.v1", secret, iv),
+            expected
+        );
+        assert_eq!(
+            decrypt_secret(&key, "m.megolm_backup.v1", &expected).unwrap(),
+            secret
+        );
+
+        assert!(check_secret_storage_key(
+            &key,
+            "AAECAwQFBgcICQoLDA0ODw",
+            "ONrOSgDDUXMzIvXsfYBi1m8m075MdjPldfXCxIpU7IY"
+        ));
+    }
+
+    #[test]
+    fn test_known_session() {
+        assert_eq!(
+            backup_public_key(&ALICE_PRIVATE),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo"
+        );
+        assert_eq!(
+            backup_public_key(&BOB_PRIVATE),
+            "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08"
+        );
+
+        // encrypted for Bob, with Alice's key as the ephemeral one
+        let session = EncryptedSession {
+            ephemeral: backup_public_key(&ALICE_PRIVATE),
+            ciphertext: "9lq9DgATQh0Ey5ZaVGHfoeMtfpavaYtV17dAmUZKJ5KP8WdLcGpW32I+81DhjjST/\
+                         0Xloq/qyXCh/htsBcyGMplStcp4HjtPU9tdpEJ7SMQ"
+                .to_string(),
+            mac: "zpzU6BkZcNI".to_string(),
+        };
+
+        assert_eq!(
+            decrypt_session(&BOB_PRIVATE, &session).unwrap(),
+            br#"{"algorithm":"m.megolm.v1.aes-sha2","sender_key":"abc","session_key":"def"}"#
+        );
+
+        // the MAC over the ciphertext isn't what v1 backups carry
+        let session = EncryptedSession {
+            mac: "HihPrTsz5gc".to_string(),
+            ..session
+        };
+
+        assert!(decrypt_session(&BOB_PRIVATE, &session).is_err());
+    }
+
+    #[test]
+    fn test_recovery_key() {
+        let key = [7u8; 32];
+        let encoded = encode_recovery_key(&key);
+
+        assert!(encoded.starts_with("Es"));
+        assert_eq!(decode_recovery_key(&encoded).unwrap(), key);
+
+        // mess with one character and the parity check should catch it
+        let mut typo: Vec<char> = encoded.chars().collect();
+        typo[5] = if typo[5] == 'a' { 'b' } else { 'a' };
+        let typo: String = typo.into_iter().collect();
+
+        assert!(decode_recovery_key(&typo).is_err());
+        assert!(decode_recovery_key("not a key").is_err());
+    }
+
+    #[test]
+    fn test_secret_storage() {
+        let key = key_from_passphrase("correct horse", "salt", 10);
+        let encrypted = encrypt_secret(&key, "m.megolm_backup.v1", b"secret");
+
+        assert_eq!(
+            decrypt_secret(&key, "m.megolm_backup.v1", &encrypted).unwrap(),
+            b"secret"
+        );
+
+        // the name is part of the key
+        assert!(decrypt_secret(&key, "m.cross_signing.master", &encrypted).is_err());
+
+        let check = encrypt_secret(&key, "", &[0u8; 32]);
+        assert!(check_secret_storage_key(&key, &check.iv, &check.mac));
+        assert!(!check_secret_storage_key(&[1u8; 32], &check.iv, &check.mac));
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        let private_key = generate_backup_key();
+        let public_key = backup_public_key(&private_key);
+
+        let encrypted = encrypt_session(&public_key, b"{\"session_key\":\"abc\"}").unwrap();
+
+        assert_eq!(
+            decrypt_session(&private_key, &encrypted).unwrap(),
+            b"{\"session_key\":\"abc\"}"
+        );
+
+        assert!(decrypt_session(&generate_backup_key(), &encrypted).is_err());
+    }
+}
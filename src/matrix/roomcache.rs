@@ -1,7 +1,7 @@
 use anyhow::{bail, Context};
 use futures::future::join_all;
 use log::info;
-use matrix_sdk::room::{Joined, MessagesOptions, Room};
+use matrix_sdk::room::{Invited, Joined, MessagesOptions, Room};
 use matrix_sdk::{Client, DisplayName};
 use ruma::api::Direction;
 use ruma::events::room::message::MessageType::Text;
@@ -11,17 +11,19 @@ use ruma::events::AnyMessageLikeEvent::RoomMessage;
 use ruma::events::AnyTimelineEvent;
 use ruma::events::AnyTimelineEvent::MessageLike;
 use ruma::events::MessageLikeEvent::Original;
-use ruma::{MilliSecondsSinceUnixEpoch, RoomId};
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId, UserId};
 use std::sync::Mutex;
 
 pub struct RoomCache {
     rooms: Mutex<Vec<DecoratedRoom>>,
+    invites: Mutex<Vec<DecoratedInvite>>,
 }
 
 impl Default for RoomCache {
     fn default() -> Self {
         RoomCache {
             rooms: Mutex::new(vec![]),
+            invites: Mutex::new(vec![]),
         }
     }
 }
@@ -37,21 +39,116 @@ impl RoomCache {
 
         let rooms = join_all(rooms).await;
 
-        let mut old_rooms = self.rooms.lock().expect("to unlock rooms");
-        *old_rooms = rooms;
+        let invites = client
+            .invited_rooms()
+            .into_iter()
+            .map(DecoratedInvite::from_invited);
+
+        let invites = join_all(invites).await;
+
+        // keep the rooms that belong to our other accounts
+        let account = client.user_id().map(|id| id.to_owned());
+
+        {
+            let mut old_rooms = self.rooms.lock().expect("to unlock rooms");
+            old_rooms.retain(|r| Some(r.account()) != account.as_deref());
+            old_rooms.extend(rooms);
+        }
+
+        let mut old_invites = self.invites.lock().expect("to unlock invites");
+        old_invites.retain(|i| Some(i.account()) != account.as_deref());
+        old_invites.extend(invites);
 
         info!("room cache populated")
     }
 
+    /// Make sure we know about these rooms, even the ones without messages;
+    /// sliding sync tells us about them a page at a time.
+    pub async fn add_rooms(&self, client: &Client, room_ids: &[OwnedRoomId]) {
+        for room_id in room_ids {
+            let joined = match client.get_joined_room(room_id) {
+                Some(joined) => joined,
+                None => continue,
+            };
+
+            if self.wrap(&joined).is_some() {
+                continue;
+            }
+
+            let name = joined.display_name().await.unwrap_or(DisplayName::Empty);
+            let mut rooms = self.rooms.lock().expect("to unlock rooms");
+
+            // a timeline event might have beaten us here
+            if !rooms.iter().any(|r| r.is(&joined)) {
+                rooms.push(DecoratedRoom {
+                    inner: joined,
+                    name,
+                    visited: false,
+                    last_message: None,
+                    last_sender: None,
+                    last_ts: None,
+                });
+            }
+        }
+    }
+
+    /// Returns the invite, if it's one we haven't seen before.
+    pub async fn add_invite(&self, invited: Invited) -> Option<DecoratedInvite> {
+        let invite = DecoratedInvite::from_invited(invited).await;
+        let mut invites = self.invites.lock().expect("to unlock invites");
+
+        for existing in invites.iter_mut() {
+            if existing.is(&invite.inner) {
+                *existing = invite;
+                return None;
+            }
+        }
+
+        invites.insert(0, invite.clone());
+        Some(invite)
+    }
+
+    /// Once it's been accepted or declined, either here or somewhere else.
+    pub fn remove_invite(&self, user_id: &UserId, room_id: &RoomId) {
+        self.invites
+            .lock()
+            .expect("to unlock invites")
+            .retain(|i| i.account() != user_id || i.room_id() != room_id);
+    }
+
+    /// Once we've left it, or forgotten it.
+    pub fn remove_room(&self, user_id: &UserId, room_id: &RoomId) {
+        self.rooms
+            .lock()
+            .expect("to unlock rooms")
+            .retain(|r| r.account() != user_id || r.room_id() != room_id);
+    }
+
+    pub fn remove_account(&self, user_id: &UserId) {
+        self.rooms
+            .lock()
+            .expect("to unlock rooms")
+            .retain(|r| r.account() != user_id);
+
+        self.invites
+            .lock()
+            .expect("to unlock invites")
+            .retain(|i| i.account() != user_id);
+    }
+
     pub fn get_rooms(&self) -> Vec<DecoratedRoom> {
         self.rooms.lock().expect("to unlock rooms").clone()
     }
 
+    pub fn get_invites(&self) -> Vec<DecoratedInvite> {
+        self.invites.lock().expect("to unlock invites").clone()
+    }
+
     pub fn wrap(&self, joined: &Joined) -> Option<DecoratedRoom> {
         let rooms = self.rooms.lock().expect("to unlock rooms");
 
         for r in rooms.iter() {
-            if r.inner.room_id() == joined.room_id() {
+            if r.is(joined) {
                 return Some(r.clone());
             }
         }
@@ -63,7 +160,7 @@ impl RoomCache {
         let mut rooms = self.rooms.lock().expect("to unlock rooms");
 
         for dec in rooms.iter_mut() {
-            if dec.inner.room_id() == room.room_id() {
+            if dec.room_id() == room.room_id() && dec.account() == room.own_user_id() {
                 dec.visited = true;
                 return;
             }
@@ -76,12 +173,16 @@ impl RoomCache {
             None => return,
         };
 
-        let decorated = DecoratedRoom::from_joined(joined).await;
+        // the event itself is usually enough, without asking for more
+        let decorated = match DecoratedRoom::from_event(joined.clone(), event).await {
+            Some(decorated) => decorated,
+            None => DecoratedRoom::from_joined(joined).await,
+        };
 
         let mut rooms = self.rooms.lock().expect("to unlock rooms");
 
         for dec in rooms.iter_mut() {
-            if dec.inner.room_id() == event.room_id() {
+            if dec.is(&decorated.inner) {
                 *dec = decorated;
                 return;
             }
@@ -111,6 +212,17 @@ impl DecoratedRoom {
         self.inner.clone()
     }
 
+    /// The account this room was joined from.
+    pub fn account(&self) -> &UserId {
+        self.inner.own_user_id()
+    }
+
+    /// The same room can be joined by more than one of our accounts, so we
+    /// need to compare both.
+    pub fn is(&self, room: &Joined) -> bool {
+        self.room_id() == room.room_id() && self.account() == room.own_user_id()
+    }
+
     pub fn unread_count(&self) -> u64 {
         if self.visited {
             return 0;
@@ -127,6 +239,41 @@ impl DecoratedRoom {
         self.inner.unread_notification_counts().highlight_count
     }
 
+    /// Decorate a room with a message we already have, if it is one. We
+    /// only use the members we know about, rather than fetching more.
+    async fn from_event(room: Joined, event: &AnyTimelineEvent) -> Option<DecoratedRoom> {
+        let (body, sender, ts) = match event {
+            MessageLike(RoomMessage(Original(c))) => {
+                let body = match &c.content.msgtype {
+                    Text(TextMessageEventContent { body, .. }) => body.clone(),
+                    _ => "".to_string(),
+                };
+
+                (body, &c.sender, c.origin_server_ts)
+            }
+            MessageLike(RoomEncrypted(Original(c))) => {
+                ("encrypted".to_string(), &c.sender, c.origin_server_ts)
+            }
+            _ => return None,
+        };
+
+        let name = room.display_name().await.unwrap_or(DisplayName::Empty);
+
+        let sender = match room.get_member_no_sync(sender).await {
+            Ok(Some(member)) => member.name().to_string(),
+            _ => sender.localpart().to_string(),
+        };
+
+        Some(DecoratedRoom {
+            inner: room,
+            name,
+            visited: false,
+            last_message: Some(body),
+            last_sender: Some(sender),
+            last_ts: Some(ts),
+        })
+    }
+
     async fn from_joined(room: Joined) -> DecoratedRoom {
         let name = room.display_name().await.unwrap_or(DisplayName::Empty);
 
@@ -194,3 +341,50 @@ impl DecoratedRoom {
         }
     }
 }
+
+/// A room we've been invited to, but haven't joined (yet).
+#[derive(Clone)]
+pub struct DecoratedInvite {
+    pub inner: Invited,
+    pub name: DisplayName,
+
+    // who sent it, by name, if we can tell
+    pub inviter: Option<String>,
+}
+
+impl DecoratedInvite {
+    pub fn room_id(&self) -> &RoomId {
+        self.inner.room_id()
+    }
+
+    pub fn inner(&self) -> Invited {
+        self.inner.clone()
+    }
+
+    /// The account that was invited.
+    pub fn account(&self) -> &UserId {
+        self.inner.own_user_id()
+    }
+
+    pub fn is(&self, room: &Invited) -> bool {
+        self.room_id() == room.room_id() && self.account() == room.own_user_id()
+    }
+
+    async fn from_invited(room: Invited) -> DecoratedInvite {
+        let name = room.display_name().await.unwrap_or(DisplayName::Empty);
+
+        let inviter = match room.invite_details().await {
+            Ok(details) => details.inviter.map(|m| m.name().to_string()),
+            Err(err) => {
+                info!("could not fetch invite details: {}", err);
+                None
+            }
+        };
+
+        DecoratedInvite {
+            inner: room,
+            name,
+            inviter,
+        }
+    }
+}
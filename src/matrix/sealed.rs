@@ -0,0 +1,177 @@
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use matrix_sdk::ruma::exports::serde_json;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+const VERSION: u8 = 1;
+const ROUNDS: u32 = 200_000;
+
+// plenty of room to raise ROUNDS later, without a bad file hanging us
+const MAX_ROUNDS: u32 = 10 * ROUNDS;
+
+/// What we actually write to disk when a session is encrypted.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    sealed: u8,
+    salt: String,
+    rounds: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// A key derived from the passphrase for a particular salt.
+struct SealKey {
+    salt: Vec<u8>,
+    rounds: u32,
+    key: Key,
+}
+
+impl SealKey {
+    fn generate(passphrase: &str) -> Self {
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        SealKey::derive(passphrase, salt, ROUNDS)
+    }
+
+    fn derive(passphrase: &str, salt: Vec<u8>, rounds: u32) -> Self {
+        let mut key = Key::default();
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), &salt, rounds, &mut key);
+
+        SealKey { salt, rounds, key }
+    }
+}
+
+/// A passphrase that can seal and open session data. Deriving the key is
+/// slow on purpose, so we hold on to one and reuse it for every write.
+pub struct Passphrase {
+    passphrase: String,
+    key: SealKey,
+}
+
+impl Passphrase {
+    pub fn new(passphrase: String) -> Self {
+        let key = SealKey::generate(&passphrase);
+        Passphrase { passphrase, key }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> anyhow::Result<String> {
+        let mut nonce = Nonce::default();
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = ChaCha20Poly1305::new(&self.key.key)
+            .encrypt(&nonce, plaintext)
+            .ok()
+            .context("Could not encrypt the session.")?;
+
+        Ok(serde_json::to_string(&Sealed {
+            sealed: VERSION,
+            salt: STANDARD.encode(&self.key.salt),
+            rounds: self.key.rounds,
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })?)
+    }
+
+    pub fn open(&self, data: &str) -> anyhow::Result<Vec<u8>> {
+        let sealed: Sealed = serde_json::from_str(data)?;
+
+        if sealed.sealed != VERSION {
+            bail!("Unknown session format: {}", sealed.sealed);
+        }
+
+        let salt = STANDARD.decode(sealed.salt)?;
+        let nonce = STANDARD.decode(sealed.nonce)?;
+        let ciphertext = STANDARD.decode(sealed.ciphertext)?;
+
+        if nonce.len() != Nonce::default().len() {
+            bail!("Invalid session nonce.");
+        }
+
+        if sealed.rounds == 0 || sealed.rounds > MAX_ROUNDS {
+            bail!("Invalid session rounds: {}", sealed.rounds);
+        }
+
+        // files written by another run will have their own salt
+        let derived;
+
+        let key = if salt == self.key.salt && sealed.rounds == self.key.rounds {
+            &self.key.key
+        } else {
+            derived = SealKey::derive(&self.passphrase, salt, sealed.rounds);
+            &derived.key
+        };
+
+        ChaCha20Poly1305::new(key)
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .ok()
+            .context("Incorrect passphrase.")
+    }
+}
+
+/// Is this data sealed, or just plain JSON?
+pub fn is_sealed(data: &str) -> bool {
+    serde_json::from_str::<Sealed>(data).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::exports::serde_json::{self, Value};
+
+    use super::{is_sealed, Passphrase, MAX_ROUNDS};
+
+    #[test]
+    fn test_round_trip() {
+        let passphrase = Passphrase::new("hunter2".to_string());
+        let sealed = passphrase.seal(b"{\"hello\":\"world\"}").unwrap();
+
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("world"));
+
+        // a fresh passphrase has a new salt, so it has to re-derive the key
+        let other = Passphrase::new("hunter2".to_string());
+
+        assert_eq!(
+            other.open(&sealed).unwrap(),
+            b"{\"hello\":\"world\"}".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let sealed = Passphrase::new("hunter2".to_string())
+            .seal(b"secret")
+            .unwrap();
+
+        let err = Passphrase::new("hunter3".to_string())
+            .open(&sealed)
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "Incorrect passphrase.");
+    }
+
+    #[test]
+    fn test_too_many_rounds() {
+        let passphrase = Passphrase::new("hunter2".to_string());
+        let sealed = passphrase.seal(b"secret").unwrap();
+
+        let mut value: Value = serde_json::from_str(&sealed).unwrap();
+        value["rounds"] = Value::from(MAX_ROUNDS + 1);
+
+        // we shouldn't even try deriving a key for it
+        let err = passphrase.open(&value.to_string()).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid session rounds"));
+    }
+
+    #[test]
+    fn test_plain_is_not_sealed() {
+        assert!(!is_sealed("{\"client_session\":{},\"user_session\":{}}"));
+        assert!(!is_sealed("not even json"));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use log::{error, info, warn};
+use matrix_sdk::ruma::exports::serde_json;
+use matrix_sdk::Session;
+use ruma::UserId;
+use serde::{Deserialize, Serialize};
+
+use crate::matrix::backup::BackupKey;
+use crate::matrix::sealed::{self, Passphrase};
+use crate::settings::encrypt_session;
+
+/// Only ever set once the user has given us a passphrase that works.
+static PASSPHRASE: Mutex<Option<Passphrase>> = Mutex::new(None);
+
+/// Held for every read-modify-write of a session file, so the sync loop and
+/// key backup can't clobber each other's changes.
+static SESSION_WRITE: Mutex<()> = Mutex::new(());
+
+/// Backup keys for the session files we can't seal, which only last as long
+/// as we do.
+static BACKUP_KEYS: Mutex<BTreeMap<PathBuf, BackupKey>> = Mutex::new(BTreeMap::new());
+
+/// The data needed to re-build a client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientSession {
+    /// The full base URL (older sessions only have the server name).
+    pub homeserver: String,
+    pub db_path: PathBuf,
+    pub passphrase: String,
+}
+
+/// The full session to persist.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FullSession {
+    pub client_session: ClientSession,
+    pub user_session: Session,
+    pub sync_token: Option<String>,
+
+    /// Only set once the account has key backup enabled on this device, and
+    /// only ever written to a sealed session file.
+    #[serde(default)]
+    pub backup_key: Option<BackupKey>,
+}
+
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .expect("no data directory found")
+        .join("matui")
+}
+
+/// Every account gets its own session file in here, named by user ID.
+fn session_dir() -> PathBuf {
+    data_dir().join("sessions")
+}
+
+pub fn session_file(user_id: &UserId) -> PathBuf {
+    session_dir().join(user_id.as_str())
+}
+
+/// All the sessions we have on disk, one per account.
+pub fn session_files() -> Vec<PathBuf> {
+    migrate_legacy_session();
+
+    let entries = match fs::read_dir(session_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+
+    files.sort();
+    files
+}
+
+pub fn read(session_file: &Path) -> anyhow::Result<FullSession> {
+    Ok(serde_json::from_str(&read_file(session_file)?)?)
+}
+
+pub fn write(session_file: &Path, session: &FullSession) -> anyhow::Result<()> {
+    fs::create_dir_all(session_dir())?;
+    write_file(session_file, serde_json::to_string(session)?)
+}
+
+/// Read anything we wrote with `write_file`, opening it if it's sealed.
+pub fn read_file(path: &Path) -> anyhow::Result<String> {
+    let contents = fs::read_to_string(path)?;
+
+    if !sealed::is_sealed(&contents) {
+        return Ok(contents);
+    }
+
+    let passphrase = PASSPHRASE.lock().expect("to unlock passphrase");
+    let passphrase = passphrase
+        .as_ref()
+        .context("The file is encrypted, but there's no passphrase.")?;
+
+    Ok(String::from_utf8(passphrase.open(&contents)?)?)
+}
+
+/// Write private data to disk, sealed if the settings ask for it.
+pub fn write_file(path: &Path, mut contents: String) -> anyhow::Result<()> {
+    if encrypt_session() {
+        match PASSPHRASE.lock().expect("to unlock passphrase").as_ref() {
+            Some(passphrase) => {
+                contents = passphrase.seal(contents.as_bytes())?;
+            }
+
+            // encryption was turned on while we were running
+            None => warn!("no passphrase yet; writing {:?} unencrypted", path),
+        }
+    }
+
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+pub fn is_encrypted(session_file: &Path) -> bool {
+    fs::read_to_string(session_file)
+        .map(|s| sealed::is_sealed(&s))
+        .unwrap_or_default()
+}
+
+/// Do we need a passphrase before we can use these sessions?
+pub fn locked(session_files: &[PathBuf]) -> bool {
+    if has_passphrase() {
+        return false;
+    }
+
+    encrypt_session() || session_files.iter().any(|f| is_encrypted(f))
+}
+
+/// Will `write_file` actually seal what we give it?
+pub fn sealing() -> bool {
+    has_passphrase() && encrypt_session()
+}
+
+fn has_passphrase() -> bool {
+    PASSPHRASE.lock().expect("to unlock passphrase").is_some()
+}
+
+/// Hold on to the passphrase, as long as it opens our encrypted sessions.
+pub fn unlock(passphrase: String) -> anyhow::Result<()> {
+    let passphrase = Passphrase::new(passphrase);
+
+    for session_file in session_files() {
+        if is_encrypted(&session_file) {
+            passphrase.open(&fs::read_to_string(session_file)?)?;
+        }
+    }
+
+    *PASSPHRASE.lock().expect("to unlock passphrase") = Some(passphrase);
+
+    Ok(())
+}
+
+/// Re-write the session if it's not in the format that the settings ask
+/// for, in either direction.
+pub fn migrate(session_file: &Path) -> anyhow::Result<()> {
+    let wanted = sealing();
+
+    // older versions wrote the backup key to plain files too
+    let plain_backup_key = !wanted && read(session_file)?.backup_key.is_some();
+
+    if is_encrypted(session_file) == wanted && !plain_backup_key {
+        return Ok(());
+    }
+
+    update(session_file, |full_session| {
+        let mut backup_keys = BACKUP_KEYS.lock().expect("to lock backup keys");
+
+        if wanted {
+            if let Some(backup_key) = backup_keys.remove(session_file) {
+                full_session.backup_key = Some(backup_key);
+            }
+        } else if let Some(backup_key) = full_session.backup_key.take() {
+            backup_keys.insert(session_file.to_path_buf(), backup_key);
+        }
+    })?;
+
+    info!(
+        "migrated {:?} to the {} format",
+        session_file,
+        if wanted { "encrypted" } else { "plain" }
+    );
+
+    Ok(())
+}
+
+pub fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
+    update(session_file, |full_session| {
+        full_session.sync_token = Some(sync_token)
+    })
+}
+
+/// The backup key opens every room key we've ever backed up, so it only goes
+/// in the session file when that's sealed. Otherwise we keep it in memory,
+/// and it's asked for again after a restart.
+pub fn persist_backup_key(session_file: &Path, backup_key: BackupKey) -> anyhow::Result<()> {
+    let sealing = sealing();
+    let mut backup_keys = BACKUP_KEYS.lock().expect("to lock backup keys");
+
+    if sealing {
+        backup_keys.remove(session_file);
+    } else {
+        backup_keys.insert(session_file.to_path_buf(), backup_key.clone());
+    }
+
+    drop(backup_keys);
+
+    update(session_file, |full_session| {
+        full_session.backup_key = sealing.then_some(backup_key)
+    })
+}
+
+pub fn backup_key(session_file: &Path) -> Option<BackupKey> {
+    let backup_keys = BACKUP_KEYS.lock().expect("to lock backup keys");
+
+    match backup_keys.get(session_file) {
+        Some(backup_key) => Some(backup_key.clone()),
+        None => read(session_file).ok()?.backup_key,
+    }
+}
+
+pub fn forget_backup_key(session_file: &Path) {
+    BACKUP_KEYS
+        .lock()
+        .expect("to lock backup keys")
+        .remove(session_file);
+}
+
+fn update(session_file: &Path, change: impl FnOnce(&mut FullSession)) -> anyhow::Result<()> {
+    let _guard = SESSION_WRITE.lock().expect("to lock session writes");
+
+    let mut full_session = read(session_file)?;
+    change(&mut full_session);
+    write(session_file, &full_session)
+}
+
+/// Before multiple accounts, there was just a single "session" file.
+fn migrate_legacy_session() {
+    let legacy = data_dir().join("session");
+
+    if !legacy.exists() {
+        return;
+    }
+
+    let destination = match read(&legacy) {
+        Ok(session) => session_file(&session.user_session.user_id),
+        Err(err) => {
+            error!("could not read legacy session: {}", err);
+            return;
+        }
+    };
+
+    if let Err(err) =
+        fs::create_dir_all(session_dir()).and_then(|_| fs::rename(&legacy, &destination))
+    {
+        error!("could not migrate legacy session: {}", err);
+    } else {
+        info!("migrated legacy session to {:?}", destination);
+    }
+}
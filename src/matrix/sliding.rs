@@ -0,0 +1,233 @@
+//! Sliding sync (MSC3575) loads the room list a page at a time, most recent
+//! first, with just the latest event for each room. That's a lot quicker
+//! than a full `/sync` for big accounts, but homeservers don't speak it yet,
+//! so it's opt-in and goes through a proxy.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Context};
+use matrix_sdk::{Client, SlidingSync, SlidingSyncList, SlidingSyncMode};
+use ruma::events::StateEventType;
+use url::{Host, Url};
+
+const LIST_NAME: &str = "rooms";
+
+// how many rooms we ask for at a time
+const BATCH_SIZE: u32 = 50;
+
+/// The proxy from the config. Without a scheme we go with HTTPS, since our
+/// access token goes along, unless it's on this machine.
+pub fn proxy_url(input: &str) -> anyhow::Result<Url> {
+    let input = input.trim();
+
+    let invalid = || format!("Invalid sliding sync proxy: {}", input);
+
+    if input.contains("://") {
+        return Url::parse(input).with_context(invalid);
+    }
+
+    let mut url = Url::parse(&format!("https://{}", input)).with_context(invalid)?;
+
+    if is_local(&url) {
+        url.set_scheme("http").map_err(|_| anyhow!(invalid()))?;
+    }
+
+    Ok(url)
+}
+
+fn is_local(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip == Ipv4Addr::LOCALHOST,
+        Some(Host::Ipv6(ip)) => ip == Ipv6Addr::LOCALHOST,
+        None => false,
+    }
+}
+
+pub async fn build(client: &Client, proxy: Url) -> anyhow::Result<SlidingSync> {
+    let list = SlidingSyncList::builder()
+        .name(LIST_NAME)
+        .sync_mode(SlidingSyncMode::GrowingFullSync)
+        .batch_size(BATCH_SIZE)
+        .sort(vec!["by_recency".to_string(), "by_name".to_string()])
+        .timeline_limit(1u32)
+        .required_state(vec![
+            (StateEventType::RoomEncryption, "".to_string()),
+            (StateEventType::RoomName, "".to_string()),
+            (StateEventType::RoomMember, "$ME".to_string()),
+        ])
+        .build()?;
+
+    // the extensions bring along to-device messages and keys, for E2E
+    let sliding_sync = client
+        .sliding_sync()
+        .await
+        .homeserver(proxy)
+        .add_list(list)
+        .with_common_extensions()
+        .build()
+        .await?;
+
+    Ok(sliding_sync)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use futures::pin_mut;
+    use futures::stream::StreamExt;
+    use log::error;
+    use matrix_sdk::ruma::exports::serde_json::{self, json, Value};
+    use matrix_sdk::{Client, Session};
+    use ruma::{device_id, room_id, user_id};
+
+    use super::{build, proxy_url};
+
+    // a stand-in for the proxy (and the homeserver behind it), with one room
+    fn serve(listener: TcpListener) {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = respond(stream) {
+                error!("proxy connection failed: {}", err);
+            }
+        }
+    }
+
+    fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        let mut length = 0;
+
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+
+            if header.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse()?;
+                }
+            }
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+
+        let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+
+        let response = if path.contains("msc3575/sync") {
+            json!({
+                "pos": "1",
+                "txn_id": request["txn_id"],
+                "lists": {
+                    "rooms": {
+                        "count": 1,
+                        "ops": [{ "op": "SYNC", "range": [0, 0], "room_ids": ["!a:example.org"] }],
+                    },
+                },
+                "rooms": {
+                    "!a:example.org": { "name": "Sliding", "initial": true },
+                },
+                "extensions": {},
+            })
+        } else if path.contains("/versions") {
+            json!({ "versions": ["v1.5"] })
+        } else if path.contains("/keys/upload") {
+            json!({ "one_time_key_counts": {} })
+        } else if path.contains("/keys/query") {
+            json!({ "device_keys": {} })
+        } else {
+            json!({})
+        };
+
+        let response = response.to_string();
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.len(),
+            response
+        )?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_first_response_has_rooms() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener));
+
+        let client = Client::builder()
+            .homeserver_url(format!("http://{}", address))
+            .build()
+            .await
+            .unwrap();
+
+        client
+            .restore_session(Session {
+                access_token: "token".to_string(),
+                refresh_token: None,
+                user_id: user_id!("@me:example.org").to_owned(),
+                device_id: device_id!("MATUI").to_owned(),
+            })
+            .await
+            .unwrap();
+
+        let proxy = proxy_url(&address.to_string()).unwrap();
+        let sliding_sync = build(&client, proxy).await.unwrap();
+
+        let stream = sliding_sync.stream();
+        pin_mut!(stream);
+
+        let summary = stream.next().await.unwrap().unwrap();
+        let room_id = room_id!("!a:example.org");
+
+        // the room list is there to choose from after one round trip
+        assert_eq!(summary.rooms, vec![room_id.to_owned()]);
+        assert!(client.get_joined_room(room_id).is_some());
+    }
+
+    #[test]
+    fn test_proxy_url() {
+        assert_eq!(
+            proxy_url("localhost:8008").unwrap().as_str(),
+            "http://localhost:8008/"
+        );
+
+        assert_eq!(
+            proxy_url("[::1]:8008").unwrap().as_str(),
+            "http://[::1]:8008/"
+        );
+
+        // anywhere else, the token isn't going out in the clear
+        assert_eq!(
+            proxy_url("slidingsync.example.org").unwrap().as_str(),
+            "https://slidingsync.example.org/"
+        );
+
+        assert_eq!(
+            proxy_url("192.168.1.5:8008").unwrap().as_str(),
+            "https://192.168.1.5:8008/"
+        );
+
+        assert_eq!(
+            proxy_url(" https://slidingsync.example.org ")
+                .unwrap()
+                .as_str(),
+            "https://slidingsync.example.org/"
+        );
+
+        assert!(proxy_url("http://").is_err());
+    }
+}
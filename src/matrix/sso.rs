@@ -0,0 +1,169 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use log::warn;
+use rand::rngs::OsRng;
+use rand::{distributions::Alphanumeric, Rng};
+
+/// How long we'll wait for the user to finish up in their browser.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+// how long any one connection gets to send its request line
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SUCCESS: &str = "<html><body><h2>You're signed in!</h2>\
+    <p>You can close this window and head back to Matui.</p></body></html>";
+
+const FAILURE: &str = "<html><body><h2>Something went wrong.</h2>\
+    <p>No login token was found; please try again.</p></body></html>";
+
+/// A random path for the redirect, so only the login we started can finish.
+/// Anything else on this machine can reach the port, after all.
+pub fn callback_path() -> String {
+    let state: String = OsRng
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    format!("/{}/", state)
+}
+
+/// Sit on the loopback listener until the homeserver redirects the browser
+/// back to us, at `path`, with a login token.
+pub fn wait_for_login_token(listener: TcpListener, path: &str) -> anyhow::Result<String> {
+    listener.set_nonblocking(true)?;
+    let started = Instant::now();
+
+    loop {
+        match listener.accept() {
+            // one bad connection (a preconnect, a port scan) shouldn't end the login
+            Ok((stream, _)) => match handle_connection(stream, path) {
+                Ok(Some(token)) => return Ok(token),
+                Ok(None) => {}
+                Err(err) => warn!("skipping SSO connection: {}", err),
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                if started.elapsed() > TIMEOUT {
+                    bail!("Timed out waiting for the SSO login.")
+                }
+
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, path: &str) -> anyhow::Result<Option<String>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut request_line)?;
+
+    let token = parse_login_token(&request_line, path);
+
+    // browsers love to ask for a favicon; just ignore anything without a token
+    // for our path
+    let body = if token.is_some() { SUCCESS } else { FAILURE };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    Ok(token)
+}
+
+/// Pull the `loginToken` query parameter out of an HTTP request line, as long
+/// as it was sent to our path.
+fn parse_login_token(request_line: &str, path: &str) -> Option<String> {
+    let target = request_line.split_whitespace().nth(1)?;
+    let (target_path, query) = target.split_once('?')?;
+
+    if target_path != path {
+        return None;
+    }
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "loginToken")
+        .map(|(_, value)| percent_decode(value))
+        .filter(|token| !token.is_empty())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_login_token;
+
+    const PATH: &str = "/abc/";
+
+    #[test]
+    fn it_finds_the_login_token() {
+        assert_eq!(
+            parse_login_token("GET /abc/?loginToken=abc123 HTTP/1.1\r\n", PATH),
+            Some("abc123".to_string())
+        );
+
+        assert_eq!(
+            parse_login_token("GET /abc/?foo=bar&loginToken=a%2Bb%3D HTTP/1.1\r\n", PATH),
+            Some("a+b=".to_string())
+        );
+    }
+
+    #[test]
+    fn it_ignores_other_requests() {
+        assert_eq!(
+            parse_login_token("GET /favicon.ico HTTP/1.1\r\n", PATH),
+            None
+        );
+        assert_eq!(
+            parse_login_token("GET /abc/?loginToken= HTTP/1.1\r\n", PATH),
+            None
+        );
+        assert_eq!(parse_login_token("", PATH), None);
+    }
+
+    #[test]
+    fn it_ignores_tokens_for_other_paths() {
+        assert_eq!(
+            parse_login_token("GET /?loginToken=abc123 HTTP/1.1\r\n", PATH),
+            None
+        );
+        assert_eq!(
+            parse_login_token("GET /xyz/?loginToken=abc123 HTTP/1.1\r\n", PATH),
+            None
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! The most recent events for each room, on disk, so a room has something
+//! to show the moment it opens. They're sealed along with the sessions, when
+//! those are, and that's the only time we keep anything that was encrypted.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use matrix_sdk::deserialized_responses::EncryptionInfo;
+use matrix_sdk::ruma::exports::serde_json;
+use ruma::events::{AnySyncTimelineEvent, AnyTimelineEvent};
+use ruma::serde::Raw;
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+use crate::matrix::session::{data_dir, read_file, sealing, write_file};
+
+// per room, which is plenty to fill a screen
+const MAX_EVENTS: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+struct CachedEvent {
+    event: Raw<AnySyncTimelineEvent>,
+    encryption: Option<EncryptionInfo>,
+    ts: MilliSecondsSinceUnixEpoch,
+}
+
+type CachedTimeline = BTreeMap<OwnedEventId, CachedEvent>;
+
+type Events = Vec<(Raw<AnySyncTimelineEvent>, Option<EncryptionInfo>)>;
+
+pub struct TimelineCache {
+    // Rooms we've caught up on since we started. Live events only go on the
+    // end of these; anything else would leave a gap.
+    fresh: Mutex<HashSet<(OwnedUserId, OwnedRoomId)>>,
+
+    // Events wait here for the writer, which runs off the async threads.
+    // Whatever shows up while it's busy goes out in one write per room.
+    pending: Mutex<Pending>,
+
+    // so two writers don't trample each other
+    writing: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Pending {
+    writes: BTreeMap<(OwnedUserId, OwnedRoomId), PendingWrite>,
+    flushing: bool,
+}
+
+struct PendingWrite {
+    events: Events,
+    keep_old: bool,
+}
+
+impl Default for TimelineCache {
+    fn default() -> Self {
+        TimelineCache {
+            fresh: Mutex::new(HashSet::new()),
+            pending: Mutex::new(Pending::default()),
+            writing: Mutex::new(()),
+        }
+    }
+}
+
+impl TimelineCache {
+    /// Everything we have for the room, oldest first.
+    pub fn read(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Vec<(AnyTimelineEvent, Option<EncryptionInfo>)> {
+        let _writing = self.writing.lock().expect("to unlock writing");
+
+        let timeline = read_timeline(user_id, room_id);
+
+        // left over from before we stopped caching these
+        if !sealing() && has_encrypted(&timeline) {
+            return vec![];
+        }
+
+        let mut events: Vec<CachedEvent> = timeline.into_values().collect();
+        events.sort_by_key(|e| e.ts);
+
+        events
+            .into_iter()
+            .filter_map(|e| {
+                let event = e.event.deserialize().ok()?;
+                Some((event.into_full_event(room_id.to_owned()), e.encryption))
+            })
+            .collect()
+    }
+
+    /// We've filled in the room from the newest event back. If that didn't
+    /// get as far as what we had, the old events are no good to us anymore.
+    pub fn caught_up(
+        self: &Arc<Self>,
+        user_id: &UserId,
+        room_id: &RoomId,
+        events: Events,
+        keep_old: bool,
+    ) {
+        self.fresh
+            .lock()
+            .expect("to unlock fresh")
+            .insert((user_id.to_owned(), room_id.to_owned()));
+
+        self.queue(user_id, room_id, events, keep_old);
+    }
+
+    /// More events for a room, which we'll only keep if it's caught up.
+    pub fn add(self: &Arc<Self>, user_id: &UserId, room_id: &RoomId, events: Events) {
+        if self.is_fresh(user_id, room_id) {
+            self.queue(user_id, room_id, events, true);
+        }
+    }
+
+    fn is_fresh(&self, user_id: &UserId, room_id: &RoomId) -> bool {
+        self.fresh
+            .lock()
+            .expect("to unlock fresh")
+            .contains(&(user_id.to_owned(), room_id.to_owned()))
+    }
+
+    fn queue(self: &Arc<Self>, user_id: &UserId, room_id: &RoomId, events: Events, keep_old: bool) {
+        let mut pending = self.pending.lock().expect("to unlock pending");
+
+        let write = pending
+            .writes
+            .entry((user_id.to_owned(), room_id.to_owned()))
+            .or_insert_with(|| PendingWrite {
+                events: vec![],
+                keep_old: true,
+            });
+
+        write.events.extend(events);
+        write.keep_old &= keep_old;
+
+        if pending.flushing {
+            return;
+        }
+
+        pending.flushing = true;
+
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.flush());
+    }
+
+    // write out everything that's waiting, until nothing is
+    fn flush(&self) {
+        loop {
+            let writes = {
+                let mut pending = self.pending.lock().expect("to unlock pending");
+
+                if pending.writes.is_empty() {
+                    pending.flushing = false;
+                    return;
+                }
+
+                std::mem::take(&mut pending.writes)
+            };
+
+            for ((user_id, room_id), write) in writes {
+                // we might have left since
+                if self.is_fresh(&user_id, &room_id) {
+                    self.write(&user_id, &room_id, write.events, write.keep_old);
+                }
+            }
+        }
+    }
+
+    /// We can't read a room once we've left, so there's nothing to show.
+    pub fn remove_room(&self, user_id: &UserId, room_id: &RoomId) {
+        let _writing = self.writing.lock().expect("to unlock writing");
+
+        self.fresh
+            .lock()
+            .expect("to unlock fresh")
+            .remove(&(user_id.to_owned(), room_id.to_owned()));
+
+        self.pending
+            .lock()
+            .expect("to unlock pending")
+            .writes
+            .remove(&(user_id.to_owned(), room_id.to_owned()));
+
+        remove_timeline(user_id, room_id);
+    }
+
+    pub fn remove_account(&self, user_id: &UserId) {
+        let _writing = self.writing.lock().expect("to unlock writing");
+
+        self.fresh
+            .lock()
+            .expect("to unlock fresh")
+            .retain(|(id, _)| id != user_id);
+
+        self.pending
+            .lock()
+            .expect("to unlock pending")
+            .writes
+            .retain(|(id, _), _| id != user_id);
+
+        let dir = timeline_dir(user_id);
+
+        if dir.exists() {
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                error!("could not remove {:?}: {}", dir, err);
+            }
+        }
+    }
+
+    fn write(&self, user_id: &UserId, room_id: &RoomId, events: Events, keep_old: bool) {
+        let _writing = self.writing.lock().expect("to unlock writing");
+
+        let mut timeline = if keep_old {
+            read_timeline(user_id, room_id)
+        } else {
+            CachedTimeline::new()
+        };
+
+        for (event, encryption) in events {
+            let deserialized = match event.deserialize() {
+                Ok(deserialized) => deserialized,
+                Err(_) => continue,
+            };
+
+            // a decrypted event replaces the encrypted one
+            timeline.insert(
+                deserialized.event_id().to_owned(),
+                CachedEvent {
+                    event,
+                    encryption,
+                    ts: deserialized.origin_server_ts(),
+                },
+            );
+        }
+
+        // decrypted events would sit on disk in the clear, so without
+        // sealing, encrypted rooms don't get cached at all
+        if !sealing() && has_encrypted(&timeline) {
+            self.fresh
+                .lock()
+                .expect("to unlock fresh")
+                .remove(&(user_id.to_owned(), room_id.to_owned()));
+
+            remove_timeline(user_id, room_id);
+            return;
+        }
+
+        trim(&mut timeline);
+
+        if let Err(err) = write_timeline(user_id, room_id, &timeline) {
+            error!("could not cache the timeline for {}: {}", room_id, err);
+        }
+    }
+}
+
+fn timeline_dir(user_id: &UserId) -> PathBuf {
+    data_dir().join("timelines").join(user_id.as_str())
+}
+
+fn read_timeline(user_id: &UserId, room_id: &RoomId) -> CachedTimeline {
+    let path = timeline_dir(user_id).join(room_id.as_str());
+
+    if !path.exists() {
+        return CachedTimeline::new();
+    }
+
+    let read = read_file(&path).and_then(|c| Ok(serde_json::from_str(&c)?));
+
+    // it's only a cache; start over
+    read.unwrap_or_else(|err| {
+        error!("could not read the timeline for {}: {}", room_id, err);
+        CachedTimeline::new()
+    })
+}
+
+fn write_timeline(
+    user_id: &UserId,
+    room_id: &RoomId,
+    timeline: &CachedTimeline,
+) -> anyhow::Result<()> {
+    let dir = timeline_dir(user_id);
+    fs::create_dir_all(&dir)?;
+
+    write_file(
+        &dir.join(room_id.as_str()),
+        serde_json::to_string(timeline)?,
+    )
+}
+
+fn remove_timeline(user_id: &UserId, room_id: &RoomId) {
+    let path = timeline_dir(user_id).join(room_id.as_str());
+
+    if path.exists() {
+        if let Err(err) = fs::remove_file(&path) {
+            error!("could not remove {:?}: {}", path, err);
+        }
+    }
+}
+
+fn has_encrypted(timeline: &CachedTimeline) -> bool {
+    timeline.values().any(|e| e.encryption.is_some())
+}
+
+// drop the oldest events, past our limit
+fn trim(timeline: &mut CachedTimeline) {
+    if timeline.len() <= MAX_EVENTS {
+        return;
+    }
+
+    let mut oldest: Vec<(MilliSecondsSinceUnixEpoch, OwnedEventId)> =
+        timeline.iter().map(|(id, e)| (e.ts, id.clone())).collect();
+
+    oldest.sort();
+
+    for (_, id) in oldest.into_iter().take(timeline.len() - MAX_EVENTS) {
+        timeline.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::exports::serde_json;
+    use ruma::serde::Raw;
+    use ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, UInt};
+
+    use super::{trim, CachedEvent, CachedTimeline, MAX_EVENTS};
+
+    #[test]
+    fn test_trim() {
+        let mut timeline = CachedTimeline::new();
+
+        for i in 0..MAX_EVENTS + 5 {
+            let id = OwnedEventId::try_from(format!("$event{}:example.org", i)).unwrap();
+            let json = serde_json::value::to_raw_value(&serde_json::json!({})).unwrap();
+
+            timeline.insert(
+                id,
+                CachedEvent {
+                    event: Raw::from_json(json),
+                    encryption: None,
+                    ts: MilliSecondsSinceUnixEpoch(UInt::from(i as u32)),
+                },
+            );
+        }
+
+        trim(&mut timeline);
+
+        assert_eq!(timeline.len(), MAX_EVENTS);
+
+        // the five oldest are gone
+        assert!(timeline.values().all(|e| e.ts.0 >= UInt::from(5u32)));
+    }
+}
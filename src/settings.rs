@@ -2,6 +2,7 @@ use config::Config;
 use log::{info, warn};
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use ruma::RoomId;
+use serde::Deserialize;
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::{RwLock, RwLockReadGuard};
@@ -42,6 +43,55 @@ pub fn clean_vim() -> bool {
     get_settings().get("clean_vim").unwrap_or_default()
 }
 
+pub fn encrypt_session() -> bool {
+    get_settings().get("encrypt_session").unwrap_or_default()
+}
+
+pub fn key_backup() -> bool {
+    get_settings().get("key_backup").unwrap_or_default()
+}
+
+pub fn sliding_sync_proxy() -> Option<String> {
+    get_settings().get("sliding_sync_proxy").ok()
+}
+
+/// What we ask the homeserver to leave out of syncs, from `[sync_filter]`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SyncFilter {
+    /// How many events per room in each sync; the rest get paged in.
+    pub timeline_limit: Option<u32>,
+    pub skip_state_types: Vec<String>,
+    pub exclude_rooms: Vec<String>,
+    pub presence: bool,
+
+    /// Typing notifications and read receipts.
+    pub ephemeral: bool,
+}
+
+impl Default for SyncFilter {
+    fn default() -> Self {
+        SyncFilter {
+            timeline_limit: None,
+            skip_state_types: vec![],
+            exclude_rooms: vec![],
+            presence: true,
+            ephemeral: true,
+        }
+    }
+}
+
+pub fn sync_filter() -> SyncFilter {
+    match get_settings().get("sync_filter") {
+        Ok(filter) => filter,
+        Err(config::ConfigError::NotFound(_)) => SyncFilter::default(),
+        Err(err) => {
+            warn!("ignoring the sync filter: {}", err);
+            SyncFilter::default()
+        }
+    }
+}
+
 fn watch_internal() {
     let (tx, rx) = channel();
 
@@ -30,6 +30,16 @@ pub fn get_file_paths() -> anyhow::Result<Vec<PathBuf>> {
     Ok(path)
 }
 
+pub fn get_file_path() -> anyhow::Result<Option<PathBuf>> {
+    let home = dirs::home_dir().context("no home directory")?;
+
+    let path = FileDialog::new()
+        .set_location(home.as_path())
+        .show_open_single_file()?;
+
+    Ok(path)
+}
+
 pub fn get_text(existing: Option<&str>, suffix: Option<&str>) -> anyhow::Result<Option<String>> {
     let editor = &var("EDITOR").unwrap_or("/usr/bin/vi".to_string());
     let mut tmpfile = Builder::new().suffix(".md").tempfile()?;
@@ -156,16 +166,25 @@ pub fn view_text(text: &str) {
     let finder = LinkFinder::new();
 
     for link in finder.links(text) {
-        let mut command = open::commands(link.as_str()).into_iter().next().unwrap();
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
-
-        if let Err(e) = command.status() {
+        if let Err(e) = open_url(link.as_str()) {
             error!("could not open link: {} {}", link.as_str(), e.to_string());
         }
     }
 }
 
+pub fn open_url(url: &str) -> anyhow::Result<()> {
+    let mut command = open::commands(url)
+        .into_iter()
+        .next()
+        .context("no way to open urls")?;
+
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    command.status()?;
+
+    Ok(())
+}
+
 pub fn send_notification(summary: &str, body: &str, image: Option<Vec<u8>>) -> anyhow::Result<()> {
     if let Some(img) = image {
         let data = Cursor::new(img);
@@ -0,0 +1,218 @@
+use std::cell::Cell;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, BorderType, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
+use ruma::OwnedUserId;
+
+use crate::app::Popup;
+use crate::matrix::matrix::Matrix;
+use crate::widgets::confirm::{Confirm, ConfirmBehavior};
+use crate::widgets::rooms::top_room;
+use crate::widgets::signin::Signin;
+use crate::widgets::EventResult::Consumed;
+use crate::{close, consumed};
+
+use super::{get_margin, EventResult};
+
+/// An account we're signed in to, along with what's waiting for it.
+struct Account {
+    user_id: OwnedUserId,
+    active: bool,
+    unread: u64,
+}
+
+pub struct Accounts {
+    accounts: Vec<Account>,
+    list_state: Cell<ListState>,
+}
+
+impl Accounts {
+    pub fn new(matrix: Matrix) -> Self {
+        let rooms = matrix.fetch_rooms();
+        let active = matrix.active_account();
+
+        let accounts = matrix
+            .accounts()
+            .into_iter()
+            .map(|user_id| Account {
+                active: active.as_ref() == Some(&user_id),
+                unread: rooms
+                    .iter()
+                    .filter(|r| *r.account() == *user_id)
+                    .map(|r| r.unread_count())
+                    .sum(),
+                user_id,
+            })
+            .collect();
+
+        let mut list_state = ListState::default();
+        list_state.select(Some(0));
+
+        Self {
+            accounts,
+            list_state: Cell::new(list_state),
+        }
+    }
+
+    pub fn widget(&self) -> AccountsWidget {
+        AccountsWidget { accounts: self }
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.next();
+                consumed!()
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.previous();
+                consumed!()
+            }
+            KeyCode::Char('l') => match self.selected() {
+                Some(account) => {
+                    let confirm = Confirm::new(
+                        "Log Out".to_string(),
+                        format!(
+                            "Log out of {}? This device will be removed from your account.",
+                            account.user_id
+                        ),
+                        "Yes".to_string(),
+                        "No".to_string(),
+                        ConfirmBehavior::Logout(account.user_id.clone()),
+                    );
+
+                    Consumed(Box::new(|app| app.set_popup(Popup::Confirm(confirm))))
+                }
+                None => EventResult::Ignored,
+            },
+            KeyCode::Enter => match self.selected() {
+                Some(account) => {
+                    let user_id = account.user_id.clone();
+
+                    Consumed(Box::new(move |app| {
+                        app.close_popup();
+                        app.matrix.set_active_account(&user_id);
+
+                        if let Some(room) = top_room(app.matrix.fetch_rooms(), &user_id) {
+                            app.select_room(room);
+                        }
+                    }))
+                }
+
+                // the last item is always "add account"
+                None => Consumed(Box::new(|app| {
+                    app.set_popup(Popup::Signin(Signin::add_account()))
+                })),
+            },
+            _ => EventResult::Ignored,
+        }
+    }
+
+    fn next(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(i) if i < self.accounts.len() => i + 1,
+            _ => 0,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn previous(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(0) | None => self.accounts.len(),
+            Some(i) => i - 1,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn selected(&self) -> Option<&Account> {
+        let state = self.list_state.take();
+        let selected = state.selected().unwrap_or_default();
+        self.list_state.set(state);
+
+        self.accounts.get(selected)
+    }
+}
+
+pub struct AccountsWidget<'a> {
+    pub accounts: &'a Accounts,
+}
+
+impl Widget for AccountsWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let height = self.accounts.accounts.len() as u16 + 9;
+
+        let area = Layout::default()
+            .direction(Direction::Horizontal)
+            .vertical_margin(get_margin(area.height, height))
+            .horizontal_margin(get_margin(area.width, 60))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("Accounts")
+            .title_alignment(Alignment::Center)
+            .style(Style::default().bg(Color::Black))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .vertical_margin(2)
+            .horizontal_margin(3)
+            .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
+            .split(area);
+
+        let mut items: Vec<ListItem> = self.accounts.accounts.iter().map(make_list_item).collect();
+
+        items.push(ListItem::new(Span::styled(
+            "+ Add account",
+            Style::default().fg(Color::Green),
+        )));
+
+        let mut list_state = self.accounts.list_state.take();
+        let list = List::new(items).highlight_symbol("> ");
+        StatefulWidget::render(list, splits[0], buf, &mut list_state);
+        self.accounts.list_state.set(list_state);
+
+        Paragraph::new("Enter to switch, l to log out")
+            .style(Style::default().fg(Color::DarkGray))
+            .alignment(Alignment::Center)
+            .render(splits[1], buf);
+    }
+}
+
+fn make_list_item(account: &Account) -> ListItem {
+    let mut spans = vec![Span::from(account.user_id.to_string())];
+
+    if account.unread > 0 {
+        spans.push(Span::styled(
+            format!(" ({})", account.unread),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    if account.active {
+        spans.push(Span::styled(" (active)", Style::default().fg(Color::Green)));
+    }
+
+    ListItem::new(Line::from(spans))
+}
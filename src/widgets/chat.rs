@@ -1,28 +1,33 @@
 use crate::app::{App, Popup};
 use crate::event::{Event, EventHandler};
 use crate::handler::Batch;
+use crate::matrix::clients::Connection;
 use crate::matrix::matrix::Matrix;
+use crate::matrix::outbox::OutboxItem;
 use crate::matrix::roomcache::DecoratedRoom;
 use crate::settings::is_muted;
 use crate::spawn::{get_file_paths, get_text};
 use crate::widgets::message::{Message, Reaction, ReactionEvent};
 use crate::widgets::react::React;
 use crate::widgets::react::ReactResult;
+use crate::widgets::rooms::leave_confirm;
 use crate::widgets::EventResult::Consumed;
 use crate::widgets::{get_margin, EventResult};
 use crate::{consumed, limit_list, pretty_list, truncate, KeyCombo};
 use anyhow::bail;
 use crossterm::event::{KeyCode, KeyEvent};
 use log::info;
+use matrix_sdk::deserialized_responses::EncryptionInfo;
 use matrix_sdk::room::{Joined, Room, RoomMember};
 use once_cell::sync::OnceCell;
 use ruma::events::receipt::ReceiptEventContent;
 use ruma::events::room::message::MessageType::Text;
 use ruma::events::AnyTimelineEvent;
-use ruma::{OwnedEventId, OwnedUserId};
+use ruma::{EventId, OwnedEventId, OwnedUserId, RoomId, UserId};
+use std::borrow::Cow;
 use std::cell::Cell;
 use std::cmp::Ordering;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ops::Deref;
 
 use ratatui::buffer::Buffer;
@@ -56,6 +61,15 @@ pub struct Chat {
     members: Vec<RoomMember>,
     pretty_members: OnceCell<String>,
     in_flight: Vec<OwnedUserId>,
+
+    // undecryptable events we've already tried again
+    retried: HashSet<OwnedEventId>,
+
+    // how the room's account is getting on with its homeserver
+    connection: Connection,
+
+    // what we're still sending to the room
+    outbox: Vec<OutboxItem>,
 }
 
 impl Chat {
@@ -65,13 +79,16 @@ impl Chat {
             None => return None,
         };
 
-        matrix.fetch_messages(room, None);
+        let me = decorated_room.account().to_owned();
+        let connection = matrix.connection(&me);
+        let outbox = matrix.outbox(&room);
+        matrix.open_timeline(room);
 
         Some(Self {
             matrix: matrix.clone(),
             room: decorated_room,
             events: BTreeSet::new(),
-            receipts: Receipts::new(matrix.me()),
+            receipts: Receipts::new(me),
             messages: vec![],
             read_to: None,
             react: None,
@@ -86,6 +103,9 @@ impl Chat {
             members: vec![],
             pretty_members: OnceCell::new(),
             in_flight: vec![],
+            retried: HashSet::new(),
+            connection,
+            outbox,
         })
     }
 
@@ -133,6 +153,24 @@ impl Chat {
             }
         }
 
+        // messages that haven't gone out yet can only be sent again (or
+        // discarded, below)
+        if let Some(item) = self.selected_reply().and_then(|m| m.outgoing.as_ref()) {
+            match input.code {
+                KeyCode::Enter if item.failed() => {
+                    self.matrix.retry_outgoing(&item.txn_id);
+                    return Ok(consumed!());
+                }
+                KeyCode::Enter
+                | KeyCode::Char('s')
+                | KeyCode::Char('c')
+                | KeyCode::Char('r')
+                | KeyCode::Char('R')
+                | KeyCode::Char('t') => return Ok(EventResult::Ignored),
+                _ => {}
+            }
+        }
+
         // then look for key combos
         if let KeyCode::Char(c) = input.code {
             if self.delete_combo.record(c) {
@@ -141,6 +179,26 @@ impl Chat {
                     None => return Ok(EventResult::Ignored),
                 };
 
+                if let Some(item) = &message.outgoing {
+                    if !item.failed() {
+                        return Ok(EventResult::Ignored);
+                    }
+
+                    let preview = truncate(message.display().to_string(), 16);
+
+                    let confirm = Confirm::new(
+                        "Discard Message".to_string(),
+                        format!("Are you sure you want to discard \"{}\"", preview),
+                        "Yes".to_string(),
+                        "No".to_string(),
+                        ConfirmBehavior::DiscardMessage(item.txn_id.clone()),
+                    );
+
+                    return Ok(Consumed(Box::new(|app| {
+                        app.set_popup(Popup::Confirm(confirm))
+                    })));
+                }
+
                 let preview = truncate(message.display().to_string(), 16);
                 let warning = format!("Are you sure you want to delete \"{}\"", preview);
 
@@ -170,13 +228,13 @@ impl Chat {
             }
             KeyCode::Enter => {
                 if let Some(message) = &self.selected_reply() {
-                    message.open(self.matrix.clone())
+                    message.open(self.matrix.clone(), self.room())
                 }
                 Ok(consumed!())
             }
             KeyCode::Char('s') => {
                 if let Some(message) = &self.selected_reply() {
-                    message.save(self.matrix.clone())
+                    message.save(self.matrix.clone(), self.room())
                 }
                 Ok(consumed!())
             }
@@ -316,6 +374,38 @@ impl Chat {
                 ));
                 Ok(consumed!())
             }
+            KeyCode::Char('t') => {
+                let message = match self.selected_reply() {
+                    Some(m) => m,
+                    None => return Ok(EventResult::Ignored),
+                };
+
+                if message.sender.id == self.me() {
+                    return Ok(EventResult::Ignored);
+                }
+
+                let confirm = Confirm::new(
+                    "Verify".to_string(),
+                    format!(
+                        "Ask {} to verify with you? They'll get the request in a direct message.",
+                        message.sender.as_str()
+                    ),
+                    "Yes".to_string(),
+                    "No".to_string(),
+                    ConfirmBehavior::VerifyUser(self.room(), message.sender.id.clone()),
+                );
+
+                Ok(Consumed(Box::new(|app| {
+                    app.set_popup(Popup::Confirm(confirm))
+                })))
+            }
+            KeyCode::Char(c @ ('L' | 'F')) => {
+                let confirm = leave_confirm(&self.room, c == 'F');
+
+                Ok(Consumed(Box::new(|app| {
+                    app.set_popup(Popup::Confirm(confirm))
+                })))
+            }
             KeyCode::Char('u') => {
                 let paths = get_file_paths()?;
 
@@ -342,18 +432,34 @@ impl Chat {
         self.focus = false;
     }
 
-    pub fn timeline_event(&mut self, event: AnyTimelineEvent) {
+    pub fn timeline_event(&mut self, event: AnyTimelineEvent, encryption: Option<EncryptionInfo>) {
         if event.room_id() != self.room.room_id() {
             return;
         }
 
         self.check_event_sender(&event);
-        self.events.insert(OrderedEvent::new(event));
-        self.messages = make_message_list(&self.events, &self.members, &self.receipts);
+        self.retry_decryption(&event);
+        self.events.insert(OrderedEvent::new(event, encryption));
+        self.messages =
+            make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
         self.pretty_members = OnceCell::new();
         self.set_fully_read();
     }
 
+    pub fn outbox_event(&mut self, mut items: Vec<OutboxItem>) {
+        items.retain(|i| i.user_id == self.room.account() && i.room_id == self.room.room_id());
+
+        self.outbox = items;
+        self.messages =
+            make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
+    }
+
+    pub fn connection_event(&mut self, user_id: &UserId, connection: Connection) {
+        if user_id == self.room.account() {
+            self.connection = connection;
+        }
+    }
+
     pub fn typing_event(&mut self, joined: Joined, ids: Vec<OwnedUserId>) {
         if joined.room_id() != self.room.room_id() {
             return;
@@ -393,9 +499,10 @@ impl Chat {
     pub fn receipt_event(&mut self, joined: &Joined, content: &ReceiptEventContent) {
         if joined.room_id() == self.room.room_id() {
             self.receipts.apply_event(content);
-            self.messages = make_message_list(&self.events, &self.members, &self.receipts);
+            self.messages =
+                make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
             self.pretty_members = OnceCell::new();
-            let me = self.matrix.me();
+            let me = self.me();
 
             // make sure we fetch any users we don't know about
             for id in Receipts::get_senders(content) {
@@ -416,16 +523,20 @@ impl Chat {
         }
 
         self.next_cursor = batch.cursor;
+        self.events.retain(|e| !batch.stale.contains(e.event_id()));
+
         let previous_count = self.messages.len();
 
-        for event in batch.events {
+        for (event, encryption) in batch.events {
             self.check_event_sender(&event);
-            self.events.insert(OrderedEvent::new(event));
+            self.retry_decryption(&event);
+            self.events.insert(OrderedEvent::new(event, encryption));
         }
 
         let reset = self.messages.is_empty();
 
-        self.messages = make_message_list(&self.events, &self.members, &self.receipts);
+        self.messages =
+            make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
         self.pretty_members = OnceCell::new();
         self.fetching.set(false);
         self.set_fully_read();
@@ -443,6 +554,50 @@ impl Chat {
         }
     }
 
+    pub fn room_keys_event(&mut self, room_id: &RoomId, sessions: &[String]) {
+        if room_id != self.room.room_id() {
+            return;
+        }
+
+        let waiting: Vec<OwnedEventId> = self
+            .events
+            .iter()
+            .filter(|e| {
+                Message::undecryptable_session(e).map_or(false, |s| sessions.iter().any(|k| k == s))
+            })
+            .map(|e| e.event_id().to_owned())
+            .collect();
+
+        for event_id in waiting {
+            self.matrix.decrypt_event(self.room(), event_id);
+        }
+    }
+
+    pub fn decrypted_event(&mut self, event: AnyTimelineEvent, encryption: Option<EncryptionInfo>) {
+        if event.room_id() != self.room.room_id() {
+            return;
+        }
+
+        // the placeholder has the same ID, so this swaps it out
+        self.events.replace(OrderedEvent::new(event, encryption));
+        self.messages =
+            make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
+    }
+
+    // The first time we see an event we can't decrypt, try again; the keys
+    // might have shown up in the meantime, and if they haven't, the attempt
+    // asks our other sessions for them.
+    fn retry_decryption(&mut self, event: &AnyTimelineEvent) {
+        if Message::undecryptable_session(event).is_none() {
+            return;
+        }
+
+        if self.retried.insert(event.event_id().to_owned()) {
+            self.matrix
+                .decrypt_event(self.room(), event.event_id().to_owned());
+        }
+    }
+
     fn check_event_sender(&mut self, event: &AnyTimelineEvent) {
         self.check_sender(&event.sender().to_owned());
     }
@@ -555,7 +710,8 @@ impl Chat {
         self.in_flight.retain(|id| id != member.user_id());
         self.members.push(member);
         self.pretty_members = OnceCell::new();
-        self.messages = make_message_list(&self.events, &self.members, &self.receipts);
+        self.messages =
+            make_message_list(&self.events, &self.outbox, &self.members, &self.receipts);
     }
 
     fn try_fetch_previous(&self) {
@@ -671,6 +827,11 @@ impl Chat {
         false
     }
 
+    // the account we're looking at this room with
+    fn me(&self) -> OwnedUserId {
+        self.room.account().to_owned()
+    }
+
     // the reactions on the currently selected message
     fn selected_reactions(&self) -> Vec<Reaction> {
         match self.selected_reply() {
@@ -681,7 +842,7 @@ impl Chat {
 
     // the reactions belonging to the current user on the selected message
     fn my_selected_reactions(&self) -> Vec<Reaction> {
-        let me = self.matrix.me();
+        let me = self.me();
         let mut ret = self.selected_reactions();
 
         ret.retain(|r| {
@@ -698,7 +859,7 @@ impl Chat {
 
     // the exact reaction event on the selected message
     fn my_selected_reaction_event(&self, body: String) -> Option<ReactionEvent> {
-        let me = self.matrix.me();
+        let me = self.me();
 
         for reaction in self.selected_reactions() {
             if reaction.body != body {
@@ -719,11 +880,12 @@ impl Chat {
 // a good PR would be to add Ord to AnyTimelineEvent
 pub struct OrderedEvent {
     inner: AnyTimelineEvent,
+    encryption: Option<EncryptionInfo>,
 }
 
 impl OrderedEvent {
-    pub fn new(inner: AnyTimelineEvent) -> OrderedEvent {
-        OrderedEvent { inner }
+    pub fn new(inner: AnyTimelineEvent, encryption: Option<EncryptionInfo>) -> OrderedEvent {
+        OrderedEvent { inner, encryption }
     }
 }
 
@@ -813,10 +975,19 @@ impl Widget for ChatWidget<'_> {
             .constraints([Constraint::Percentage(100)].as_ref())
             .split(splits[0])[0];
 
-        let (p_content, p_color) = if self.chat.typing.is_some() {
-            (self.chat.typing.as_ref().unwrap().as_str(), Color::Yellow)
-        } else {
-            (self.chat.pretty_members(), Color::Magenta)
+        // losing the connection trumps everything else
+        let (p_content, p_color) = match (&self.chat.connection, &self.chat.typing) {
+            (Connection::Reconnecting(err), _) => {
+                (Cow::from(format!("Reconnecting: {}", err)), Color::Yellow)
+            }
+            (Connection::Offline(err), _) => (
+                Cow::from(format!("Offline (Ctrl-r to retry): {}", err)),
+                Color::Red,
+            ),
+            (Connection::Connected, Some(typing)) => (Cow::from(typing.as_str()), Color::Yellow),
+            (Connection::Connected, None) => {
+                (Cow::from(self.chat.pretty_members()), Color::Magenta)
+            }
         };
 
         Paragraph::new(p_content)
@@ -853,6 +1024,7 @@ impl Widget for ChatWidget<'_> {
 
 fn make_message_list(
     timeline: &BTreeSet<OrderedEvent>,
+    outbox: &[OutboxItem],
     members: &Vec<RoomMember>,
     receipts: &Receipts,
 ) -> Vec<Message> {
@@ -876,6 +1048,19 @@ fn make_message_list(
     // apply our read receipts
     Message::apply_receipts(&mut messages, &mut receipts.get_all());
 
+    // and note how each one reached us
+    let encryption: HashMap<&EventId, &EncryptionInfo> = timeline
+        .iter()
+        .filter_map(|e| e.encryption.as_ref().map(|info| (e.event_id(), info)))
+        .collect();
+
+    messages
+        .iter_mut()
+        .for_each(|m| m.update_encryption(&encryption));
+
+    // and whatever's still on its way, after everything else
+    messages.extend(outbox.iter().filter_map(Message::try_from_outgoing));
+
     // update senders to friendly names
     messages.iter_mut().for_each(|m| m.update_senders(members));
 
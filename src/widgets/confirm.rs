@@ -1,13 +1,15 @@
 use crossterm::event::{KeyCode, KeyEvent};
 
-use matrix_sdk::room::Joined;
-use ruma::OwnedEventId;
+use matrix_sdk::encryption::verification::{QrVerification, VerificationRequest};
+use matrix_sdk::room::{Invited, Joined};
+use ruma::{OwnedDeviceId, OwnedEventId, OwnedTransactionId, OwnedUserId};
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Style};
 use ratatui::widgets::{Block, BorderType, Borders, Paragraph, Widget};
 
 use crate::widgets::button::Button;
+use crate::widgets::security::enable_backup;
 use crate::widgets::{focus_next, Focusable};
 use crate::{close, consumed};
 
@@ -16,7 +18,20 @@ use super::{get_margin, EventResult};
 #[derive(Clone)]
 pub enum ConfirmBehavior {
     Verification,
+    VerificationRequest(VerificationRequest),
+    QrVerification(QrVerification),
     DeleteMessage(Joined, OwnedEventId),
+    DiscardMessage(OwnedTransactionId),
+    DeleteDevices(OwnedUserId, Vec<OwnedDeviceId>),
+    DeclineInvite(Invited),
+
+    /// Leave the room, and forget it if that's true.
+    LeaveRoom(Joined, bool),
+    Logout(OwnedUserId),
+    VerifyUser(Joined, OwnedUserId),
+
+    /// Replace the key backup, signing the new one if that's true.
+    ResetBackup(bool),
 }
 
 pub struct Confirm {
@@ -89,6 +104,28 @@ impl Confirm {
                     app.close_popup();
                 }
             })),
+            ConfirmBehavior::VerificationRequest(request) if focused => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.accept_verification(request);
+                }))
+            }
+            ConfirmBehavior::VerificationRequest(request) => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.decline_verification(request);
+                }))
+            }
+            ConfirmBehavior::QrVerification(qr) if focused => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.confirm_qr_verification(qr);
+                }))
+            }
+            ConfirmBehavior::QrVerification(qr) => EventResult::Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.mismatched_qr_verification(qr);
+            })),
             ConfirmBehavior::DeleteMessage(room, id) if focused => {
                 EventResult::Consumed(Box::new(|app| {
                     app.matrix.redact_event(room, id);
@@ -96,6 +133,50 @@ impl Confirm {
                 }))
             }
             ConfirmBehavior::DeleteMessage(_, _) => close!(),
+            ConfirmBehavior::DiscardMessage(txn_id) if focused => {
+                EventResult::Consumed(Box::new(move |app| {
+                    app.matrix.discard_outgoing(&txn_id);
+                    app.close_popup();
+                }))
+            }
+            ConfirmBehavior::DiscardMessage(_) => close!(),
+            ConfirmBehavior::DeleteDevices(user_id, devices) if focused => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.delete_devices(user_id, devices, None);
+                }))
+            }
+            ConfirmBehavior::DeleteDevices(_, _) => close!(),
+            ConfirmBehavior::DeclineInvite(room) if focused => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.decline_invite(room);
+                }))
+            }
+            ConfirmBehavior::DeclineInvite(_) => close!(),
+            ConfirmBehavior::LeaveRoom(room, forget) if focused => {
+                EventResult::Consumed(Box::new(move |app| {
+                    app.close_popup();
+                    app.matrix.leave_room(room, forget);
+                }))
+            }
+            ConfirmBehavior::LeaveRoom(_, _) => close!(),
+            ConfirmBehavior::Logout(user_id) if focused => EventResult::Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.logout(user_id);
+            })),
+            ConfirmBehavior::Logout(_) => close!(),
+            ConfirmBehavior::VerifyUser(room, user_id) if focused => {
+                EventResult::Consumed(Box::new(|app| {
+                    app.close_popup();
+                    app.matrix.verify_user(room, user_id);
+                }))
+            }
+            ConfirmBehavior::VerifyUser(_, _) => close!(),
+            ConfirmBehavior::ResetBackup(sign) if focused => {
+                EventResult::Consumed(Box::new(move |app| enable_backup(app, sign, true)))
+            }
+            ConfirmBehavior::ResetBackup(_) => close!(),
         }
     }
 }
@@ -0,0 +1,357 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, BorderType, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
+use ruma::{OwnedDeviceId, OwnedUserId, UserId};
+
+use crate::app::Popup;
+use crate::matrix::matrix::{Matrix, OwnDevice};
+use crate::widgets::confirm::{Confirm, ConfirmBehavior};
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::Consumed;
+use crate::{close, consumed};
+
+use super::{get_margin, EventResult};
+
+/// Our own sessions for the active account.
+pub struct Devices {
+    user_id: Option<OwnedUserId>,
+    devices: Option<Vec<OwnDevice>>,
+    marked: HashSet<OwnedDeviceId>,
+    rename: Option<TextInput>,
+    list_state: Cell<ListState>,
+}
+
+impl Devices {
+    pub fn new(matrix: Matrix) -> Self {
+        let user_id = matrix.active_account();
+
+        if user_id.is_some() {
+            matrix.fetch_devices();
+        }
+
+        let mut list_state = ListState::default();
+        list_state.select(Some(0));
+
+        Self {
+            user_id,
+            devices: None,
+            marked: HashSet::new(),
+            rename: None,
+            list_state: Cell::new(list_state),
+        }
+    }
+
+    pub fn widget(&self) -> DevicesWidget {
+        DevicesWidget { devices: self }
+    }
+
+    pub fn devices_event(&mut self, user_id: &UserId, mut devices: Vec<OwnDevice>) {
+        if self.user_id.as_deref() != Some(user_id) {
+            return;
+        }
+
+        // the one we're using goes first
+        devices.sort_by(|a, b| {
+            b.current
+                .cmp(&a.current)
+                .then(a.device_id.cmp(&b.device_id))
+        });
+
+        // forget marks on sessions that have gone away
+        self.marked
+            .retain(|id| devices.iter().any(|d| &d.device_id == id));
+
+        self.devices = Some(devices);
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if self.rename.is_some() {
+            return self.rename_key_event(input);
+        }
+
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.next();
+                consumed!()
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.previous();
+                consumed!()
+            }
+            KeyCode::Char('v') | KeyCode::Enter => match self.selected() {
+                Some(device) if !device.current && !device.verified => {
+                    let device_id = device.device_id.clone();
+
+                    Consumed(Box::new(|app| {
+                        app.close_popup();
+                        app.matrix.verify_device(device_id);
+                    }))
+                }
+                _ => consumed!(),
+            },
+            KeyCode::Char('r') => {
+                if let Some(device) = self.selected() {
+                    let mut input = TextInput::new("Name".to_string(), true, false);
+                    input.set_value(device.display_name.clone().unwrap_or_default());
+                    self.rename = Some(input);
+                }
+
+                consumed!()
+            }
+            KeyCode::Char(' ') => {
+                match self.selected() {
+                    Some(device) if !device.current => {
+                        let device_id = device.device_id.clone();
+
+                        if !self.marked.remove(&device_id) {
+                            self.marked.insert(device_id);
+                        }
+                    }
+                    _ => {}
+                }
+
+                consumed!()
+            }
+            KeyCode::Char('d') => self.delete(),
+            _ => EventResult::Ignored,
+        }
+    }
+
+    fn rename_key_event(&mut self, input: &KeyEvent) -> EventResult {
+        match input.code {
+            KeyCode::Esc => {
+                self.rename = None;
+                consumed!()
+            }
+            KeyCode::Enter => {
+                let name = self.rename.take().map(|i| i.value()).unwrap_or_default();
+
+                match self.selected() {
+                    Some(device) if !name.trim().is_empty() => {
+                        let device_id = device.device_id.clone();
+                        let name = name.trim().to_string();
+
+                        Consumed(Box::new(|app| app.matrix.rename_device(device_id, name)))
+                    }
+                    _ => consumed!(),
+                }
+            }
+            _ => match &mut self.rename {
+                Some(rename) => rename.key_event(input),
+                None => EventResult::Ignored,
+            },
+        }
+    }
+
+    // delete the marked sessions, or the selected one if nothing is marked
+    fn delete(&self) -> EventResult {
+        let user_id = match &self.user_id {
+            Some(user_id) => user_id.clone(),
+            None => return EventResult::Ignored,
+        };
+
+        let devices: Vec<OwnedDeviceId> = if self.marked.is_empty() {
+            self.selected()
+                .filter(|d| !d.current)
+                .map(|d| vec![d.device_id.clone()])
+                .unwrap_or_default()
+        } else {
+            self.devices
+                .iter()
+                .flatten()
+                .filter(|d| self.marked.contains(&d.device_id))
+                .map(|d| d.device_id.clone())
+                .collect()
+        };
+
+        if devices.is_empty() {
+            return consumed!();
+        }
+
+        let message = match devices.as_slice() {
+            [device_id] => format!("Sign out of session {}?", device_id),
+            _ => format!("Sign out of {} sessions?", devices.len()),
+        };
+
+        let confirm = Confirm::new(
+            "Delete Sessions".to_string(),
+            message,
+            "Delete".to_string(),
+            "Cancel".to_string(),
+            ConfirmBehavior::DeleteDevices(user_id, devices),
+        );
+
+        Consumed(Box::new(|app| app.set_popup(Popup::Confirm(confirm))))
+    }
+
+    fn len(&self) -> usize {
+        self.devices.as_ref().map(Vec::len).unwrap_or_default()
+    }
+
+    fn next(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(i) if i + 1 < self.len() => i + 1,
+            _ => 0,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn previous(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(0) | None => self.len().saturating_sub(1),
+            Some(i) => i - 1,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn selected(&self) -> Option<&OwnDevice> {
+        let state = self.list_state.take();
+        let selected = state.selected().unwrap_or_default();
+        self.list_state.set(state);
+
+        self.devices.as_ref()?.get(selected)
+    }
+}
+
+pub struct DevicesWidget<'a> {
+    pub devices: &'a Devices,
+}
+
+impl Widget for DevicesWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let rename_height = if self.devices.rename.is_some() { 3 } else { 0 };
+        let height = self.devices.len().max(1) as u16 * 2 + rename_height + 7;
+
+        let area = Layout::default()
+            .direction(Direction::Horizontal)
+            .vertical_margin(get_margin(area.height, height))
+            .horizontal_margin(get_margin(area.width, 76))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("Sessions")
+            .title_alignment(Alignment::Center)
+            .style(Style::default().bg(Color::Black))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .vertical_margin(2)
+            .horizontal_margin(3)
+            .constraints(
+                [
+                    Constraint::Min(1),
+                    Constraint::Length(rename_height),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        let devices = match &self.devices.devices {
+            Some(devices) => devices,
+            None => {
+                Paragraph::new("Loading...")
+                    .style(Style::default().fg(Color::DarkGray))
+                    .render(splits[0], buf);
+                return;
+            }
+        };
+
+        let items: Vec<ListItem> = devices
+            .iter()
+            .map(|d| make_list_item(d, self.devices.marked.contains(&d.device_id)))
+            .collect();
+
+        let mut list_state = self.devices.list_state.take();
+        let list = List::new(items).highlight_symbol("> ");
+        StatefulWidget::render(list, splits[0], buf, &mut list_state);
+        self.devices.list_state.set(list_state);
+
+        if let Some(rename) = &self.devices.rename {
+            rename.widget().render(splits[1], buf);
+        }
+
+        let hint = if self.devices.rename.is_some() {
+            "Enter to rename, Esc to cancel"
+        } else {
+            "v verify, r rename, Space mark, d delete"
+        };
+
+        Paragraph::new(hint)
+            .style(Style::default().fg(Color::DarkGray))
+            .alignment(Alignment::Center)
+            .render(splits[2], buf);
+    }
+}
+
+fn make_list_item(device: &OwnDevice, marked: bool) -> ListItem {
+    let mark = if marked { "[x] " } else { "" };
+
+    let name = device
+        .display_name
+        .clone()
+        .unwrap_or_else(|| device.device_id.to_string());
+
+    let (status, color) = if device.current {
+        ("this session", Color::Green)
+    } else if device.verified {
+        ("verified", Color::Green)
+    } else {
+        ("unverified", Color::Yellow)
+    };
+
+    let title = Line::from(vec![
+        Span::styled(mark, Style::default().fg(Color::Red)),
+        Span::from(name),
+        Span::styled(format!(" ({})", status), Style::default().fg(color)),
+    ]);
+
+    let ip = device.last_seen_ip.as_deref().unwrap_or("unknown IP");
+
+    let seen = match device.last_seen {
+        Some(ts) => format!("last seen {}", pretty_elapsed(ts.as_secs().into())),
+        None => "never seen".to_string(),
+    };
+
+    let details = Line::from(Span::styled(
+        format!("  {}, {}, {}", device.device_id, ip, seen),
+        Style::default().fg(Color::DarkGray),
+    ));
+
+    ListItem::new(vec![title, details])
+}
+
+fn pretty_elapsed(then: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // clocks aren't always in agreement with the server's
+    timeago::Formatter::new().convert(Duration::from_secs(now.saturating_sub(then)))
+}
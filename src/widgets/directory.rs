@@ -0,0 +1,330 @@
+use std::cell::Cell;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use matrix_sdk::ruma::directory::PublicRoomsChunk;
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{
+    Block, BorderType, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
+
+use crate::matrix::directory::{DirectoryPage, DirectoryQuery};
+use crate::matrix::matrix::Matrix;
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::{Consumed, Ignored};
+use crate::widgets::{focus_next, focus_prev, get_margin, EventResult, Focusable};
+use crate::{close, consumed};
+
+/// Browse the public rooms on our homeserver, or any other.
+pub struct Directory {
+    search: TextInput,
+    server: TextInput,
+    query: DirectoryQuery,
+    loading: bool,
+    rooms: Vec<PublicRoomsChunk>,
+    next_batch: Option<String>,
+    total: Option<u64>,
+    error: Option<String>,
+    list_state: Cell<ListState>,
+}
+
+impl Directory {
+    pub fn new(matrix: Matrix) -> Self {
+        let query = DirectoryQuery::default();
+        matrix.public_rooms(query.clone(), None);
+
+        Self {
+            search: TextInput::new("Search".to_string(), true, false),
+            server: TextInput::new("Server (optional)".to_string(), false, false),
+            query,
+            loading: true,
+            rooms: vec![],
+            next_batch: None,
+            total: None,
+            error: None,
+            list_state: Cell::new(ListState::default()),
+        }
+    }
+
+    pub fn widget(&self) -> DirectoryWidget {
+        DirectoryWidget { directory: self }
+    }
+
+    pub fn directory_event(
+        &mut self,
+        query: DirectoryQuery,
+        appended: bool,
+        page: Result<DirectoryPage, String>,
+    ) {
+        // the user might have moved on to another search
+        if query != self.query {
+            return;
+        }
+
+        self.loading = false;
+
+        let page = match page {
+            Ok(page) => page,
+            Err(err) => {
+                self.error = Some(err);
+                return;
+            }
+        };
+
+        if appended {
+            self.rooms.extend(page.rooms);
+        } else {
+            let mut state = ListState::default();
+            state.select(if page.rooms.is_empty() { None } else { Some(0) });
+
+            self.rooms = page.rooms;
+            self.list_state.set(state);
+        }
+
+        self.next_batch = page.next_batch;
+        self.total = page.total;
+    }
+
+    fn focus_order(&mut self) -> Vec<Box<dyn Focusable + '_>> {
+        vec![Box::new(&mut self.search), Box::new(&mut self.server)]
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if let Consumed(_) = self.search.key_event(input) {
+            return consumed!();
+        }
+
+        if let Consumed(_) = self.server.key_event(input) {
+            return consumed!();
+        }
+
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Tab => focus_next(self.focus_order()),
+            KeyCode::BackTab => focus_prev(self.focus_order()),
+            KeyCode::Down => self.next(),
+            KeyCode::Up => {
+                self.previous();
+                consumed!()
+            }
+            KeyCode::Enter => self.submit(),
+            _ => Ignored,
+        }
+    }
+
+    // a new search if anything changed, otherwise join what's selected
+    fn submit(&mut self) -> EventResult {
+        let query = match DirectoryQuery::parse(&self.server.value(), &self.search.value()) {
+            Ok(query) => query,
+            Err(err) => {
+                self.error = Some(err.to_string());
+                return consumed!();
+            }
+        };
+
+        if query != self.query {
+            self.query = query.clone();
+            self.loading = true;
+            self.rooms = vec![];
+            self.next_batch = None;
+            self.total = None;
+            self.error = None;
+            self.list_state.set(ListState::default());
+
+            return Consumed(Box::new(move |app| app.matrix.public_rooms(query, None)));
+        }
+
+        let room_id = match self.selected() {
+            Some(room) => room.room_id.clone(),
+            None => return consumed!(),
+        };
+
+        // another server's rooms are best joined through that server
+        let via = self.query.server.clone().into_iter().collect();
+
+        Consumed(Box::new(move |app| {
+            app.close_popup();
+            app.matrix.join_room(room_id.into(), via);
+        }))
+    }
+
+    // moving past the last room loads the next page, if there is one
+    fn next(&mut self) -> EventResult {
+        let mut state = self.list_state.take();
+
+        let (i, more) = match state.selected() {
+            Some(i) if i + 1 < self.rooms.len() => (i + 1, false),
+            Some(i) => (i, true),
+            None if self.rooms.is_empty() => {
+                self.list_state.set(state);
+                return consumed!();
+            }
+            None => (0, false),
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+
+        let since = match &self.next_batch {
+            Some(since) if more && !self.loading => since.clone(),
+            _ => return consumed!(),
+        };
+
+        self.loading = true;
+        let query = self.query.clone();
+
+        Consumed(Box::new(move |app| {
+            app.matrix.public_rooms(query, Some(since))
+        }))
+    }
+
+    fn previous(&mut self) {
+        let mut state = self.list_state.take();
+
+        if let Some(i) = state.selected() {
+            state.select(Some(i.saturating_sub(1)));
+        }
+
+        self.list_state.set(state);
+    }
+
+    fn selected(&self) -> Option<&PublicRoomsChunk> {
+        let state = self.list_state.take();
+        let selected = state.selected();
+        self.list_state.set(state);
+
+        selected.and_then(|i| self.rooms.get(i))
+    }
+
+    fn status(&self) -> String {
+        if self.loading {
+            return "Loading...".to_string();
+        }
+
+        let count = match self.total {
+            Some(total) if self.next_batch.is_some() => {
+                format!("{} of about {} rooms", self.rooms.len(), total)
+            }
+            _ => format!("{} rooms", self.rooms.len()),
+        };
+
+        format!("{}. Enter to join, Tab to change server.", count)
+    }
+}
+
+pub struct DirectoryWidget<'a> {
+    pub directory: &'a Directory,
+}
+
+impl Widget for DirectoryWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = Layout::default()
+            .direction(Direction::Horizontal)
+            .vertical_margin(2)
+            .horizontal_margin(get_margin(area.width, 70))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let title = match &self.directory.query.server {
+            Some(server) => format!("Room Directory ({})", server),
+            None => "Room Directory".to_string(),
+        };
+
+        let block = Block::default()
+            .title(title)
+            .title_alignment(Alignment::Center)
+            .style(Style::default().bg(Color::Black))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .vertical_margin(2)
+            .horizontal_margin(2)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(3),
+                    Constraint::Percentage(100),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        self.directory.search.widget().render(splits[0], buf);
+        self.directory.server.widget().render(splits[1], buf);
+
+        let width = splits[2].width.saturating_sub(4).max(1) as usize;
+
+        let items: Vec<ListItem> = self
+            .directory
+            .rooms
+            .iter()
+            .map(|r| make_list_item(r, width))
+            .collect();
+
+        let list_area = Layout::default()
+            .horizontal_margin(1)
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(splits[2])[0];
+
+        let mut list_state = self.directory.list_state.take();
+        let list = List::new(items).highlight_symbol("> ");
+        StatefulWidget::render(list, list_area, buf, &mut list_state);
+        self.directory.list_state.set(list_state);
+
+        let status = match &self.directory.error {
+            Some(error) => Paragraph::new(error.clone()).style(Style::default().fg(Color::Red)),
+            None => {
+                Paragraph::new(self.directory.status()).style(Style::default().fg(Color::DarkGray))
+            }
+        };
+
+        status.alignment(Alignment::Center).render(splits[3], buf);
+    }
+}
+
+fn make_list_item(room: &PublicRoomsChunk, width: usize) -> ListItem {
+    let name = room
+        .name
+        .clone()
+        .or_else(|| room.canonical_alias.as_ref().map(|a| a.to_string()))
+        .unwrap_or_else(|| room.room_id.to_string());
+
+    let members = u64::from(room.num_joined_members);
+
+    let spans = vec![
+        Span::from(name),
+        Span::styled(
+            format!(
+                " ({} {})",
+                members,
+                if members == 1 { "member" } else { "members" }
+            ),
+            Style::default().fg(Color::DarkGray),
+        ),
+    ];
+
+    let mut lines = Text::from(Line::from(spans));
+
+    // topics can go on, so we keep to one line
+    let mut topic = room.topic.clone().unwrap_or_default().replace('\n', " ");
+
+    if topic.chars().count() > width {
+        topic = topic.chars().take(width - 1).chain(['…']).collect();
+    }
+
+    lines.extend(Text::from(Line::from(vec![Span::styled(
+        topic,
+        Style::default().fg(Color::DarkGray),
+    )])));
+
+    ListItem::new(lines)
+}
@@ -32,7 +32,7 @@ impl Widget for HelpWidget {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let area = Layout::default()
             .direction(Direction::Horizontal)
-            .vertical_margin(get_margin(area.height, 20))
+            .vertical_margin(get_margin(area.height, 31))
             .horizontal_margin(get_margin(area.width, 70))
             .constraints([Constraint::Percentage(100)].as_ref())
             .split(area)[0];
@@ -62,18 +62,29 @@ impl Widget for HelpWidget {
             .split(splits[0])[0];
 
         Table::new(vec![
-              Row::new(vec!["Space", "Show the room switcher"]),
+              Row::new(vec!["Space", "Show the room switcher (and any invites)."]),
+              Row::new(vec!["a", "Show the account switcher (l logs out of an account)."]),
+              Row::new(vec!["S", "Show security settings (cross-signing, key backup)."]),
+              Row::new(vec!["D", "Show your sessions (verify, rename, delete)."]),
+              Row::new(vec!["J", "Join a room by alias, ID or link."]),
+              Row::new(vec!["B", "Browse the public rooms on any server."]),
+              Row::new(vec!["N", "Create a new room."]),
+              Row::new(vec!["M", "Start a direct message."]),
+              Row::new(vec!["L", "Leave the current room (Ctrl-l in the room switcher)."]),
+              Row::new(vec!["F", "Leave and forget the current room (Ctrl-f in the switcher)."]),
               Row::new(vec!["j*", "Select one line down."]),
               Row::new(vec!["k*", "Select one line up."]),
               Row::new(vec!["i", "Create a new message using the external editor."]),
-              Row::new(vec!["Enter", "Open the selected message (images, videos, urls, etc)."]),
+              Row::new(vec!["Enter", "Open the selected message, or send it again if it failed."]),
               Row::new(vec!["s", "Save the selected message (images and videos)."]),
               Row::new(vec!["c", "Edit the selected message in the external editor."]),
               Row::new(vec!["r", "React to the selected message."]),
               Row::new(vec!["R", "Reply to the selected message."]),
+              Row::new(vec!["t", "Verify the sender of the selected message."]),
               Row::new(vec!["v", "View the selected message in the external editor."]),
               Row::new(vec!["V", "View the current room in the external editor."]),
               Row::new(vec!["u", "Upload a file."]),
+              Row::new(vec!["Ctrl-r", "Reconnect now, if the connection dropped."]),
               Row::new(vec!["?", "Show this helper."]),
               Row::new(vec!["", "* arrow keys are fine too."]),
           ])
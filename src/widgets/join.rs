@@ -0,0 +1,115 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, BorderType, Borders, Paragraph, Widget, Wrap};
+
+use crate::matrix::join::parse_room;
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::{Consumed, Ignored};
+use crate::widgets::{get_margin, EventResult};
+use crate::{close, consumed};
+
+/// Join a room by alias, ID or link.
+pub struct Join {
+    room: TextInput,
+    error: Option<String>,
+}
+
+impl Default for Join {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Join {
+    pub fn new() -> Self {
+        Self {
+            room: TextInput::new("Room".to_string(), true, false),
+            error: None,
+        }
+    }
+
+    pub fn widget(&self) -> JoinWidget {
+        JoinWidget { join: self }
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if let Consumed(_) = self.room.key_event(input) {
+            self.error = None;
+            return consumed!();
+        }
+
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Enter => self.submit(),
+            _ => Ignored,
+        }
+    }
+
+    fn submit(&mut self) -> EventResult {
+        let (room, via) = match parse_room(&self.room.value()) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                self.error = Some(err.to_string());
+                return consumed!();
+            }
+        };
+
+        Consumed(Box::new(|app| {
+            app.close_popup();
+            app.matrix.join_room(room, via);
+        }))
+    }
+}
+
+pub struct JoinWidget<'a> {
+    pub join: &'a Join,
+}
+
+impl Widget for JoinWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = Layout::default()
+            .horizontal_margin(get_margin(area.width, 60))
+            .vertical_margin(get_margin(area.height, 14))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("Join Room")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .style(Style::default().bg(Color::Black));
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .horizontal_margin(4)
+            .vertical_margin(2)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        Paragraph::new("An alias (#room:server), a room ID (!id:server) or a matrix.to link.")
+            .wrap(Wrap { trim: true })
+            .render(splits[0], buf);
+
+        self.join.room.widget().render(splits[1], buf);
+
+        if let Some(error) = &self.join.error {
+            Paragraph::new(error.clone())
+                .style(Style::default().fg(Color::Red))
+                .render(splits[2], buf);
+        }
+    }
+}
@@ -1,20 +1,23 @@
 use crate::widgets::message::MessageType::File;
 use chrono::TimeZone;
 use std::cell::Cell;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap};
 use std::time::{Duration, SystemTime};
 
 use crate::matrix::matrix::{pad_emoji, AfterDownload, Matrix};
+use crate::matrix::outbox::{OutboxItem, OutboxState, Outgoing};
 use crate::matrix::username::Username;
 use crate::spawn::view_text;
 use crate::{limit_list, pretty_list};
 use chrono::offset::Local;
-use matrix_sdk::room::RoomMember;
+use matrix_sdk::deserialized_responses::{EncryptionInfo, VerificationState};
+use matrix_sdk::room::{Joined, RoomMember};
 use once_cell::unsync::OnceCell;
 use ratatui::style::{Color, Style};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::ListItem;
 use ruma::events::relation::{InReplyTo, Replacement};
+use ruma::events::room::encrypted::EncryptedEventScheme;
 use ruma::events::room::message::MessageType::{self, Image, Text, Video};
 use ruma::events::room::message::{
     FileMessageEventContent, ImageMessageEventContent, Relation, TextMessageEventContent,
@@ -22,12 +25,13 @@ use ruma::events::room::message::{
 };
 use ruma::events::room::redaction::RoomRedactionEvent;
 use ruma::events::AnyMessageLikeEvent::Reaction as Rctn;
+use ruma::events::AnyMessageLikeEvent::RoomEncrypted;
 use ruma::events::AnyMessageLikeEvent::RoomMessage;
 use ruma::events::AnyMessageLikeEvent::RoomRedaction;
 use ruma::events::AnyTimelineEvent;
 use ruma::events::AnyTimelineEvent::MessageLike;
 use ruma::events::MessageLikeEvent;
-use ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId};
+use ruma::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId};
 
 use super::receipts::Receipt;
 
@@ -41,11 +45,24 @@ pub struct Message {
     pub sent: MilliSecondsSinceUnixEpoch,
     pub body: MessageType,
     pub history: Vec<MessageType>,
+
+    // the latest edit, which is where the body came from
+    pub edited_by: Option<OwnedEventId>,
+
     pub sender: Username,
     pub reactions: Vec<Reaction>,
     pub replies: Vec<Message>,
     pub receipts: Vec<Username>,
 
+    // None if it wasn't encrypted
+    pub encryption: Option<EncryptionInfo>,
+
+    // the room key we'd need to decrypt it, if we couldn't
+    pub missing_session: Option<String>,
+
+    // set while it's still in our outbox
+    pub outgoing: Option<OutboxItem>,
+
     last_height: Cell<LastHeight>,
 }
 
@@ -97,6 +114,9 @@ impl Message {
             self.sender.id
         );
 
+        ret.push_str(&self.display_encryption());
+        ret.push_str("\n\n");
+
         ret.push_str(self.display());
         ret.push_str("\n\n");
 
@@ -130,6 +150,66 @@ impl Message {
         ret
     }
 
+    fn display_encryption(&self) -> String {
+        if let Some(OutboxState::Failed(err)) = self.outgoing.as_ref().map(|o| &o.state) {
+            return format!("Not sent yet: {}", err);
+        }
+
+        if self.outgoing.is_some() {
+            return "Not sent yet.".to_string();
+        }
+
+        if let Some(session_id) = &self.missing_session {
+            return format!(
+                "Encrypted, but we don't have the key yet (session {}).",
+                session_id
+            );
+        }
+
+        let info = match &self.encryption {
+            Some(info) => info,
+            None => return "Not encrypted.".to_string(),
+        };
+
+        let device = info
+            .sender_device
+            .as_ref()
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let trust = match info.verification_state {
+            VerificationState::Trusted => "a verified session",
+            VerificationState::Untrusted => "an unverified session",
+            VerificationState::UnknownDevice => "a session we don't know about",
+        };
+
+        format!("Encrypted, from {} ({}).", trust, device)
+    }
+
+    // a marker for the author line, if there's something to say about trust
+    fn trust_span(&self) -> Option<Span> {
+        let (text, color) = match &self.encryption.as_ref()?.verification_state {
+            VerificationState::Trusted => (" 🔒", Color::Green),
+            VerificationState::Untrusted => (" ⚠ unverified", Color::Yellow),
+            VerificationState::UnknownDevice => (" ⚠ unknown session", Color::Red),
+        };
+
+        Some(Span::styled(text, Style::default().fg(color)))
+    }
+
+    // how sending is going, for messages that haven't gone out yet
+    fn outgoing_span(&self) -> Option<Span> {
+        let item = self.outgoing.as_ref()?;
+
+        let (text, color) = match &item.state {
+            OutboxState::Failed(_) => (" failed (Enter to retry, dd to discard)", Color::Red),
+            OutboxState::Sending if item.attempts > 0 => (" waiting to retry", Color::Yellow),
+            OutboxState::Sending => (" sending", Color::DarkGray),
+        };
+
+        Some(Span::styled(text, Style::default().fg(color)))
+    }
+
     pub fn pretty_elapsed(&self) -> String {
         let formatter = timeago::Formatter::new();
 
@@ -143,38 +223,86 @@ impl Message {
     }
 
     pub fn style(&self) -> Style {
+        if self.missing_session.is_some() || self.outgoing.is_some() {
+            return Style::default().fg(Color::DarkGray);
+        }
+
         match &self.body {
             Text(_) => Style::default(),
             _ => Style::default().fg(Color::Blue),
         }
     }
 
-    pub fn open(&self, matrix: Matrix) {
+    pub fn open(&self, matrix: Matrix, room: Joined) {
         match &self.body {
-            Image(_) => matrix.download_content(self.body.clone(), AfterDownload::View),
-            Video(_) => matrix.download_content(self.body.clone(), AfterDownload::View),
-            File(_) => matrix.download_content(self.body.clone(), AfterDownload::Save),
+            Image(_) => matrix.download_content(room, self.body.clone(), AfterDownload::View),
+            Video(_) => matrix.download_content(room, self.body.clone(), AfterDownload::View),
+            File(_) => matrix.download_content(room, self.body.clone(), AfterDownload::Save),
             Text(_) => view_text(self.display()),
             _ => {}
         }
     }
 
-    pub fn save(&self, matrix: Matrix) {
+    pub fn save(&self, matrix: Matrix, room: Joined) {
         match &self.body {
-            Image(_) => matrix.download_content(self.body.clone(), AfterDownload::Save),
-            Video(_) => matrix.download_content(self.body.clone(), AfterDownload::Save),
-            File(_) => matrix.download_content(self.body.clone(), AfterDownload::Save),
+            Image(_) => matrix.download_content(room, self.body.clone(), AfterDownload::Save),
+            Video(_) => matrix.download_content(room, self.body.clone(), AfterDownload::Save),
+            File(_) => matrix.download_content(room, self.body.clone(), AfterDownload::Save),
             _ => {}
         }
     }
 
-    pub fn edit(&mut self, new_body: MessageType) {
+    pub fn edit(&mut self, new_body: MessageType, edited_by: OwnedEventId) {
         let old = std::mem::replace(&mut self.body, new_body);
         self.history.push(old);
+        self.edited_by = Some(edited_by);
+    }
+
+    /// The session ID of an event we couldn't decrypt.
+    pub fn undecryptable_session(event: &AnyTimelineEvent) -> Option<&str> {
+        match event {
+            MessageLike(RoomEncrypted(MessageLikeEvent::Original(c))) => match &c.content.scheme {
+                EncryptedEventScheme::MegolmV1AesSha2(megolm) => Some(&megolm.session_id),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // a placeholder, until the keys show up
+    fn try_from_undecryptable(event: &AnyTimelineEvent) -> Option<Self> {
+        let session_id = Message::undecryptable_session(event)?;
+
+        let body = Text(TextMessageEventContent::plain(format!(
+            "Unable to decrypt this message yet (session {}).",
+            session_id
+        )));
+
+        Some(Message {
+            id: event.event_id().to_owned(),
+            in_reply_to: None,
+            room_id: event.room_id().to_owned(),
+            sent: event.origin_server_ts(),
+            body,
+            history: vec![],
+            edited_by: None,
+            sender: Username::new(event.sender().to_owned()),
+            reactions: Vec::new(),
+            replies: Vec::new(),
+            receipts: Vec::new(),
+            encryption: None,
+            missing_session: Some(session_id.to_string()),
+            outgoing: None,
+            last_height: Cell::new(LastHeight::default()),
+        })
     }
 
     // can we make a brand-new message, just from this event?
     pub fn try_from(event: &AnyTimelineEvent, force: bool) -> Option<Self> {
+        if let Some(message) = Message::try_from_undecryptable(event) {
+            return Some(message);
+        }
+
         if let MessageLike(RoomMessage(MessageLikeEvent::Original(c))) = event {
             let c = c.clone();
 
@@ -209,10 +337,14 @@ impl Message {
                 sent: c.origin_server_ts,
                 body,
                 history: vec![],
+                edited_by: None,
                 sender: Username::new(c.sender),
                 reactions: Vec::new(),
                 replies: Vec::new(),
                 receipts: Vec::new(),
+                encryption: None,
+                missing_session: None,
+                outgoing: None,
                 last_height: Cell::new(LastHeight::default()),
             });
         }
@@ -220,6 +352,39 @@ impl Message {
         None
     }
 
+    /// A stand-in for something we're still sending, until the real event
+    /// comes back to us.
+    pub fn try_from_outgoing(item: &OutboxItem) -> Option<Self> {
+        let body = match &item.outgoing {
+            Outgoing::Text(message) | Outgoing::Reply(message, _) => message.clone(),
+            Outgoing::Replacement(_, message, _) => message.clone(),
+            Outgoing::Reaction(_, key) => format!("Reacting with {}", pad_emoji(key)),
+        };
+
+        let in_reply_to = match &item.outgoing {
+            Outgoing::Reply(_, id) => Some(id.clone()),
+            _ => None,
+        };
+
+        Some(Message {
+            id: OwnedEventId::try_from(format!("${}", item.txn_id)).ok()?,
+            in_reply_to,
+            room_id: item.room_id.clone(),
+            sent: item.queued,
+            body: Text(TextMessageEventContent::plain(body)),
+            history: vec![],
+            edited_by: None,
+            sender: Username::new(item.user_id.clone()),
+            reactions: Vec::new(),
+            replies: Vec::new(),
+            receipts: Vec::new(),
+            encryption: None,
+            missing_session: None,
+            outgoing: Some(item.clone()),
+            last_height: Cell::new(LastHeight::default()),
+        })
+    }
+
     // if not, we should send the event here, to possibly act on existing
     // events
     pub fn apply_timeline_event(
@@ -241,7 +406,7 @@ impl Message {
             {
                 for message in messages.iter_mut() {
                     if message.id == id {
-                        message.edit(content);
+                        message.edit(content, c.event_id.clone());
                         return MergeResult::Consumed;
                     }
                 }
@@ -389,6 +554,16 @@ impl Message {
         }
     }
 
+    pub fn update_encryption(&mut self, encryption: &HashMap<&EventId, &EncryptionInfo>) {
+        // what we show is the latest edit, so that's what the shield is for
+        let id = self.edited_by.as_deref().unwrap_or(&self.id);
+        self.encryption = encryption.get(id).map(|info| (*info).clone());
+
+        for reply in self.replies.iter_mut() {
+            reply.update_encryption(encryption);
+        }
+    }
+
     // try our best to remove the fomatting that Matrix adds to the top of
     // message reply bodies
     fn remove_reply_header(body: &str) -> &str {
@@ -473,6 +648,14 @@ impl Message {
             Span::styled(self.pretty_elapsed(), Style::default().fg(Color::DarkGray)),
         ];
 
+        if let Some(span) = self.trust_span() {
+            spans.push(span);
+        }
+
+        if let Some(span) = self.outgoing_span() {
+            spans.push(span);
+        }
+
         if !self.history.is_empty() {
             spans.push(Span::styled(" (edited)", Style::default().fg(Color::Red)))
         }
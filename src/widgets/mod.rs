@@ -1,11 +1,20 @@
 use crate::app::App;
 use crate::widgets::EventResult::Ignored;
 
+pub mod accounts;
+pub mod devices;
+pub mod directory;
 pub mod error;
+pub mod password;
 pub mod progress;
+pub mod qrcode;
 pub mod rooms;
+pub mod security;
 pub mod signin;
 pub mod help;
+pub mod join;
+pub mod newdm;
+pub mod newroom;
 
 pub mod button;
 pub mod chat;
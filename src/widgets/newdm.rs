@@ -0,0 +1,266 @@
+use std::cell::Cell;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, BorderType, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
+use ruma::OwnedUserId;
+
+use crate::matrix::create::FoundUser;
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::{Consumed, Ignored};
+use crate::widgets::{get_margin, EventResult};
+use crate::{close, consumed};
+
+/// Find someone in the user directory and chat with them directly.
+pub struct NewDm {
+    search: TextInput,
+    searched: Option<String>,
+    searching: bool,
+    users: Vec<FoundUser>,
+    error: Option<String>,
+    list_state: Cell<ListState>,
+}
+
+impl Default for NewDm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NewDm {
+    pub fn new() -> Self {
+        Self {
+            search: TextInput::new("Search".to_string(), true, false),
+            searched: None,
+            searching: false,
+            users: vec![],
+            error: None,
+            list_state: Cell::new(ListState::default()),
+        }
+    }
+
+    pub fn widget(&self) -> NewDmWidget {
+        NewDmWidget { new_dm: self }
+    }
+
+    pub fn users_event(&mut self, term: String, users: Result<Vec<FoundUser>, String>) {
+        // we only care about the latest search
+        if term != self.term() {
+            return;
+        }
+
+        self.searching = false;
+
+        // leave it unsearched, so Enter tries again
+        let mut users = match users {
+            Ok(users) => users,
+            Err(err) => {
+                self.searched = None;
+                self.users = vec![];
+                self.error = Some(err);
+                self.list_state.set(ListState::default());
+                return;
+            }
+        };
+
+        // directories don't always list everyone, so a full ID is good enough
+        if let Ok(user_id) = OwnedUserId::try_from(term.as_str()) {
+            if !users.iter().any(|u| u.user_id == user_id) {
+                users.insert(
+                    0,
+                    FoundUser {
+                        user_id,
+                        display_name: None,
+                    },
+                );
+            }
+        }
+
+        let mut state = ListState::default();
+        state.select(if users.is_empty() { None } else { Some(0) });
+
+        self.searched = Some(term);
+        self.users = users;
+        self.list_state.set(state);
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if let Consumed(_) = self.search.key_event(input) {
+            return consumed!();
+        }
+
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Down => {
+                self.next();
+                consumed!()
+            }
+            KeyCode::Up => {
+                self.previous();
+                consumed!()
+            }
+            KeyCode::Enter => self.submit(),
+            _ => Ignored,
+        }
+    }
+
+    // search for something new, or open a chat with who we found
+    fn submit(&mut self) -> EventResult {
+        let term = self.term();
+
+        if term.is_empty() {
+            return consumed!();
+        }
+
+        if self.searched.as_ref() != Some(&term) {
+            self.searching = true;
+            self.error = None;
+            return Consumed(Box::new(move |app| app.matrix.search_users(term)));
+        }
+
+        match self.selected() {
+            Some(user) => {
+                let user_id = user.user_id.clone();
+
+                Consumed(Box::new(move |app| {
+                    app.close_popup();
+                    app.matrix.start_dm(user_id);
+                }))
+            }
+            None => consumed!(),
+        }
+    }
+
+    fn term(&self) -> String {
+        self.search.value().trim().to_string()
+    }
+
+    fn next(&mut self) {
+        if self.users.is_empty() {
+            return;
+        }
+
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(i) if i + 1 < self.users.len() => i + 1,
+            _ => 0,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn previous(&mut self) {
+        if self.users.is_empty() {
+            return;
+        }
+
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(0) | None => self.users.len() - 1,
+            Some(i) => i - 1,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn selected(&self) -> Option<&FoundUser> {
+        let state = self.list_state.take();
+        let selected = state.selected();
+        self.list_state.set(state);
+
+        selected.and_then(|i| self.users.get(i))
+    }
+}
+
+pub struct NewDmWidget<'a> {
+    pub new_dm: &'a NewDm,
+}
+
+impl Widget for NewDmWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = Layout::default()
+            .horizontal_margin(get_margin(area.width, 60))
+            .vertical_margin(get_margin(area.height, 22))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("New Direct Message")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .style(Style::default().bg(Color::Black));
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .horizontal_margin(4)
+            .vertical_margin(2)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Min(1),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        self.new_dm.search.widget().render(splits[0], buf);
+
+        if self.new_dm.searching {
+            Paragraph::new("Searching...")
+                .style(Style::default().fg(Color::DarkGray))
+                .render(splits[2], buf);
+        } else if let Some(error) = &self.new_dm.error {
+            Paragraph::new(error.clone())
+                .style(Style::default().fg(Color::Red))
+                .render(splits[2], buf);
+        } else if self.new_dm.searched.is_some() && self.new_dm.users.is_empty() {
+            Paragraph::new("Nobody found. Try their full Matrix ID.")
+                .style(Style::default().fg(Color::DarkGray))
+                .render(splits[2], buf);
+        } else {
+            let items: Vec<ListItem> = self.new_dm.users.iter().map(make_list_item).collect();
+
+            let mut list_state = self.new_dm.list_state.take();
+            let list = List::new(items).highlight_symbol("> ");
+            StatefulWidget::render(list, splits[2], buf, &mut list_state);
+            self.new_dm.list_state.set(list_state);
+        }
+
+        Paragraph::new("Enter to search, then Enter again to chat")
+            .style(Style::default().fg(Color::DarkGray))
+            .alignment(Alignment::Center)
+            .render(splits[3], buf);
+    }
+}
+
+fn make_list_item(user: &FoundUser) -> ListItem {
+    let mut spans = vec![];
+
+    if let Some(name) = &user.display_name {
+        spans.push(Span::from(name.clone()));
+        spans.push(Span::from(" "));
+    }
+
+    spans.push(Span::styled(
+        user.user_id.to_string(),
+        Style::default().fg(Color::DarkGray),
+    ));
+
+    ListItem::new(Line::from(spans))
+}
@@ -0,0 +1,190 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, BorderType, Borders, Paragraph, Widget};
+
+use crate::widgets::button::Button;
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::{Consumed, Ignored};
+use crate::widgets::{focus_next, focus_prev, get_margin, EventResult, Focusable};
+use crate::{close, consumed};
+
+/// Create a new room, encrypted and private unless asked otherwise.
+pub struct NewRoom {
+    name: TextInput,
+    topic: TextInput,
+    encrypted: bool,
+    public: bool,
+    encryption: Button,
+    visibility: Button,
+    create: Button,
+    error: Option<String>,
+}
+
+impl Default for NewRoom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NewRoom {
+    pub fn new() -> Self {
+        Self {
+            name: TextInput::new("Name".to_string(), true, false),
+            topic: TextInput::new("Topic (optional)".to_string(), false, false),
+            encrypted: true,
+            public: false,
+            encryption: Button::new(encryption_label(true), false),
+            visibility: Button::new(visibility_label(false), false),
+            create: Button::new("Create".to_string(), false),
+            error: None,
+        }
+    }
+
+    fn focus_order(&mut self) -> Vec<Box<dyn Focusable + '_>> {
+        vec![
+            Box::new(&mut self.name),
+            Box::new(&mut self.topic),
+            Box::new(&mut self.encryption),
+            Box::new(&mut self.visibility),
+            Box::new(&mut self.create),
+        ]
+    }
+
+    pub fn widget(&self) -> NewRoomWidget {
+        NewRoomWidget { new_room: self }
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if let Consumed(_) = self.name.key_event(input) {
+            self.error = None;
+            return consumed!();
+        }
+
+        if let Consumed(_) = self.topic.key_event(input) {
+            return consumed!();
+        }
+
+        // buttons can't change their labels, so we swap in new ones
+        if let Consumed(_) = self.encryption.key_event(input) {
+            self.encrypted = !self.encrypted;
+            self.encryption = Button::new(encryption_label(self.encrypted), true);
+            return consumed!();
+        }
+
+        if let Consumed(_) = self.visibility.key_event(input) {
+            self.public = !self.public;
+            self.visibility = Button::new(visibility_label(self.public), true);
+            return consumed!();
+        }
+
+        if let Consumed(_) = self.create.key_event(input) {
+            return self.submit();
+        }
+
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Enter | KeyCode::Tab | KeyCode::Down => focus_next(self.focus_order()),
+            KeyCode::BackTab | KeyCode::Up => focus_prev(self.focus_order()),
+            _ => Ignored,
+        }
+    }
+
+    fn submit(&mut self) -> EventResult {
+        let name = self.name.value().trim().to_string();
+
+        if name.is_empty() {
+            self.error = Some("Give the room a name.".to_string());
+            return consumed!();
+        }
+
+        let topic = Some(self.topic.value().trim().to_string()).filter(|t| !t.is_empty());
+        let encrypted = self.encrypted;
+        let public = self.public;
+
+        Consumed(Box::new(move |app| {
+            app.close_popup();
+            app.matrix.create_room(name, topic, encrypted, public);
+        }))
+    }
+}
+
+fn encryption_label(encrypted: bool) -> String {
+    if encrypted {
+        "Encrypted".to_string()
+    } else {
+        "Not Encrypted".to_string()
+    }
+}
+
+fn visibility_label(public: bool) -> String {
+    if public {
+        "Public".to_string()
+    } else {
+        "Private".to_string()
+    }
+}
+
+pub struct NewRoomWidget<'a> {
+    pub new_room: &'a NewRoom,
+}
+
+impl Widget for NewRoomWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = Layout::default()
+            .horizontal_margin(get_margin(area.width, 60))
+            .vertical_margin(get_margin(area.height, 24))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("New Room")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .style(Style::default().bg(Color::Black));
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .horizontal_margin(8)
+            .vertical_margin(3)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        self.new_room.name.widget().render(splits[0], buf);
+        self.new_room.topic.widget().render(splits[2], buf);
+
+        let toggles = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(splits[4]);
+
+        self.new_room.encryption.widget().render(toggles[0], buf);
+        self.new_room.visibility.widget().render(toggles[1], buf);
+        self.new_room.create.widget().render(splits[6], buf);
+
+        if let Some(error) = &self.new_room.error {
+            Paragraph::new(error.clone())
+                .style(Style::default().fg(Color::Red))
+                .render(splits[8], buf);
+        }
+    }
+}
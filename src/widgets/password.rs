@@ -0,0 +1,213 @@
+use std::path::PathBuf;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, BorderType, Borders, Paragraph, Widget, Wrap};
+
+use crate::handler::Reauth;
+use crate::widgets::textinput::TextInput;
+use crate::widgets::EventResult::{Consumed, Ignored};
+use crate::widgets::{focus_next, focus_prev, get_margin, EventResult, Focusable};
+use crate::{close, consumed};
+
+/// What to do with the password once we have it.
+#[derive(Clone)]
+pub enum PasswordBehavior {
+    /// Replace the existing backup, if that's true.
+    EnableBackup(bool),
+    ExportKeys,
+    ImportKeys(PathBuf),
+    Reauth(Reauth, Option<String>),
+    RestoreBackup,
+    UnlockSessions,
+}
+
+pub struct Password {
+    title: String,
+    message: String,
+    password: TextInput,
+    confirm: Option<TextInput>,
+    error: Option<String>,
+    behavior: PasswordBehavior,
+}
+
+impl Password {
+    /// With `confirm`, the password has to be typed twice. That's for new
+    /// passwords, where a typo would be costly.
+    pub fn new(title: String, message: String, confirm: bool, behavior: PasswordBehavior) -> Self {
+        let confirm = if confirm {
+            Some(TextInput::new("Confirm".to_string(), false, true))
+        } else {
+            None
+        };
+
+        Self {
+            title,
+            message,
+            password: TextInput::new("Password".to_string(), true, true),
+            confirm,
+            error: None,
+            behavior,
+        }
+    }
+
+    pub fn widget(&self) -> PasswordWidget {
+        PasswordWidget { password: self }
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        if let Consumed(_) = self.password.key_event(input) {
+            return consumed!();
+        }
+
+        if let Some(Consumed(_)) = self.confirm.as_mut().map(|c| c.key_event(input)) {
+            return consumed!();
+        }
+
+        match input.code {
+            KeyCode::Esc if self.cancelable() => close!(),
+            KeyCode::Enter if self.password.focused && self.confirm.is_some() => {
+                focus_next(self.focus_order())
+            }
+            KeyCode::Enter => self.submit(),
+            KeyCode::Tab | KeyCode::Down => focus_next(self.focus_order()),
+            KeyCode::BackTab | KeyCode::Up => focus_prev(self.focus_order()),
+            _ => Ignored,
+        }
+    }
+
+    fn focus_order(&mut self) -> Vec<Box<dyn Focusable + '_>> {
+        let mut order: Vec<Box<dyn Focusable + '_>> = vec![Box::new(&mut self.password)];
+
+        if let Some(confirm) = self.confirm.as_mut() {
+            order.push(Box::new(confirm));
+        }
+
+        order
+    }
+
+    // there's no going back from some of these
+    fn cancelable(&self) -> bool {
+        match self.behavior {
+            PasswordBehavior::EnableBackup(_) => true,
+            PasswordBehavior::ExportKeys => true,
+            PasswordBehavior::ImportKeys(_) => true,
+            PasswordBehavior::Reauth(..) => true,
+            PasswordBehavior::RestoreBackup => true,
+            PasswordBehavior::UnlockSessions => false,
+        }
+    }
+
+    fn submit(&mut self) -> EventResult {
+        let password = self.password.value();
+
+        if password.is_empty() {
+            self.error = Some("The password can't be empty.".to_string());
+            return consumed!();
+        }
+
+        if let Some(confirm) = &self.confirm {
+            if confirm.value() != password {
+                self.error = Some("The passwords don't match.".to_string());
+                return consumed!();
+            }
+        }
+
+        match self.behavior.clone() {
+            PasswordBehavior::EnableBackup(replace) => Consumed(Box::new(move |app| {
+                app.close_popup();
+                app.matrix.enable_backup(Some(password), replace);
+            })),
+            PasswordBehavior::ExportKeys => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.export_room_keys(password);
+            })),
+            PasswordBehavior::ImportKeys(path) => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.import_room_keys(path, password);
+            })),
+            PasswordBehavior::Reauth(reauth, session) => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.reauth(reauth, session, password);
+            })),
+            PasswordBehavior::RestoreBackup => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.restore_backup(password);
+            })),
+            PasswordBehavior::UnlockSessions => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.unlock(password);
+            })),
+        }
+    }
+}
+
+pub struct PasswordWidget<'a> {
+    pub password: &'a Password,
+}
+
+impl Widget for PasswordWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let height = if self.password.confirm.is_some() {
+            16
+        } else {
+            14
+        };
+
+        let area = Layout::default()
+            .horizontal_margin(get_margin(area.width, 60))
+            .vertical_margin(get_margin(area.height, height))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title(self.password.title.clone())
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .style(Style::default().bg(Color::Black));
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .horizontal_margin(4)
+            .vertical_margin(2)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        Paragraph::new(self.password.message.clone())
+            .wrap(Wrap { trim: true })
+            .render(splits[0], buf);
+
+        self.password.password.widget().render(splits[1], buf);
+
+        // the error goes at the bottom, wherever that is
+        let error_area = match &self.password.confirm {
+            Some(confirm) => {
+                confirm.widget().render(splits[3], buf);
+                splits[4]
+            }
+            None => splits[2],
+        };
+
+        if let Some(error) = &self.password.error {
+            Paragraph::new(error.clone())
+                .style(Style::default().fg(Color::Red))
+                .render(error_area, buf);
+        }
+    }
+}
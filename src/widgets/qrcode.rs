@@ -0,0 +1,150 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use matrix_sdk::encryption::verification::VerificationRequest;
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, BorderType, Borders, Paragraph, Widget, Wrap};
+
+use crate::widgets::EventResult::Consumed;
+
+use super::{get_margin, EventResult};
+
+// scanners want four modules of empty space around the code
+const QUIET_ZONE: usize = 4;
+
+/// A QR code for the other side of a verification to scan.
+pub struct QrCode {
+    request: VerificationRequest,
+    lines: Vec<String>,
+}
+
+impl QrCode {
+    pub fn new(request: VerificationRequest, width: usize, modules: Vec<bool>) -> Self {
+        Self {
+            request,
+            lines: half_blocks(width, &modules),
+        }
+    }
+
+    pub fn widget(&self) -> QrCodeWidget {
+        QrCodeWidget { qr_code: self }
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        let request = self.request.clone();
+
+        match input.code {
+            KeyCode::Char('e') => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.start_sas_verification(request);
+            })),
+            KeyCode::Esc => Consumed(Box::new(|app| {
+                app.close_popup();
+                app.matrix.decline_verification(request);
+            })),
+            _ => EventResult::Ignored,
+        }
+    }
+}
+
+/// Draw the code two rows to a line, with half blocks. The light modules are
+/// the ones we draw, so it reads the same on any terminal background.
+pub fn half_blocks(width: usize, modules: &[bool]) -> Vec<String> {
+    let size = width + QUIET_ZONE * 2;
+
+    let light = |x: usize, y: usize| {
+        if x < QUIET_ZONE || y < QUIET_ZONE || x >= width + QUIET_ZONE || y >= width + QUIET_ZONE {
+            return true;
+        }
+
+        !modules[(y - QUIET_ZONE) * width + (x - QUIET_ZONE)]
+    };
+
+    (0..size)
+        .step_by(2)
+        .map(|y| {
+            (0..size)
+                .map(|x| match (light(x, y), y + 1 < size && light(x, y + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub struct QrCodeWidget<'a> {
+    pub qr_code: &'a QrCode,
+}
+
+impl Widget for QrCodeWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let lines = &self.qr_code.lines;
+        let code_width = lines.first().map(|l| l.chars().count()).unwrap_or_default() as u16;
+        let code_height = lines.len() as u16;
+
+        let area = Layout::default()
+            .direction(Direction::Horizontal)
+            .vertical_margin(get_margin(area.height, code_height + 8))
+            .horizontal_margin(get_margin(area.width, code_width.max(50) + 6))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("Verify")
+            .title_alignment(Alignment::Center)
+            .style(Style::default().bg(Color::Black))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .vertical_margin(2)
+            .horizontal_margin(3)
+            .constraints(
+                [
+                    Constraint::Length(code_height),
+                    Constraint::Length(1),
+                    Constraint::Length(2),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        Paragraph::new(lines.join("\n"))
+            .style(Style::default().fg(Color::White).bg(Color::Black))
+            .alignment(Alignment::Center)
+            .render(splits[0], buf);
+
+        Paragraph::new("Scan this with your other session, or press e to compare emoji instead.")
+            .wrap(Wrap { trim: true })
+            .alignment(Alignment::Center)
+            .render(splits[2], buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::half_blocks;
+
+    #[test]
+    fn test_half_blocks() {
+        // a single dark module, in the middle of the quiet zone
+        assert_eq!(
+            half_blocks(1, &[true]),
+            vec![
+                "█████████".to_string(),
+                "█████████".to_string(),
+                "████▄████".to_string(),
+                "█████████".to_string(),
+                "▀▀▀▀▀▀▀▀▀".to_string(),
+            ]
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use crate::matrix::matrix::Matrix;
-use crate::matrix::roomcache::DecoratedRoom;
+use crate::app::Popup;
+use crate::matrix::roomcache::{DecoratedInvite, DecoratedRoom};
 use crate::{close, consumed};
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use matrix_sdk::room::Joined;
+use ruma::UserId;
 use std::cell::Cell;
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
@@ -10,6 +12,7 @@ use ratatui::style::{Color, Style};
 use ratatui::text::{Line, Span, Text};
 use ratatui::widgets::{Block, BorderType, Borders, List, ListItem, ListState, StatefulWidget, Widget};
 
+use crate::widgets::confirm::{Confirm, ConfirmBehavior};
 use crate::widgets::get_margin;
 use crate::widgets::textinput::TextInput;
 use crate::widgets::EventResult::Consumed;
@@ -18,8 +21,10 @@ use super::EventResult;
 
 pub struct Rooms {
     pub textinput: TextInput,
+    pub invites: Vec<DecoratedInvite>,
     pub joined: Vec<DecoratedRoom>,
     pub list_state: Cell<ListState>,
+    show_account: bool,
 }
 
 impl Rooms {
@@ -29,7 +34,7 @@ impl Rooms {
 
         // if the current room is at the top, put it at the bottom
         if let Some(current) = current {
-            if rooms.len() > 1 && rooms.first().unwrap().inner.room_id() == current.room_id() {
+            if rooms.len() > 1 && rooms.first().unwrap().is(&current) {
                 let first = rooms.remove(0);
                 rooms.push(first);
             }
@@ -37,8 +42,10 @@ impl Rooms {
 
         let mut ret = Self {
             textinput: TextInput::new("Search".to_string(), true, false),
+            invites: matrix.fetch_invites(),
             joined: rooms,
             list_state: Cell::new(ListState::default()),
+            show_account: matrix.accounts().len() > 1,
         };
 
         ret.reset();
@@ -60,16 +67,47 @@ impl Rooms {
                 self.previous();
                 consumed!()
             }
-            KeyCode::Enter => {
-                if let Some(selected_room) = self.selected_room() {
+            KeyCode::Enter => match self.selected() {
+                Some(Selected::Invite(invite)) => {
+                    let room = invite.inner();
+                    Consumed(Box::new(|app| {
+                        app.close_popup();
+                        app.matrix.accept_invite(room);
+                    }))
+                }
+                Some(Selected::Room(selected_room)) => {
                     let room = selected_room.inner();
                     Consumed(Box::new(|app| {
                         app.select_room(room);
                         app.close_popup();
                     }))
-                } else {
-                    EventResult::Ignored
                 }
+                None => EventResult::Ignored,
+            },
+            KeyCode::Char('d') if input.modifiers == KeyModifiers::CONTROL => {
+                let invite = match self.selected() {
+                    Some(Selected::Invite(invite)) => invite,
+                    _ => return EventResult::Ignored,
+                };
+
+                let confirm = Confirm::new(
+                    "Decline Invite".to_string(),
+                    format!("Are you sure you want to decline the invite to {}?", invite.name),
+                    "Yes".to_string(),
+                    "No".to_string(),
+                    ConfirmBehavior::DeclineInvite(invite.inner()),
+                );
+
+                Consumed(Box::new(|app| app.set_popup(Popup::Confirm(confirm))))
+            }
+            KeyCode::Char(c @ ('l' | 'f')) if input.modifiers == KeyModifiers::CONTROL => {
+                let room = match self.selected() {
+                    Some(Selected::Room(room)) => room,
+                    _ => return EventResult::Ignored,
+                };
+
+                let confirm = leave_confirm(&room, c == 'f');
+                Consumed(Box::new(|app| app.set_popup(Popup::Confirm(confirm))))
             }
             _ => {
                 if let Consumed(_) = self.textinput.key_event(input) {
@@ -87,7 +125,7 @@ impl Rooms {
 
         let i = match state.selected() {
             Some(i) => {
-                if i >= self.filtered_rooms().len() - 1 {
+                if i >= self.filtered_len() - 1 {
                     0
                 } else {
                     i + 1
@@ -106,7 +144,7 @@ impl Rooms {
         let i = match state.selected() {
             Some(i) => {
                 if i == 0 {
-                    self.filtered_rooms().len() - 1
+                    self.filtered_len() - 1
                 } else {
                     i - 1
                 }
@@ -124,6 +162,15 @@ impl Rooms {
         self.list_state.set(state);
     }
 
+    fn filtered_invites(&self) -> Vec<&DecoratedInvite> {
+        let pattern = self.textinput.value.to_lowercase();
+
+        self.invites
+            .iter()
+            .filter(|i| i.name.to_string().to_lowercase().contains(pattern.as_str()))
+            .collect()
+    }
+
     fn filtered_rooms(&self) -> Vec<&DecoratedRoom> {
         let pattern = self.textinput.value.to_lowercase();
 
@@ -133,20 +180,34 @@ impl Rooms {
             .collect()
     }
 
-    fn selected_room(&self) -> Option<DecoratedRoom> {
+    // invites go at the top
+    fn filtered_len(&self) -> usize {
+        self.filtered_invites().len() + self.filtered_rooms().len()
+    }
+
+    fn selected(&self) -> Option<Selected> {
+        let filtered_invites = self.filtered_invites();
         let filtered_rooms = self.filtered_rooms();
 
-        if filtered_rooms.is_empty() {
-            return None;
-        }
+        let state = self.list_state.take();
+        let i = state.selected().unwrap_or_default();
+        self.list_state.set(state);
 
-        match self.list_state.take().selected() {
-            Some(i) => Some(filtered_rooms[i].clone()),
-            None => Some(filtered_rooms[0].clone()),
+        if i < filtered_invites.len() {
+            return Some(Selected::Invite(filtered_invites[i].clone()));
         }
+
+        filtered_rooms
+            .get(i - filtered_invites.len())
+            .map(|r| Selected::Room((*r).clone()))
     }
 }
 
+enum Selected {
+    Invite(DecoratedInvite),
+    Room(DecoratedRoom),
+}
+
 pub struct RoomsWidget<'a> {
     pub rooms: &'a Rooms,
 }
@@ -162,9 +223,17 @@ impl Widget for RoomsWidget<'_> {
 
         buf.merge(&Buffer::empty(area));
 
+        let unread: u64 = self.rooms.joined.iter().map(|r| r.unread_count()).sum();
+
+        let title = if unread > 0 {
+            format!("Rooms ({})", unread)
+        } else {
+            "Rooms".to_string()
+        };
+
         // Render the main block
         let block = Block::default()
-            .title("Rooms")
+            .title(title)
             .title_alignment(Alignment::Center)
             .style(Style::default().bg(Color::Black))
             .borders(Borders::ALL)
@@ -181,12 +250,19 @@ impl Widget for RoomsWidget<'_> {
 
         self.rooms.textinput.widget().render(splits[0], buf);
 
-        let items: Vec<ListItem> = self
+        let invites = self
+            .rooms
+            .filtered_invites()
+            .into_iter()
+            .map(|i| make_invite_item(i, self.rooms.show_account));
+
+        let rooms = self
             .rooms
             .filtered_rooms()
             .into_iter()
-            .map(make_list_item)
-            .collect();
+            .map(|r| make_list_item(r, self.rooms.show_account));
+
+        let items: Vec<ListItem> = invites.chain(rooms).collect();
 
         let area = Layout::default()
             .horizontal_margin(1)
@@ -200,7 +276,7 @@ impl Widget for RoomsWidget<'_> {
     }
 }
 
-fn make_list_item(joined: &DecoratedRoom) -> ListItem {
+fn make_list_item(joined: &DecoratedRoom, show_account: bool) -> ListItem {
     let name = joined.name.to_string();
     let unread = joined.unread_count();
     let highlights = joined.highlight_count();
@@ -221,6 +297,14 @@ fn make_list_item(joined: &DecoratedRoom) -> ListItem {
         ));
     }
 
+    // with more than one account, make it clear which one we'd be using
+    if show_account {
+        spans.push(Span::styled(
+            format!(" {}", joined.account()),
+            Style::default().fg(Color::Blue),
+        ));
+    }
+
     let mut lines = Text::from(Line::from(spans));
 
     let spans = vec![Span::styled(
@@ -237,7 +321,76 @@ fn make_list_item(joined: &DecoratedRoom) -> ListItem {
     ListItem::new(lines)
 }
 
+fn make_invite_item(invite: &DecoratedInvite, show_account: bool) -> ListItem {
+    let mut spans = vec![
+        Span::from(invite.name.to_string()),
+        Span::styled(" (invite)", Style::default().fg(Color::Yellow)),
+    ];
+
+    if show_account {
+        spans.push(Span::styled(
+            format!(" {}", invite.account()),
+            Style::default().fg(Color::Blue),
+        ));
+    }
+
+    let mut lines = Text::from(Line::from(spans));
+
+    let from = match &invite.inviter {
+        Some(inviter) => format!("Invited by {}. ", inviter),
+        None => "".to_string(),
+    };
+
+    lines.extend(Text::from(Line::from(vec![Span::styled(
+        format!("{}Enter to join, Ctrl-d to decline.", from),
+        Style::default().fg(Color::DarkGray),
+    )])));
+
+    ListItem::new(lines)
+}
+
 pub fn sort_rooms(rooms: &mut [DecoratedRoom]) {
     rooms.sort_by_key(|r| (r.unread_count(), r.last_ts));
     rooms.reverse()
 }
+
+/// Where to go once this room is gone: the one after it, or the one before
+/// if it was last.
+pub fn next_room(mut rooms: Vec<DecoratedRoom>, room: &Joined) -> Option<Joined> {
+    rooms.retain(|r| r.account() == room.own_user_id());
+    sort_rooms(&mut rooms);
+
+    let i = rooms.iter().position(|r| r.is(room)).unwrap_or_default();
+    rooms.retain(|r| !r.is(room));
+
+    rooms.get(i).or(rooms.last()).map(|r| r.inner())
+}
+
+pub fn leave_confirm(room: &DecoratedRoom, forget: bool) -> Confirm {
+    let (title, message) = if forget {
+        (
+            "Forget Room",
+            format!(
+                "Leave and forget {}? You won't see it in your room list again.",
+                room.name
+            ),
+        )
+    } else {
+        ("Leave Room", format!("Are you sure you want to leave {}?", room.name))
+    };
+
+    Confirm::new(
+        title.to_string(),
+        message,
+        "Yes".to_string(),
+        "No".to_string(),
+        ConfirmBehavior::LeaveRoom(room.inner(), forget),
+    )
+}
+
+/// The room we'd want to see first for the given account.
+pub fn top_room(mut rooms: Vec<DecoratedRoom>, account: &UserId) -> Option<Joined> {
+    rooms.retain(|r| r.account() == account);
+    sort_rooms(&mut rooms);
+    rooms.first().map(|r| r.inner())
+}
@@ -0,0 +1,370 @@
+use std::cell::Cell;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use log::error;
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, BorderType, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget,
+};
+use ruma::{OwnedUserId, UserId};
+
+use crate::app::{App, Popup};
+use crate::event::Event;
+use crate::matrix::backup::BackupStatus;
+use crate::matrix::matrix::{CrossSigning, Matrix};
+use crate::spawn::get_file_path;
+use crate::widgets::confirm::{Confirm, ConfirmBehavior};
+use crate::widgets::error::Error;
+use crate::widgets::password::{Password, PasswordBehavior};
+use crate::widgets::EventResult::Consumed;
+use crate::{close, consumed};
+
+use super::{get_margin, EventResult};
+
+/// Something we can do from the security popup.
+#[derive(Clone, Copy)]
+enum Action {
+    SetUpCrossSigning,
+    EnableBackup,
+    RestoreBackup,
+    ResetBackup,
+    ExportKeys,
+    ImportKeys,
+}
+
+impl Action {
+    fn label(&self) -> &'static str {
+        match self {
+            Action::SetUpCrossSigning => "Set up cross-signing",
+            Action::EnableBackup => "Back up my keys",
+            Action::RestoreBackup => "Restore keys from backup",
+            Action::ResetBackup => "Replace the backup with a new one",
+            Action::ExportKeys => "Export room keys to a file",
+            Action::ImportKeys => "Import room keys from a file",
+        }
+    }
+}
+
+/// Encryption settings for the active account.
+pub struct Security {
+    user_id: Option<OwnedUserId>,
+    cross_signing: Option<CrossSigning>,
+    backup: Option<BackupStatus>,
+    list_state: Cell<ListState>,
+}
+
+impl Security {
+    pub fn new(matrix: Matrix) -> Self {
+        let user_id = matrix.active_account();
+
+        // we'll hear back about these in a bit
+        if user_id.is_some() {
+            matrix.cross_signing_status();
+            matrix.backup_status();
+        }
+
+        let mut list_state = ListState::default();
+        list_state.select(Some(0));
+
+        Self {
+            user_id,
+            cross_signing: None,
+            backup: None,
+            list_state: Cell::new(list_state),
+        }
+    }
+
+    pub fn widget(&self) -> SecurityWidget {
+        SecurityWidget { security: self }
+    }
+
+    pub fn cross_signing_status_event(&mut self, user_id: &UserId, status: CrossSigning) {
+        if self.user_id.as_deref() == Some(user_id) {
+            self.cross_signing = Some(status);
+        }
+    }
+
+    pub fn backup_status_event(&mut self, user_id: &UserId, status: BackupStatus) {
+        if self.user_id.as_deref() == Some(user_id) {
+            self.backup = Some(status);
+        }
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        let mut actions = vec![];
+
+        if let Some(CrossSigning::Missing) = self.cross_signing {
+            actions.push(Action::SetUpCrossSigning);
+        }
+
+        actions.extend(match self.backup {
+            None | Some(BackupStatus::Off) => vec![],
+            Some(BackupStatus::Missing) => vec![Action::EnableBackup],
+            Some(BackupStatus::Locked { .. }) => vec![Action::RestoreBackup, Action::ResetBackup],
+            Some(BackupStatus::Enabled { .. }) => vec![Action::RestoreBackup],
+        });
+
+        if self.user_id.is_some() {
+            actions.extend([Action::ExportKeys, Action::ImportKeys]);
+        }
+
+        actions
+    }
+
+    pub fn key_event(&mut self, input: &KeyEvent) -> EventResult {
+        match input.code {
+            KeyCode::Esc => close!(),
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.next();
+                consumed!()
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.previous();
+                consumed!()
+            }
+            KeyCode::Enter => match self.selected() {
+                Some(Action::SetUpCrossSigning) => match self.user_id.clone() {
+                    Some(user_id) => Consumed(Box::new(|app| {
+                        app.close_popup();
+                        app.matrix.bootstrap_cross_signing(user_id, None);
+                    })),
+                    None => EventResult::Ignored,
+                },
+                Some(Action::EnableBackup) => {
+                    let sign = self.signs_backup();
+                    Consumed(Box::new(move |app| enable_backup(app, sign, false)))
+                }
+                Some(Action::ResetBackup) => {
+                    let confirm = Confirm::new(
+                        "Replace Key Backup".to_string(),
+                        "Replace the backup with a new one? Any keys that are only in the \
+                         old one will be lost."
+                            .to_string(),
+                        "Replace".to_string(),
+                        "Cancel".to_string(),
+                        ConfirmBehavior::ResetBackup(self.signs_backup()),
+                    );
+
+                    Consumed(Box::new(|app| app.set_popup(Popup::Confirm(confirm))))
+                }
+                Some(Action::RestoreBackup) => {
+                    let password = Password::new(
+                        "Restore Keys".to_string(),
+                        "Enter your recovery key, or the passphrase for your secret storage."
+                            .to_string(),
+                        false,
+                        PasswordBehavior::RestoreBackup,
+                    );
+
+                    Consumed(Box::new(|app| app.set_popup(Popup::Password(password))))
+                }
+                Some(Action::ExportKeys) => {
+                    let password = Password::new(
+                        "Export Room Keys".to_string(),
+                        "Choose a passphrase to protect the export. It will be saved to your \
+                         downloads folder."
+                            .to_string(),
+                        true,
+                        PasswordBehavior::ExportKeys,
+                    );
+
+                    Consumed(Box::new(|app| app.set_popup(Popup::Password(password))))
+                }
+                Some(Action::ImportKeys) => import_keys(),
+                None => EventResult::Ignored,
+            },
+            _ => EventResult::Ignored,
+        }
+    }
+
+    // with cross-signing, the backup gets signed with the master key
+    fn signs_backup(&self) -> bool {
+        matches!(
+            self.cross_signing,
+            Some(CrossSigning::Ready | CrossSigning::Unverified)
+        )
+    }
+
+    fn next(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(i) if i + 1 < self.actions().len() => i + 1,
+            _ => 0,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn previous(&mut self) {
+        let mut state = self.list_state.take();
+
+        let i = match state.selected() {
+            Some(0) | None => self.actions().len().saturating_sub(1),
+            Some(i) => i - 1,
+        };
+
+        state.select(Some(i));
+        self.list_state.set(state);
+    }
+
+    fn selected(&self) -> Option<Action> {
+        let state = self.list_state.take();
+        let selected = state.selected().unwrap_or_default();
+        self.list_state.set(state);
+
+        self.actions().get(selected).copied()
+    }
+}
+
+/// Start a new backup, asking for secret storage first if we can sign it.
+pub fn enable_backup(app: &mut App, sign: bool, replace: bool) {
+    if !sign {
+        app.close_popup();
+        app.matrix.enable_backup(None, replace);
+        return;
+    }
+
+    let password = Password::new(
+        "Back Up Keys".to_string(),
+        "Enter your recovery key, or the passphrase for your secret storage, so your other \
+         sessions can trust the backup."
+            .to_string(),
+        false,
+        PasswordBehavior::EnableBackup(replace),
+    );
+
+    app.set_popup(Popup::Password(password));
+}
+
+fn import_keys() -> EventResult {
+    let path = get_file_path();
+
+    if let Err(err) = App::get_sender().send(Event::Redraw) {
+        error!("could not redraw: {}", err);
+    }
+
+    match path {
+        Ok(Some(path)) => {
+            let password = Password::new(
+                "Import Room Keys".to_string(),
+                format!("Enter the passphrase for {}.", path.display()),
+                false,
+                PasswordBehavior::ImportKeys(path),
+            );
+
+            Consumed(Box::new(|app| app.set_popup(Popup::Password(password))))
+        }
+        Ok(None) => consumed!(),
+        Err(err) => Consumed(Box::new(move |app| {
+            app.set_popup(Popup::Error(Error::new(err.to_string())))
+        })),
+    }
+}
+
+pub struct SecurityWidget<'a> {
+    pub security: &'a Security,
+}
+
+impl Widget for SecurityWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = Layout::default()
+            .direction(Direction::Horizontal)
+            .vertical_margin(get_margin(area.height, 18))
+            .horizontal_margin(get_margin(area.width, 60))
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)[0];
+
+        buf.merge(&Buffer::empty(area));
+
+        let block = Block::default()
+            .title("Security")
+            .title_alignment(Alignment::Center)
+            .style(Style::default().bg(Color::Black))
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+
+        block.render(area, buf);
+
+        let splits = Layout::default()
+            .direction(Direction::Vertical)
+            .vertical_margin(2)
+            .horizontal_margin(3)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Min(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        let account = self
+            .security
+            .user_id
+            .as_ref()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+
+        Paragraph::new(vec![
+            Line::from(Span::styled(account, Style::default().fg(Color::Magenta))),
+            cross_signing_line(self.security.cross_signing.as_ref()),
+            backup_line(self.security.backup.as_ref()),
+        ])
+        .render(splits[0], buf);
+
+        let items: Vec<ListItem> = self
+            .security
+            .actions()
+            .iter()
+            .map(|a| ListItem::new(a.label()))
+            .collect();
+
+        let mut list_state = self.security.list_state.take();
+        let list = List::new(items).highlight_symbol("> ");
+        StatefulWidget::render(list, splits[2], buf, &mut list_state);
+        self.security.list_state.set(list_state);
+    }
+}
+
+fn cross_signing_line(status: Option<&CrossSigning>) -> Line {
+    let (text, color) = match status {
+        None => ("checking...", Color::DarkGray),
+        Some(CrossSigning::Missing) => ("not set up", Color::Yellow),
+        Some(CrossSigning::Unverified) => ("this session isn't verified", Color::Yellow),
+        Some(CrossSigning::Ready) => ("ready", Color::Green),
+    };
+
+    Line::from(vec![
+        Span::from("Cross-signing: "),
+        Span::styled(text, Style::default().fg(color)),
+    ])
+}
+
+fn backup_line(status: Option<&BackupStatus>) -> Line {
+    let (text, color) = match status {
+        None => ("checking...".to_string(), Color::DarkGray),
+        Some(BackupStatus::Off) => (
+            "off (set key_backup in the config)".to_string(),
+            Color::DarkGray,
+        ),
+        Some(BackupStatus::Missing) => ("not set up".to_string(), Color::Yellow),
+        Some(BackupStatus::Locked { version, count }) => (
+            format!("version {}, {} keys (not on this device)", version, count),
+            Color::Yellow,
+        ),
+        Some(BackupStatus::Enabled { version, count }) => {
+            (format!("version {}, {} keys", version, count), Color::Green)
+        }
+    };
+
+    Line::from(vec![
+        Span::from("Key backup: "),
+        Span::styled(text, Style::default().fg(color)),
+    ])
+}
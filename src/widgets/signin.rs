@@ -3,43 +3,92 @@ use ratatui::buffer::Buffer;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Style};
 use ratatui::widgets::{Block, BorderType, Borders, Widget};
+use ruma::UserId;
 
-use crate::consumed;
 use crate::widgets::button::Button;
 use crate::widgets::textinput::TextInput;
 use crate::widgets::EventResult::{Consumed, Ignored};
 use crate::widgets::{focus_next, focus_prev, get_margin, EventResult, Focusable};
+use crate::{close, consumed};
 
 pub struct Signin {
+    title: String,
     pub id: TextInput,
     pub password: TextInput,
+    pub homeserver: TextInput,
     submit: Button,
+    sso: Button,
+    cancelable: bool,
+    resume: bool,
 }
 
 impl Default for Signin {
     fn default() -> Self {
         let id = TextInput::new("Matrix ID".to_string(), true, false);
         let password = TextInput::new("Password".to_string(), false, true);
+        let homeserver = TextInput::new("Homeserver (optional)".to_string(), false, false);
 
         let submit = Button::new("Submit".to_string(), false);
+        let sso = Button::new("Use SSO".to_string(), false);
 
         Self {
+            title: "Sign In".to_string(),
             id,
             password,
+            homeserver,
             submit,
+            sso,
+            cancelable: false,
+            resume: false,
         }
     }
 }
 
 impl Signin {
+    /// Sign in to another account, which can be abandoned with Esc.
+    pub fn add_account() -> Self {
+        Self {
+            cancelable: true,
+            ..Self::default()
+        }
+    }
+
+    /// Sign back in to an account the homeserver logged out. After a soft
+    /// logout, we'll pick up right where we left off on the same device.
+    pub fn expired(user_id: &UserId, soft_logout: bool) -> Self {
+        let mut signin = Self {
+            title: "Session Expired".to_string(),
+            cancelable: true,
+            resume: soft_logout,
+            ..Self::default()
+        };
+
+        signin.id.set_value(user_id.to_string());
+        signin.id.focused = false;
+        signin.password.focused = true;
+        signin
+    }
+
     fn focus_order(&mut self) -> Vec<Box<dyn Focusable + '_>> {
         vec![
             Box::new(&mut self.id),
             Box::new(&mut self.password),
+            Box::new(&mut self.homeserver),
             Box::new(&mut self.submit),
+            Box::new(&mut self.sso),
         ]
     }
 
+    fn homeserver_value(&self) -> Option<String> {
+        let value = self.homeserver.value();
+
+        if value.trim().is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
     pub fn widget(&self) -> SigninWidget {
         SigninWidget { signin: self }
     }
@@ -53,17 +102,36 @@ impl Signin {
             return consumed!();
         }
 
+        if let Consumed(_) = self.homeserver.key_event(input) {
+            return consumed!();
+        }
+
         if let Consumed(_) = self.submit.key_event(input) {
             let id = self.id.value();
             let password = self.password.value();
+            let homeserver = self.homeserver_value();
+            let resume = self.resume;
 
             return EventResult::Consumed(Box::new(move |app| {
-                app.matrix.login(id.as_str(), password.as_str());
+                app.matrix
+                    .login(id.as_str(), password.as_str(), homeserver, resume);
+                app.close_popup();
+            }));
+        }
+
+        if let Consumed(_) = self.sso.key_event(input) {
+            let id = self.id.value();
+            let homeserver = self.homeserver_value();
+            let resume = self.resume;
+
+            return EventResult::Consumed(Box::new(move |app| {
+                app.matrix.login_sso(id.as_str(), homeserver, resume);
                 app.close_popup();
             }));
         }
 
         match input.code {
+            KeyCode::Esc if self.cancelable => close!(),
             KeyCode::Enter | KeyCode::Tab | KeyCode::Down => focus_next(self.focus_order()),
             KeyCode::BackTab | KeyCode::Up => focus_prev(self.focus_order()),
             _ => Ignored,
@@ -79,7 +147,7 @@ impl Widget for SigninWidget<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let area = Layout::default()
             .horizontal_margin(get_margin(area.width, 60))
-            .vertical_margin(get_margin(area.height, 18))
+            .vertical_margin(get_margin(area.height, 22))
             .constraints([Constraint::Percentage(100)].as_ref())
             .split(area)[0];
 
@@ -93,6 +161,8 @@ impl Widget for SigninWidget<'_> {
                     Constraint::Length(1),
                     Constraint::Length(3),
                     Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
                     Constraint::Percentage(100),
                 ]
                 .as_ref(),
@@ -100,7 +170,7 @@ impl Widget for SigninWidget<'_> {
             .split(area);
 
         let block = Block::default()
-            .title("Sign In")
+            .title(self.signin.title.clone())
             .title_alignment(Alignment::Center)
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded)
@@ -109,13 +179,15 @@ impl Widget for SigninWidget<'_> {
         block.render(area, buf);
         self.signin.id.widget().render(splits[0], buf);
         self.signin.password.widget().render(splits[2], buf);
+        self.signin.homeserver.widget().render(splits[4], buf);
 
-        // pop the submit button on the right side
-        let area = Layout::default()
+        // pop the submit button on the right side, with SSO on the left
+        let buttons = Layout::default()
             .direction(Direction::Horizontal)
             .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-            .split(splits[4])[1];
+            .split(splits[6]);
 
-        self.signin.submit.widget().render(area, buf);
+        self.signin.sso.widget().render(buttons[0], buf);
+        self.signin.submit.widget().render(buttons[1], buf);
     }
 }
@@ -82,6 +82,11 @@ impl TextInput {
         self.value.clone()
     }
 
+    pub fn set_value(&mut self, value: String) {
+        self.cursor = value.len();
+        self.value = value;
+    }
+
     fn append_char(&mut self, ch: char) {
         if self.cursor == self.value.len() {
             self.value.push(ch);